@@ -0,0 +1,153 @@
+use crate::{config::Float, transform::{Quaternion, Transform}, util::Interval, vec3::Vec3};
+
+pub trait Interpolate: Copy {
+    // a at t = 0, b at t = 1
+    fn interpolate(a: Self, b: Self, t: Float) -> Self;
+}
+
+impl Interpolate for Float {
+    fn interpolate(a: Self, b: Self, t: Float) -> Self {
+        a + t * (b - a)
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(a: Self, b: Self, t: Float) -> Self {
+        a + t * (b - a)
+    }
+}
+
+impl Interpolate for Quaternion {
+    fn interpolate(a: Self, b: Self, t: Float) -> Self {
+        a.slerp(b, t)
+    }
+}
+
+// Values at increasing points in time, interpolated in between and held before the first and after the last
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframes<T> {
+    keys: Vec<(Float, T)>,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new(mut keys: Vec<(Float, T)>) -> Self {
+        assert!(!keys.is_empty(), "Keyframes need at least one key");
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Keyframes { keys }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Keyframes { keys: vec![(0., value)] }
+    }
+
+    pub fn keys(&self) -> &[(Float, T)] {
+        &self.keys
+    }
+
+    pub fn at(&self, time: Float) -> T {
+        // index of the first key after time
+        let next = self.keys.partition_point(|&(t, _)| t <= time);
+        match next {
+            0 => self.keys[0].1,
+            n if n == self.keys.len() => self.keys[n - 1].1,
+            n => {
+                let ((t0, a), (t1, b)) = (self.keys[n - 1], self.keys[n]);
+                T::interpolate(a, b, (time - t0) / (t1 - t0))
+            },
+        }
+    }
+}
+
+// Scale, then rotation, then translation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransformKey {
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+impl Default for TransformKey {
+    fn default() -> Self {
+        TransformKey { translation: Vec3::default(), rotation: Quaternion::IDENTITY, scale: vec3!(1, 1, 1) }
+    }
+}
+
+impl TransformKey {
+    pub fn transform(&self) -> Transform {
+        let scale = Transform::scale(self.scale).expect("Keyframed scale factors must not be zero");
+        Transform::translate(self.translation) * Transform::from_quaternion(self.rotation) * scale
+    }
+}
+
+impl Interpolate for TransformKey {
+    fn interpolate(a: Self, b: Self, t: Float) -> Self {
+        TransformKey {
+            translation: Vec3::interpolate(a.translation, b.translation, t),
+            rotation: Quaternion::interpolate(a.rotation, b.rotation, t),
+            scale: Vec3::interpolate(a.scale, b.scale, t),
+        }
+    }
+}
+
+// Frames spread evenly over start..end, each exposed for the given fraction of the time between frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeline {
+    pub frames: usize,
+    pub start: Float,
+    pub end: Float,
+    pub shutter: Float,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        // a 180 degree shutter, in film terms
+        Timeline { frames: 1, start: 0., end: 1., shutter: 0.5 }
+    }
+}
+
+impl Timeline {
+    pub fn frame_shutter(&self, frame: usize) -> Interval {
+        let duration = (self.end - self.start) / self.frames as Float;
+        let open = self.start + frame as Float * duration;
+        (open, open + self.shutter * duration).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{animation::{Keyframes, Timeline, TransformKey}, config::Float, transform::Quaternion};
+
+    #[test]
+    fn test_keyframes() {
+        let keys = Keyframes::new(vec![(1., 10.), (0., 0.), (3., 30.)]);
+        assert_eq!(keys.at(-1.), 0.);
+        assert_eq!(keys.at(0.5), 5.);
+        assert_eq!(keys.at(1.), 10.);
+        assert_eq!(keys.at(2.), 20.);
+        assert_eq!(keys.at(4.), 30.);
+        assert_eq!(Keyframes::constant(2. as Float).at(7.), 2.);
+    }
+
+    #[test]
+    fn test_transform_keys() {
+        let a = TransformKey::default();
+        let b = TransformKey { translation: vec3!(2, 0, 0), rotation: Quaternion::from_axis_angle(vec3!(0, 0, 1), 180.), scale: vec3!(3, 3, 3) };
+        let keys = Keyframes::new(vec![(0., a), (1., b)]);
+
+        // a quarter turn, twice the size and halfway over
+        let p = keys.at(0.5).transform().point(vec3!(1, 0, 0));
+        assert_abs_diff_eq!(p.x, 1., epsilon = 1e-12);
+        assert_abs_diff_eq!(p.y, 2., epsilon = 1e-12);
+        assert_abs_diff_eq!(p.z, 0., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline { frames: 4, start: 1., end: 3., shutter: 0.5 };
+        assert_eq!(timeline.frame_shutter(0), (1., 1.25).into());
+        assert_eq!(timeline.frame_shutter(3), (2.5, 2.75).into());
+        assert_eq!(Timeline { shutter: 0., ..timeline }.frame_shutter(1), (1.5, 1.5).into());
+    }
+}
@@ -0,0 +1,55 @@
+use std::{hint::black_box, time::Instant};
+
+use crate::{
+    config::{Film, Float}, hit::bvh::{BvhBuilder, BvhLayout, BvhOptions, BvhSplit}, random::{random_float, reseed_thread}, ray::{Ray, ray}, scene::Scene,
+    vec3::random_unit_vector
+};
+
+// Builds the scene with every combination of BVH split and layout, and traces the same rays through each:
+// one camera ray per pixel, plus a shadow ray in a random direction from every point those hit.
+// Timings are single threaded, so they're only comparable to each other.
+pub fn compare_bvh_layouts(film: &Film, options: BvhOptions, build: impl Fn(&mut BvhBuilder) -> Scene) {
+    const REPEATS: usize = 4;
+
+    println!("{:<8} {:<6} {:>10} {:>10} {:>12} {:>12}", "split", "layout", "build", "SAH cost", "primary", "shadow");
+    let mut reference = None;
+
+    for split in [BvhSplit::Median, BvhSplit::Sah] {
+        for layout in [BvhLayout::Tree, BvhLayout::Flat, BvhLayout::Wide4, BvhLayout::Wide8] {
+            // scenes and rays use random numbers, replay the same ones for every combination
+            reseed_thread(0);
+            let mut builder = BvhBuilder::new(BvhOptions { split, layout, ..options });
+            let scene = build(&mut builder);
+
+            let primary: Vec<Ray> = (0..film.height)
+                .flat_map(|y| (0..film.width).map(move |x| (x as Float + 0.5, y as Float + 0.5)))
+                .filter_map(|st| scene.cam.ray(st))
+                .collect();
+
+            let start = Instant::now();
+            let mut hits = Vec::new();
+            for _ in 0..REPEATS {
+                hits = primary.iter().map(|&r| scene.objects.hit(r, 0.001, Float::INFINITY).map(|h| (h.pos, h.t))).collect();
+            }
+            let primary_time = start.elapsed() / REPEATS as u32;
+
+            let shadow: Vec<Ray> = hits.iter().flatten().map(|&(pos, _)| ray(pos, random_unit_vector(), random_float())).collect();
+            let start = Instant::now();
+            for _ in 0..REPEATS {
+                black_box(shadow.iter().filter(|&&r| scene.objects.occluded(r, 0.001, Float::INFINITY)).count());
+            }
+            let shadow_time = start.elapsed() / REPEATS as u32;
+
+            let rays_per_sec = |n: usize, d: std::time::Duration| format!("{:.2} Mray/s", n as Float / d.as_secs_f64() as Float / 1e6);
+            println!("{:<8} {:<6} {:>10.2?} {:>10.2} {:>12} {:>12}", format!("{split:?}"), format!("{layout:?}"), builder.stats.build_time,
+                builder.stats.cost, rays_per_sec(primary.len(), primary_time), rays_per_sec(shadow.len(), shadow_time));
+
+            // every structure has to find the same closest hits
+            let ts: Vec<Option<Float>> = hits.iter().map(|h| h.map(|(_, t)| t)).collect();
+            match &reference {
+                None => reference = Some(ts),
+                Some(expected) => assert!(*expected == ts, "{split:?} {layout:?} BVH found different hits"),
+            }
+        }
+    }
+}
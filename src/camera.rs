@@ -1,56 +1,74 @@
-use crate::{config::{Film, Float}, ray::{ray, Ray}, util::{radians, Interval}, vec3::{cross, random_vector_in_unit_disk, Vec3}};
-
-#[derive(Clone, Copy)]
-pub struct Camera {
-    cam_pos: Vec3,
-    pixel_center_upper_left: Vec3,
-    pixel_delta_u: Vec3,
-    pixel_delta_v: Vec3,
-    defocus_disk_u: Vec3,
-    defocus_disk_v: Vec3,
-    time: Interval,
-}
+use crate::{animation::{Keyframes, TransformKey}, config::Float, ray::{ray, Ray}, util::Interval, vec3::{cross, Vec3}};
 
-impl Camera {
-    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, vfov: Float, focal_len: Float, defocus_angle: Float, time: Interval) -> Camera {
-        let aspect_ratio = film.width as Float / film.height as Float;
-        let theta = radians(vfov);
-        let h = (theta / 2.).tan(); // half viewport height divided by focal length
-        let viewport_height = 2. * h * focal_len;
-        let viewport_width = viewport_height * aspect_ratio;
+pub mod thin_lens;
+pub mod aperture;
+pub mod orthographic;
+pub mod fisheye;
+pub mod equirectangular;
+pub mod realistic;
 
-        let w = (from - to).normalize();
-        let u = cross(up, w).normalize(); // viewport-horizontal axis
-        let v = cross(w, u); // viewport-vertical axis
+pub trait Camera {
+    // (s, t) is a position on the film in pixels, from the upper left corner.
+    // None where the projection doesn't cover the film, those samples stay black
+    fn ray(&self, st: (Float, Float)) -> Option<Ray>;
 
-        let viewport_u = viewport_width * u; // vector across viewport width
-        let viewport_v = viewport_height * -v; // vector across viewport height
+    // the ray with how much the light along it counts, for lenses that let less of it reach parts of the film
+    fn weighted_ray(&self, st: (Float, Float)) -> Option<(Ray, Float)> {
+        self.ray(st).map(|r| (r, 1.))
+    }
 
-        let pixel_delta_u = viewport_u / film.width as Float; // horizontal vector between viewport pixels
-        let pixel_delta_v = viewport_v / film.height as Float; // vertical vector between viewport pixels
+    // rays get random times within the shutter interval
+    fn shutter(&self) -> Interval;
+    fn set_shutter(&mut self, time: Interval);
+}
 
-        let viewport_upper_left = from - (focal_len * w) - viewport_u / 2. - viewport_v / 2.; // upper-left-most corner of the viewport in world space
-        let pixel_center_upper_left = viewport_upper_left + pixel_delta_u / 2. + pixel_delta_v / 2.; // center of upper-left-most pixel
+// Where a camera sits and which way it faces, u points right, v up and w backwards, away from what it looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraFrame {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
 
-        let defocus_radius = focal_len * radians(defocus_angle / 2.).tan();
-        let defocus_disk_u = u * defocus_radius;
-        let defocus_disk_v = v * defocus_radius;
+impl CameraFrame {
+    pub fn look_at(from: Vec3, to: Vec3, up: Vec3) -> CameraFrame {
+        let w = (from - to).normalize();
+        let u = cross(up, w).normalize();
+        let v = cross(w, u);
+        CameraFrame { origin: from, u, v, w }
+    }
 
-        Camera { cam_pos: from, pixel_center_upper_left, pixel_delta_u, pixel_delta_v, defocus_disk_u, defocus_disk_v, time }
+    // from camera space, where the camera looks down -z with y up, to world space
+    pub fn to_world(self, d: Vec3) -> Vec3 {
+        d.x * self.u + d.y * self.v + d.z * self.w
     }
+}
 
-    pub fn ray(&self, (s, t): (Float, Float)) -> Ray {
-        let target = self.pixel_center_upper_left + s * self.pixel_delta_u + t * self.pixel_delta_v;
-        let offset = random_vector_in_unit_disk();
-        let pos = self.cam_pos + offset.x * self.defocus_disk_u + offset.y * self.defocus_disk_v;
-        let direction = target - pos;
+// Moves any camera as a whole over time, on top of its own placement
+pub struct Rigged {
+    pub rig: Keyframes<TransformKey>,
+    pub camera: Box<dyn Camera + Send + Sync>,
+}
 
-        ray(pos, direction, self.time.random())
+impl Camera for Rigged {
+    fn ray(&self, st: (Float, Float)) -> Option<Ray> {
+        let r = self.camera.ray(st)?;
+        let transform = self.rig.at(r.time).transform();
+        Some(ray(transform.point(r.origin), transform.vector(r.direction), r.time))
+    }
+
+    fn weighted_ray(&self, st: (Float, Float)) -> Option<(Ray, Float)> {
+        let (r, weight) = self.camera.weighted_ray(st)?;
+        let transform = self.rig.at(r.time).transform();
+        Some((ray(transform.point(r.origin), transform.vector(r.direction), r.time), weight))
     }
-}
 
-impl Default for Camera {
-    fn default() -> Self {
-        Camera::new(&Film::new((1, 1)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 90., 1., 0., (0., 0.).into())
+    fn shutter(&self) -> Interval {
+        self.camera.shutter()
     }
-}
\ No newline at end of file
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.camera.set_shutter(time);
+    }
+}
@@ -0,0 +1,114 @@
+use image::RgbImage;
+
+use crate::{color::color_rgb, config::{Float, PI}, random::random_float, util::radians, vec3::{random_vector_in_unit_disk, vec3, Vec3}};
+
+// The shape of the lens opening, which is the shape out of focus highlights take. Disks and polygons fit the unit
+// disk, masks the square around it (see ApertureMask). The camera scales them to the lens
+#[derive(Clone, Debug, Default)]
+pub enum Aperture {
+    #[default]
+    Disk,
+    // a regular polygon with a corner at rotation degrees from the right, counterclockwise
+    Polygon { blades: u32, rotation: Float },
+    Mask(ApertureMask),
+}
+
+impl Aperture {
+    // a uniformly distributed point on the aperture, in the xy plane
+    pub fn sample(&self) -> Vec3 {
+        match self {
+            Aperture::Disk => random_vector_in_unit_disk(),
+            Aperture::Polygon { blades, rotation } => {
+                // pick one of the triangles between the center and each edge, they're all the same size
+                let n = *blades as Float;
+                let blade = (random_float() * n).floor().min(n - 1.);
+                let angle = radians(*rotation) + blade * 2. * PI / n;
+                let (a, b) = (vec3(angle.cos(), angle.sin(), 0.), vec3((angle + 2. * PI / n).cos(), (angle + 2. * PI / n).sin(), 0.));
+                let (mut s, mut t) = (random_float(), random_float());
+                if s + t > 1. {
+                    (s, t) = (1. - s, 1. - t);
+                }
+                s * a + t * b
+            },
+            Aperture::Mask(mask) => mask.sample(),
+        }
+    }
+}
+
+// A greyscale image of the opening, brighter pixels let more light through. It's centered on the lens with its
+// longer side across the unit disk, so a round opening touching the image edges is as large as Aperture::Disk, and
+// whatever is open in the corners reaches out to sqrt(2)
+#[derive(Clone, Debug)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // running sum of pixel luminance, for picking pixels in proportion to it
+    cdf: Vec<Float>,
+}
+
+impl ApertureMask {
+    // None when the image is black all over
+    pub fn new(image: &RgbImage) -> Option<ApertureMask> {
+        let mut sum = 0.;
+        let cdf: Vec<Float> = image.pixels().map(|p| {
+            sum += color_rgb(p[0] as Float, p[1] as Float, p[2] as Float).luminance();
+            sum
+        }).collect();
+        (sum > 0.).then(|| ApertureMask { width: image.width() as usize, height: image.height() as usize, cdf })
+    }
+
+    fn sample(&self) -> Vec3 {
+        let total = self.cdf[self.cdf.len() - 1];
+        let u = random_float() * total;
+        let pixel = self.cdf.partition_point(|&c| c <= u).min(self.cdf.len() - 1);
+        let (x, y) = ((pixel % self.width) as Float + random_float(), (pixel / self.width) as Float + random_float());
+        let size = self.width.max(self.height) as Float;
+        vec3((2. * x - self.width as Float) / size, (self.height as Float - 2. * y) / size, 0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use crate::{camera::aperture::{Aperture, ApertureMask}, config::{Float, PI}};
+
+    #[test]
+    fn test_polygon_aperture() {
+        // a square standing on a corner is the diamond |x| + |y| <= 1
+        let aperture = Aperture::Polygon { blades: 4, rotation: 90. };
+        let points: Vec<_> = (0..1000).map(|_| aperture.sample()).collect();
+        assert!(points.iter().all(|p| p.x.abs() + p.y.abs() <= 1. + 1e-9 && p.z == 0.));
+        // and uniform, so a quarter lands in each quadrant
+        let upper_right = points.iter().filter(|p| p.x > 0. && p.y > 0.).count();
+        assert!((200..300).contains(&upper_right), "{upper_right}");
+
+        // hexagon edges are cos(30 degrees) from the center
+        let aperture = Aperture::Polygon { blades: 6, rotation: 0. };
+        for _ in 0..1000 {
+            let p = aperture.sample();
+            let angle = p.y.atan2(p.x).rem_euclid(PI / 3.) - PI / 6.;
+            assert!(p.length() * angle.cos() <= (PI / 6.).cos() + 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_aperture_mask() {
+        assert!(ApertureMask::new(&RgbImage::new(4, 2)).is_none());
+
+        // only the lower right pixel is open
+        let mut image = RgbImage::new(4, 2);
+        image.put_pixel(3, 1, Rgb([255, 255, 255]));
+        let aperture = Aperture::Mask(ApertureMask::new(&image).unwrap());
+        for _ in 0..100 {
+            let p = aperture.sample();
+            assert!((0.5..=1.).contains(&p.x) && (-0.5..=0.).contains(&p.y), "{p:?}");
+        }
+
+        // a dimmer pixel gets fewer samples
+        image.put_pixel(0, 0, Rgb([64, 64, 64]));
+        let aperture = Aperture::Mask(ApertureMask::new(&image).unwrap());
+        let dim = (0..1000).filter(|_| aperture.sample().x < 0.).count() as Float;
+        assert!((150. ..250.).contains(&dim), "{dim}");
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{camera::{Camera, CameraFrame}, config::{Float, Film, PI}, ray::{ray, Ray}, util::Interval, vec3::Vec3};
+
+// A full 360 by 180 degree latitude-longitude panorama, as used for environment maps and VR.
+// Longitude runs across the film and latitude down it, with the view direction in the middle and up at the top
+#[derive(Clone)]
+pub struct EquirectangularCamera {
+    frame: CameraFrame,
+    size: (usize, usize),
+    time: Interval,
+}
+
+impl EquirectangularCamera {
+    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, time: Interval) -> EquirectangularCamera {
+        EquirectangularCamera { frame: CameraFrame::look_at(from, to, up), size: (film.width, film.height), time }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn ray(&self, (s, t): (Float, Float)) -> Option<Ray> {
+        let longitude = (s / self.size.0 as Float - 0.5) * 2. * PI;
+        let latitude = (0.5 - t / self.size.1 as Float) * PI;
+        let direction = vec3!(latitude.cos() * longitude.sin(), latitude.sin(), -latitude.cos() * longitude.cos());
+        Some(ray(self.frame.origin, self.frame.to_world(direction), self.time.random()))
+    }
+
+    fn shutter(&self) -> Interval {
+        self.time
+    }
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{camera::{Camera, equirectangular::EquirectangularCamera}, config::Film};
+
+    #[test]
+    fn test_equirectangular_camera() {
+        // looking along +x
+        let cam = EquirectangularCamera::new(&Film::new((360, 180)), vec3!(1, 2, 3), vec3!(2, 2, 3), vec3!(0, 1, 0), (0., 0.).into());
+        let r = cam.ray((180., 90.)).unwrap();
+        assert_eq!(r.origin, vec3!(1, 2, 3));
+        assert_abs_diff_eq!(r.direction, vec3!(1, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((270., 90.)).unwrap().direction, vec3!(0, 0, 1), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((0., 90.)).unwrap().direction, vec3!(-1, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((360., 90.)).unwrap().direction, vec3!(-1, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((123., 0.)).unwrap().direction, vec3!(0, 1, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((180., 135.)).unwrap().direction, vec3!(1, -1, 0).normalize(), epsilon = 1e-9);
+    }
+}
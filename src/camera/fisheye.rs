@@ -0,0 +1,66 @@
+use crate::{camera::{Camera, CameraFrame}, config::{Film, Float}, ray::{ray, Ray}, util::{radians, Interval}, vec3::Vec3};
+
+// An equidistant fisheye, the angle away from the view direction grows linearly with the distance from the middle of the film.
+// vfov spans the image circle, which touches the top and bottom of the film and can take in more than a hemisphere
+#[derive(Clone)]
+pub struct FisheyeCamera {
+    frame: CameraFrame,
+    size: (usize, usize),
+    half_fov: Float,
+    time: Interval,
+}
+
+impl FisheyeCamera {
+    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, vfov: Float, time: Interval) -> FisheyeCamera {
+        FisheyeCamera { frame: CameraFrame::look_at(from, to, up), size: (film.width, film.height), half_fov: radians(vfov) / 2., time }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn ray(&self, (s, t): (Float, Float)) -> Option<Ray> {
+        let (width, height) = (self.size.0 as Float, self.size.1 as Float);
+        // position in the image circle, which has radius 1
+        let (x, y) = ((2. * s - width) / height, 1. - 2. * t / height);
+        let r = x.hypot(y);
+        if r > 1. {
+            return None;
+        }
+
+        let theta = r * self.half_fov;
+        // sin(theta) / r, which tends to half_fov in the middle
+        let scale = if r > 0. { theta.sin() / r } else { self.half_fov };
+        let direction = self.frame.to_world(vec3!(x * scale, y * scale, -theta.cos()));
+        Some(ray(self.frame.origin, direction, self.time.random()))
+    }
+
+    fn shutter(&self) -> Interval {
+        self.time
+    }
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{camera::{Camera, fisheye::FisheyeCamera}, config::Film};
+
+    #[test]
+    fn test_fisheye_camera() {
+        let cam = FisheyeCamera::new(&Film::new((200, 100)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 180., (0., 0.).into());
+        assert_abs_diff_eq!(cam.ray((100., 50.)).unwrap().direction, vec3!(0, 0, -1), epsilon = 1e-9);
+        // the rim of a 180 degree circle looks sideways, halfway out is at 45 degrees
+        assert_abs_diff_eq!(cam.ray((100., 0.)).unwrap().direction, vec3!(0, 1, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((50., 50.)).unwrap().direction, vec3!(-1, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((125., 50.)).unwrap().direction, vec3!(1, 0, -1).normalize(), epsilon = 1e-9);
+        assert!(cam.ray((10., 50.)).is_none());
+        assert!(cam.ray((50., 0.)).is_none());
+
+        // beyond a hemisphere the rim looks backwards
+        let cam = FisheyeCamera::new(&Film::new((100, 100)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 270., (0., 0.).into());
+        assert_abs_diff_eq!(cam.ray((100., 50.)).unwrap().direction, vec3!(1, 0, 1).normalize(), epsilon = 1e-9);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{camera::{Camera, CameraFrame}, config::{Film, Float}, ray::{ray, Ray}, util::Interval, vec3::Vec3};
+
+// Parallel rays, for elevations and plans where things don't shrink with distance.
+// view_height is how much of the scene the film covers vertically, in world units
+#[derive(Clone)]
+pub struct OrthographicCamera {
+    frame: CameraFrame,
+    size: (usize, usize),
+    pixel_size: Float,
+    time: Interval,
+}
+
+impl OrthographicCamera {
+    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, view_height: Float, time: Interval) -> OrthographicCamera {
+        OrthographicCamera { frame: CameraFrame::look_at(from, to, up), size: (film.width, film.height), pixel_size: view_height / film.height as Float, time }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn ray(&self, (s, t): (Float, Float)) -> Option<Ray> {
+        let (width, height) = (self.size.0 as Float, self.size.1 as Float);
+        let offset = vec3!(s - width / 2., height / 2. - t, 0) * self.pixel_size;
+        Some(ray(self.frame.origin + self.frame.to_world(offset), -self.frame.w, self.time.random()))
+    }
+
+    fn shutter(&self) -> Interval {
+        self.time
+    }
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{camera::{Camera, orthographic::OrthographicCamera}, config::Film};
+
+    #[test]
+    fn test_orthographic_camera() {
+        // looking down at the xz plane, with -z at the top of the film
+        let cam = OrthographicCamera::new(&Film::new((40, 20)), vec3!(0, 10, 0), vec3!(0, 0, 0), vec3!(0, 0, -1), 4., (0., 0.).into());
+        let r = cam.ray((20., 10.)).unwrap();
+        assert_eq!((r.origin, r.direction), (vec3!(0, 10, 0), vec3!(0, -1, 0)));
+        let r = cam.ray((0., 0.)).unwrap();
+        assert_eq!((r.origin, r.direction), (vec3!(-4, 10, -2), vec3!(0, -1, 0)));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{camera::{Camera, CameraFrame}, config::{Film, Float}, random::random_float, ray::{ray, Ray}, util::Interval, vec3::{dot, vec3, Vec3}};
+
+// One surface of a lens prescription, listed from the front of the lens to the film like lens data sheets do.
+// Lengths are in millimeters: the radius of curvature (0 for the aperture stop), the distance to the next surface,
+// the index of refraction behind the surface (0 or 1 for air) and the diameter of its opening
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LensElement {
+    pub radius: Float,
+    pub thickness: Float,
+    pub ior: Float,
+    pub aperture: Float,
+}
+
+impl LensElement {
+    fn is_stop(&self) -> bool {
+        self.radius == 0.
+    }
+
+    fn ior(&self) -> Float {
+        if self.ior == 0. { 1. } else { self.ior }
+    }
+}
+
+// Film points are grouped into rings around the center, each with its own bounds on the exit pupil
+const PUPIL_RINGS: usize = 32;
+// points on the rear element tried per ring when finding those bounds, a square number
+const PUPIL_SAMPLES: usize = 128 * 128;
+
+// The part of the rear element that light from a ring of film points can get through the lens from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PupilBounds {
+    pub min: (Float, Float),
+    pub max: (Float, Float),
+}
+
+impl PupilBounds {
+    fn point(x: Float, y: Float) -> PupilBounds {
+        PupilBounds { min: (x, y), max: (x, y) }
+    }
+
+    fn contains(&self, (x, y): (Float, Float)) -> bool {
+        x >= self.min.0 && x <= self.max.0 && y >= self.min.1 && y <= self.max.1
+    }
+
+    fn union(&self, (x, y): (Float, Float)) -> PupilBounds {
+        PupilBounds { min: (self.min.0.min(x), self.min.1.min(y)), max: (self.max.0.max(x), self.max.1.max(y)) }
+    }
+
+    fn area(&self) -> Float {
+        (self.max.0 - self.min.0) * (self.max.1 - self.min.1)
+    }
+}
+
+// A camera that traces rays through the elements of a real lens, in the style of pbrt's RealisticCamera.
+// The lens system sits in camera space with the film at z = 0 and the front element towards -z, scaled from
+// millimeters to meters when rays leave it. Vignetting and distortion come from the lens itself
+#[derive(Clone)]
+pub struct RealisticCamera {
+    frame: CameraFrame,
+    size: (usize, usize),
+    // width and height of the film in millimeters
+    film_size: (Float, Float),
+    elements: Vec<LensElement>,
+    exit_pupils: Vec<PupilBounds>,
+    time: Interval,
+}
+
+impl RealisticCamera {
+    // Moves the lens so that it focuses focus_distance meters in front of the film, None if it can't.
+    // sensor_height is in millimeters, 24 for full frame
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, elements: Vec<LensElement>, sensor_height: Float, focus_distance: Float, time: Interval) -> Option<RealisticCamera> {
+        let film_size = (sensor_height * film.width as Float / film.height as Float, sensor_height);
+        let mut cam = RealisticCamera { frame: CameraFrame::look_at(from, to, up), size: (film.width, film.height), film_size, elements, exit_pupils: Vec::new(), time };
+
+        let rear_thickness = cam.focus_thickness(focus_distance * 1000.)?;
+        cam.elements.last_mut()?.thickness = rear_thickness;
+
+        let ring_width = film_size.0.hypot(film_size.1) / 2. / PUPIL_RINGS as Float;
+        cam.exit_pupils = (0..PUPIL_RINGS).map(|i| cam.bound_exit_pupil(i as Float * ring_width, (i + 1) as Float * ring_width)).collect();
+        Some(cam)
+    }
+
+    // distance from the rear element to the film
+    fn rear_z(&self) -> Float {
+        self.elements.last().map_or(0., |e| e.thickness)
+    }
+
+    // distance from the front element to the film
+    fn front_z(&self) -> Float {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_radius(&self) -> Float {
+        self.elements.last().map_or(0., |e| e.aperture / 2.)
+    }
+
+    // Follows a ray from the film out through the front element, None if it's blocked on the way
+    pub fn trace_from_film(&self, mut o: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        let mut z = 0.;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            z -= element.thickness;
+            let ior_in_front = if i > 0 { self.elements[i - 1].ior() } else { 1. };
+            (o, d) = pass_element(element, z, o, d, element.ior() / ior_in_front)?;
+        }
+        Some((o, d))
+    }
+
+    // Follows a ray from the scene in through the rear element
+    pub fn trace_from_scene(&self, mut o: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        let mut z = -self.front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let ior_in_front = if i > 0 { self.elements[i - 1].ior() } else { 1. };
+            (o, d) = pass_element(element, z, o, d, ior_in_front / element.ior())?;
+            z += element.thickness;
+        }
+        Some((o, d))
+    }
+
+    // The z of the principal plane and focal point on the scene side and on the film side, found with rays
+    // parallel to the axis like a thick lens would be
+    fn cardinal_points(&self) -> Option<((Float, Float), (Float, Float))> {
+        let x = 0.001 * self.film_size.0.hypot(self.film_size.1);
+        let cardinal = |o: Vec3, (out_o, out_d): (Vec3, Vec3)| {
+            let focal = out_o.z + -out_o.x / out_d.x * out_d.z;
+            let principal = out_o.z + (o.x - out_o.x) / out_d.x * out_d.z;
+            (principal, focal)
+        };
+        let from_scene = vec3(x, 0., -self.front_z() - 1.);
+        let film_side = cardinal(from_scene, self.trace_from_scene(from_scene, vec3(0., 0., 1.))?);
+        let from_film = vec3(x, 0., 1. - self.rear_z());
+        let scene_side = cardinal(from_film, self.trace_from_film(from_film, vec3(0., 0., -1.))?);
+        Some((scene_side, film_side))
+    }
+
+    // The rear element's distance from the film that puts objects at focus_distance millimeters in focus
+    fn focus_thickness(&self, focus_distance: Float) -> Option<Float> {
+        let ((scene_principal, _), (film_principal, film_focal)) = self.cardinal_points()?;
+        let f = film_focal - film_principal;
+        // moving the lens by delta towards the scene has to satisfy 1 / (a - delta) + 1 / (b + delta) = 1 / f
+        let (a, b) = (scene_principal + focus_distance, -film_principal);
+        let c = (a + b) * (a + b - 4. * f);
+        let thickness = self.rear_z() + 0.5 * (a - b - c.sqrt());
+        // a lens without power has its focal points at infinity, which makes all of these NaN
+        if !(c >= 0. && f > 0. && thickness.is_finite()) {
+            return None;
+        }
+        Some(thickness)
+    }
+
+    pub fn focal_length(&self) -> Option<Float> {
+        let (_, (film_principal, film_focal)) = self.cardinal_points()?;
+        Some(film_focal - film_principal)
+    }
+
+    fn bound_exit_pupil(&self, film_x0: Float, film_x1: Float) -> PupilBounds {
+        let r = 1.5 * self.rear_radius();
+        let n = (PUPIL_SAMPLES as Float).sqrt() as usize;
+        let mut bounds: Option<PupilBounds> = None;
+        for i in 0..PUPIL_SAMPLES {
+            // film points from a radical inverse, so they don't line up with the grid on the rear element
+            let film = vec3(film_x0 + (i as u32).reverse_bits() as Float / 2. * (film_x1 - film_x0) / (1u64 << 31) as Float, 0., 0.);
+            let rear = ((2. * ((i % n) as Float + 0.5) / n as Float - 1.) * r, (2. * ((i / n) as Float + 0.5) / n as Float - 1.) * r);
+            if bounds.is_some_and(|b| b.contains(rear)) || self.trace_from_film(film, vec3(rear.0, rear.1, -self.rear_z()) - film).is_some() {
+                bounds = Some(bounds.map_or(PupilBounds::point(rear.0, rear.1), |b| b.union(rear)));
+            }
+        }
+        // grow by a couple of grid cells so nothing in between samples gets missed
+        let margin = 2. * 2. * r / n as Float;
+        match bounds {
+            Some(b) => PupilBounds { min: (b.min.0 - margin, b.min.1 - margin), max: (b.max.0 + margin, b.max.1 + margin) },
+            None => PupilBounds { min: (-r, -r), max: (r, r) },
+        }
+    }
+
+    // A point on the rear element for the film point to send a ray through, and the area it was picked from
+    fn sample_exit_pupil(&self, (x, y): (Float, Float)) -> (Vec3, Float) {
+        let r = x.hypot(y);
+        let ring = (r / (self.film_size.0.hypot(self.film_size.1) / 2.) * PUPIL_RINGS as Float) as usize;
+        let bounds = self.exit_pupils[ring.min(PUPIL_RINGS - 1)];
+        let (px, py) = (bounds.min.0 + random_float() * (bounds.max.0 - bounds.min.0), bounds.min.1 + random_float() * (bounds.max.1 - bounds.min.1));
+        // the bounds are for film points on the x axis, turn them to where this one is
+        let (sin, cos) = if r > 0. { (y / r, x / r) } else { (0., 1.) };
+        (vec3(cos * px - sin * py, sin * px + cos * py, -self.rear_z()), bounds.area())
+    }
+}
+
+// Intersects the element at z and refracts into it with the given ratio of indices of refraction, None if the
+// ray misses the element's opening or is reflected
+fn pass_element(element: &LensElement, z: Float, o: Vec3, d: Vec3, eta: Float) -> Option<(Vec3, Vec3)> {
+    if element.is_stop() {
+        let t = (z - o.z) / d.z;
+        let p = o + t * d;
+        return (t >= 0. && p.x * p.x + p.y * p.y <= element.aperture * element.aperture / 4.).then_some((p, d));
+    }
+
+    // the sphere's center is radius behind the vertex, convex surfaces have it towards the film
+    let oc = o - vec3(0., 0., z + element.radius);
+    let (a, b, c) = (dot(d, d), 2. * dot(d, oc), dot(oc, oc) - element.radius * element.radius);
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return None;
+    }
+    let (t0, t1) = ((-b - discriminant.sqrt()) / (2. * a), (-b + discriminant.sqrt()) / (2. * a));
+    // the vertex side of the sphere is the nearer one for rays going towards the center
+    let t = if (d.z > 0.) != (element.radius < 0.) { t0 } else { t1 };
+    let p = o + t * d;
+    if t < 0. || p.x * p.x + p.y * p.y > element.aperture * element.aperture / 4. {
+        return None;
+    }
+
+    let wi = -d.normalize();
+    let n = (oc + t * d).normalize();
+    let n = if dot(n, wi) < 0. { -n } else { n };
+    let cos_i = dot(n, wi);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i).max(0.);
+    if sin2_t >= 1. {
+        return None;
+    }
+    Some((p, eta * -wi + (eta * cos_i - (1. - sin2_t).sqrt()) * n))
+}
+
+impl Camera for RealisticCamera {
+    fn ray(&self, st: (Float, Float)) -> Option<Ray> {
+        self.weighted_ray(st).map(|(r, _)| r)
+    }
+
+    fn weighted_ray(&self, (s, t): (Float, Float)) -> Option<(Ray, Float)> {
+        // the lens turns the image upside down, so the top left of the image is at the bottom right of the film
+        let (width, height) = self.film_size;
+        let film = vec3(-(s / self.size.0 as Float - 0.5) * width, (t / self.size.1 as Float - 0.5) * height, 0.);
+        let (rear, area) = self.sample_exit_pupil((film.x, film.y));
+        let (o, d) = self.trace_from_film(film, rear - film)?;
+
+        // light falls off with the fourth power of the cosine to the film normal. The weight is relative to the middle
+        // of the film, so the lens doesn't change the image's brightness as a whole
+        let cos = -(rear - film).normalize().z;
+        let weight = cos * cos * cos * cos * area / self.exit_pupils[0].area();
+        Some((ray(self.frame.origin + self.frame.to_world(o / 1000.), self.frame.to_world(d).normalize(), self.time.random()), weight))
+    }
+
+    fn shutter(&self) -> Interval {
+        self.time
+    }
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.time = time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{camera::{Camera, realistic::{LensElement, RealisticCamera}}, config::{Film, Float}, import::lens::load_lens};
+
+    fn dgauss(focus_distance: Float) -> RealisticCamera {
+        let elements = load_lens("res/lenses/dgauss50.dat").unwrap();
+        RealisticCamera::new(&Film::new((300, 200)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), elements, 24., focus_distance, (0., 0.).into()).unwrap()
+    }
+
+    #[test]
+    fn test_realistic_camera() {
+        let cam = dgauss(2.);
+        let focal_length = cam.focal_length().unwrap();
+        assert!((49. ..51.).contains(&focal_length), "{focal_length}");
+
+        // rays from the middle of the film meet again around the focus distance, up to the lens' aberrations
+        // the exit pupil bounds are a little generous, so some rays still get blocked
+        let spot = |z: Float| (0..100).filter_map(|_| cam.weighted_ray((150., 100.))).map(|(r, weight)| {
+            assert!(r.direction.z < -0.99 && (0.8..=1.).contains(&weight), "{r:?} {weight}");
+            let p = r.at((-z - r.origin.z) / r.direction.z);
+            p.x.hypot(p.y)
+        }).fold(0., Float::max);
+        let focused = spot(2.);
+        assert!(focused < 0.002 && focused < spot(1.8) && focused < spot(2.4), "{focused}");
+
+        // the image comes out the right way up
+        let r = cam.ray((290., 10.)).unwrap();
+        assert!(r.direction.x > 0. && r.direction.y > 0., "{r:?}");
+        let r = cam.ray((10., 190.)).unwrap();
+        assert!(r.direction.x < 0. && r.direction.y < 0., "{r:?}");
+    }
+
+    #[test]
+    fn test_realistic_camera_no_power() {
+        // a stop alone doesn't bend light, so it can't focus anything
+        let elements = vec![LensElement { radius: 0., thickness: 10., ior: 0., aperture: 20. }];
+        assert!(RealisticCamera::new(&Film::new((300, 200)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), elements, 24., 2., (0., 0.).into()).is_none());
+    }
+
+    #[test]
+    fn test_realistic_camera_vignetting() {
+        let cam = dgauss(10.);
+        let light = |st| (0..1000).map(|_| cam.weighted_ray(st).map_or(0., |(_, weight)| weight)).sum::<Float>() / 1000.;
+        let (center, corner) = (light((150., 100.)), light((0., 0.)));
+        assert!(corner < 0.7 * center, "{center} {corner}");
+        assert!(corner > 0., "{corner}");
+    }
+}
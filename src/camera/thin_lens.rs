@@ -0,0 +1,169 @@
+use crate::{animation::{Interpolate, Keyframes}, camera::{Camera, CameraFrame, aperture::Aperture}, config::{Film, Float}, ray::{ray, Ray}, util::{radians, Interval}, vec3::Vec3};
+
+// Everything that places and focuses the camera, vfov and defocus_angle in degrees
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraKey {
+    pub from: Vec3,
+    pub to: Vec3,
+    pub up: Vec3,
+    pub vfov: Float,
+    pub focus_distance: Float,
+    pub defocus_angle: Float,
+}
+
+impl Interpolate for CameraKey {
+    fn interpolate(a: Self, b: Self, t: Float) -> Self {
+        CameraKey {
+            from: Vec3::interpolate(a.from, b.from, t),
+            to: Vec3::interpolate(a.to, b.to, t),
+            up: Vec3::interpolate(a.up, b.up, t),
+            vfov: Float::interpolate(a.vfov, b.vfov, t),
+            focus_distance: Float::interpolate(a.focus_distance, b.focus_distance, t),
+            defocus_angle: Float::interpolate(a.defocus_angle, b.defocus_angle, t),
+        }
+    }
+}
+
+// Height of a full frame 35mm sensor in meters, which relates vfov to a focal length
+const SENSOR_HEIGHT: Float = 0.024;
+
+// The defocus_angle of a real lens stopped down to f_stop, for scenes modelled in meters
+pub fn f_stop_defocus_angle(f_stop: Float, vfov: Float, focus_distance: Float) -> Float {
+    let focal_length = SENSOR_HEIGHT / 2. / radians(vfov / 2.).tan();
+    let aperture_radius = focal_length / f_stop / 2.;
+    2. * (aperture_radius / focus_distance).atan().to_degrees()
+}
+
+// The pixel grid and lens of one camera placement in world space. Film coordinates start at the upper-left corner of
+// the viewport, like for the other projections, so the samplers' (x + 0.5, y + 0.5) is the center of pixel (x, y)
+#[derive(Clone, Copy, Debug)]
+pub struct Viewport {
+    cam_pos: Vec3,
+    viewport_upper_left: Vec3,
+    pixel_delta_u: Vec3,
+    pixel_delta_v: Vec3,
+    defocus_disk_u: Vec3,
+    defocus_disk_v: Vec3,
+}
+
+impl Viewport {
+    pub fn new((width, height): (usize, usize), key: &CameraKey) -> Viewport {
+        let CameraKey { from, to, up, vfov, focus_distance: focal_len, defocus_angle } = *key;
+        let aspect_ratio = width as Float / height as Float;
+        let theta = radians(vfov);
+        let h = (theta / 2.).tan(); // half viewport height divided by focal length
+        let viewport_height = 2. * h * focal_len;
+        let viewport_width = viewport_height * aspect_ratio;
+
+        let CameraFrame { u, v, w, .. } = CameraFrame::look_at(from, to, up);
+
+        let viewport_u = viewport_width * u; // vector across viewport width
+        let viewport_v = viewport_height * -v; // vector across viewport height
+
+        let pixel_delta_u = viewport_u / width as Float; // horizontal vector between viewport pixels
+        let pixel_delta_v = viewport_v / height as Float; // vertical vector between viewport pixels
+
+        let viewport_upper_left = from - (focal_len * w) - viewport_u / 2. - viewport_v / 2.; // upper-left-most corner of the viewport in world space
+
+        let defocus_radius = focal_len * radians(defocus_angle / 2.).tan();
+        let defocus_disk_u = u * defocus_radius;
+        let defocus_disk_v = v * defocus_radius;
+
+        Viewport { cam_pos: from, viewport_upper_left, pixel_delta_u, pixel_delta_v, defocus_disk_u, defocus_disk_v }
+    }
+}
+
+// A perspective camera with a thin lens, which blurs whatever is away from the focus distance
+#[derive(Clone)]
+pub struct ThinLensCamera {
+    viewport: Viewport,
+    size: (usize, usize),
+    time: Interval,
+    // replaces viewport with one evaluated at each ray's time
+    path: Option<Keyframes<CameraKey>>,
+    aperture: Aperture,
+}
+
+impl ThinLensCamera {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(film: &Film, from: Vec3, to: Vec3, up: Vec3, vfov: Float, focal_len: Float, defocus_angle: Float, time: Interval) -> ThinLensCamera {
+        let key = CameraKey { from, to, up, vfov, focus_distance: focal_len, defocus_angle };
+        let size = (film.width, film.height);
+        ThinLensCamera { viewport: Viewport::new(size, &key), size, time, path: None, aperture: Aperture::Disk }
+    }
+
+    // fly-throughs and focus pulls, the camera is placed anew for every ray
+    pub fn animated(film: &Film, path: Keyframes<CameraKey>, time: Interval) -> ThinLensCamera {
+        let size = (film.width, film.height);
+        ThinLensCamera { viewport: Viewport::new(size, &path.at(time.min)), size, time, path: Some(path), aperture: Aperture::Disk }
+    }
+
+    pub fn with_aperture(self, aperture: Aperture) -> ThinLensCamera {
+        ThinLensCamera { aperture, ..self }
+    }
+}
+
+impl Camera for ThinLensCamera {
+    fn ray(&self, (s, t): (Float, Float)) -> Option<Ray> {
+        let offset = self.aperture.sample();
+        let time = self.time.random();
+        let viewport = match &self.path {
+            Some(path) => Viewport::new(self.size, &path.at(time)),
+            None => self.viewport,
+        };
+        let target = viewport.viewport_upper_left + s * viewport.pixel_delta_u + t * viewport.pixel_delta_v;
+        let pos = viewport.cam_pos + offset.x * viewport.defocus_disk_u + offset.y * viewport.defocus_disk_v;
+        Some(ray(pos, target - pos, time))
+    }
+
+    fn shutter(&self) -> Interval {
+        self.time
+    }
+
+    fn set_shutter(&mut self, time: Interval) {
+        self.time = time;
+    }
+}
+
+impl Default for ThinLensCamera {
+    fn default() -> Self {
+        ThinLensCamera::new(&Film::new((1, 1)), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 90., 1., 0., (0., 0.).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{animation::Keyframes, camera::{Camera, thin_lens::{CameraKey, ThinLensCamera, f_stop_defocus_angle}}, config::{Film, Float}, util::radians};
+
+    #[test]
+    fn test_animated_camera() {
+        let film = Film::new((2, 2));
+        let a = CameraKey { from: vec3!(0, 0, 0), to: vec3!(0, 0, -1), up: vec3!(0, 1, 0), vfov: 90., focus_distance: 1., defocus_angle: 0. };
+        let b = CameraKey { from: vec3!(4, 0, 0), to: vec3!(4, 0, -1), vfov: 60., focus_distance: 3., defocus_angle: 10., ..a };
+        let mut cam = ThinLensCamera::animated(&film, Keyframes::new(vec![(0., a), (1., b)]), (0., 0.).into());
+
+        // the corner of a 90 degree square view is at 45 degrees both ways
+        let r = cam.ray((0., 0.)).unwrap();
+        assert_abs_diff_eq!(r.origin, vec3!(0, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(r.direction.normalize(), vec3!(-1, 1, -1).normalize(), epsilon = 1e-9);
+        assert_abs_diff_eq!(cam.ray((1., 1.)).unwrap().direction.normalize(), vec3!(0, 0, -1), epsilon = 1e-9);
+
+        // halfway along, rays through the middle of the image meet two units ahead whatever their origin on the lens
+        cam.set_shutter((0.5, 0.5).into());
+        for _ in 0..10 {
+            let r = cam.ray((1., 1.)).unwrap();
+            assert!((r.origin - vec3!(2, 0, 0)).length() <= 2. * radians(2.5).tan() + 1e-9);
+            assert_abs_diff_eq!(r.at((-2. - r.origin.z) / r.direction.z), vec3!(2, 0, -2), epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_f_stop_defocus_angle() {
+        // a 50mm lens at f/2 has a 25mm aperture, seen from 10m away
+        let vfov = 2. * (0.012 as Float / 0.05).atan().to_degrees();
+        assert_abs_diff_eq!(f_stop_defocus_angle(2., vfov, 10.), 2. * (0.0125 as Float / 10.).atan().to_degrees(), epsilon = 1e-12);
+        assert!(f_stop_defocus_angle(8., vfov, 10.) < f_stop_defocus_angle(2., vfov, 10.));
+    }
+}
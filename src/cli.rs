@@ -0,0 +1,211 @@
+use std::{path::PathBuf, str::FromStr, thread::available_parallelism};
+
+use clap::{Parser, ValueEnum, builder::RangedU64ValueParser};
+
+use crate::{config::Float, hit::bvh::{BvhLayout, BvhOptions, BvhSplit}, import::scene::RenderSettings, integrator::{EvaluatorKind, IntegratorSettings}, sampler::SamplerKind};
+
+#[derive(Parser, Debug)]
+#[command(about = "Renders a built-in scene or a scene file")]
+pub struct Args {
+    /// Built-in scene (random, simple, cornell, forest), TOML scene file or PLY mesh
+    #[arg(default_value = "random")]
+    pub scene: String,
+
+    /// Image width, overrides the scene file
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub width: Option<usize>,
+
+    /// Image height, overrides the scene file
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub height: Option<usize>,
+
+    /// Samples every pixel gets regardless of its variance, overrides the scene file
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub min_samples: Option<usize>,
+
+    /// Upper bound on samples per pixel, overrides the scene file
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_samples: Option<usize>,
+
+    /// Maximum path length, overrides the scene file
+    #[arg(long)]
+    pub max_bounces: Option<usize>,
+
+    /// Pixel sampler, overrides the scene file
+    #[arg(long)]
+    pub sampler: Option<SamplerKind>,
+
+    /// Ray evaluator, overrides the scene file
+    #[arg(long)]
+    pub evaluator: Option<EvaluatorKind>,
+
+    /// Pixels stop sampling once their variance drops below this, overrides the scene file
+    #[arg(long, value_parser = positive_float)]
+    pub variance_target: Option<Float>,
+
+    /// Worker threads, defaults to the number of available cores
+    #[arg(long, default_value_t = available_parallelism().map_or(1, |n| n.get()), value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub threads: usize,
+
+    /// Tile size as WIDTHxHEIGHT, or a single number for square tiles
+    #[arg(long, default_value = "50x50")]
+    pub tile_size: TileSize,
+
+    /// How the BVH is split
+    #[arg(long, default_value = "sah")]
+    pub bvh: BvhSplit,
+
+    /// How the BVH nodes are stored
+    #[arg(long, default_value = "flat")]
+    pub bvh_layout: BvhLayout,
+
+    /// Number of buckets the SAH evaluates per BVH node
+    #[arg(long, default_value_t = BvhOptions::default().bins, value_parser = RangedU64ValueParser::<usize>::new().range(2..))]
+    pub bvh_bins: usize,
+
+    /// Maximum number of objects per BVH leaf
+    #[arg(long, default_value_t = BvhOptions::default().max_leaf_size)]
+    pub bvh_leaf_size: usize,
+
+    /// Time every BVH split and layout on the scene instead of rendering it
+    #[cfg(feature = "bench")]
+    #[arg(long)]
+    pub compare_bvh: bool,
+
+    /// Output image path
+    #[arg(short, long, default_value = "out/out.png")]
+    pub output: PathBuf,
+
+    /// Render this many frames as an animation, numbered after the output path, overrides the scene file
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub frames: Option<u32>,
+
+    /// Skip the frames of an animation up to the last one already written
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Output format, guessed from the output path if not given
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// Don't open preview windows
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Expose the image for its average luminance, overrides the scene's exposure settings
+    #[arg(long)]
+    pub auto_exposure: bool,
+
+    /// Seed for the random number generators
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Ppm,
+}
+
+impl Args {
+    // out/anim.png becomes out/anim_0000.png, out/anim_0001.png, ...
+    pub fn frame_output(&self, frame: usize) -> PathBuf {
+        let stem = self.output.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.output.extension() {
+            Some(extension) => format!("{stem}_{frame:04}.{}", extension.to_string_lossy()),
+            None => format!("{stem}_{frame:04}"),
+        };
+        self.output.with_file_name(name)
+    }
+
+    // the options given here win over the scene file's render settings
+    pub fn integrator_settings(&self, render_settings: RenderSettings) -> Result<IntegratorSettings, String> {
+        let min_samples = self.min_samples.unwrap_or(render_settings.min_samples);
+        let max_samples = self.max_samples.unwrap_or(render_settings.max_samples);
+        if min_samples > max_samples {
+            return Err(format!("min_samples ({min_samples}) must not be more than max_samples ({max_samples})"));
+        }
+        Ok(IntegratorSettings {
+            tile_size: (self.tile_size.0, self.tile_size.1),
+            workers: self.threads,
+            min_samples,
+            max_samples,
+            variance_target: self.variance_target.unwrap_or(render_settings.variance_target),
+            max_bounces: self.max_bounces.unwrap_or(render_settings.max_bounces),
+            preview: !self.headless,
+        })
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.format.unwrap_or(match self.output.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("ppm") => OutputFormat::Ppm,
+            _ => OutputFormat::Png,
+        })
+    }
+}
+
+fn positive_float(s: &str) -> Result<Float, String> {
+    match s.parse::<Float>() {
+        Ok(value) if value > 0. => Ok(value),
+        Ok(value) => Err(format!("expected a positive number, found {value}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileSize(pub usize, pub usize);
+
+impl FromStr for TileSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| v.trim().parse::<usize>().ok().filter(|&v| v > 0).ok_or_else(|| format!("invalid tile size '{s}'"));
+        match s.split_once('x') {
+            Some((w, h)) => Ok(TileSize(parse(w)?, parse(h)?)),
+            None => parse(s).map(|n| TileSize(n, n)),
+        }
+    }
+}
+
+#[test]
+fn test_args() {
+    let args = Args::try_parse_from(["main", "scenes/simple.toml", "--tile-size", "32x16", "--output", "out/a.PPM", "--headless", "--sampler", "center", "--max-bounces", "8"]).unwrap();
+    assert_eq!(args.scene, "scenes/simple.toml");
+    assert_eq!(args.sampler, Some(SamplerKind::Center));
+    assert_eq!(args.max_bounces, Some(8));
+    assert_eq!(args.evaluator, None);
+    assert_eq!(args.bvh, BvhSplit::Sah);
+    assert_eq!(args.tile_size, TileSize(32, 16));
+    assert_eq!(args.output_format(), OutputFormat::Ppm);
+    assert!(args.headless && !args.auto_exposure);
+
+    let args = Args::try_parse_from(["main", "--tile-size", "64", "--format", "ppm", "--bvh", "median", "--bvh-leaf-size", "1", "--auto-exposure"]).unwrap();
+    assert_eq!((args.bvh, args.bvh_leaf_size), (BvhSplit::Median, 1));
+    assert!(args.auto_exposure);
+    assert_eq!(args.scene, "random");
+    assert_eq!(args.tile_size, TileSize(64, 64));
+    assert_eq!(args.output_format(), OutputFormat::Ppm);
+
+    let args = Args::try_parse_from(["main", "--frames", "24", "--resume", "-o", "out/anim.png"]).unwrap();
+    assert_eq!((args.frames, args.resume), (Some(24), true));
+    assert_eq!(args.frame_output(7), PathBuf::from("out/anim_0007.png"));
+    assert!(Args::try_parse_from(["main", "--frames", "0"]).is_err());
+
+    let args = Args::try_parse_from(["main", "--threads", "3", "--width", "320", "--height", "200", "--max-samples", "64"]).unwrap();
+    assert_eq!((args.threads, args.width, args.height, args.max_samples), (3, Some(320), Some(200), Some(64)));
+    for option in ["--threads", "--width", "--height", "--min-samples", "--max-samples", "--variance-target", "--bvh-bins"] {
+        assert!(Args::try_parse_from(["main", option, "0"]).is_err());
+    }
+    assert!(Args::try_parse_from(["main", "--bvh-bins", "1"]).is_err());
+    assert!(Args::try_parse_from(["main", "--variance-target", "NaN"]).is_err());
+
+    let render_settings = RenderSettings { min_samples: 16, max_samples: 32, ..RenderSettings::default() };
+    let args = Args::try_parse_from(["main", "--max-samples", "128", "--variance-target", "0.01", "--headless"]).unwrap();
+    let settings = args.integrator_settings(render_settings).unwrap();
+    assert_eq!((settings.min_samples, settings.max_samples, settings.variance_target, settings.preview), (16, 128, 0.01, false));
+    let args = Args::try_parse_from(["main", "--min-samples", "64"]).unwrap();
+    assert_eq!(args.integrator_settings(render_settings).unwrap_err(), "min_samples (64) must not be more than max_samples (32)");
+
+    assert!(Args::try_parse_from(["main", "--tile-size", "0x4"]).is_err());
+    assert!(Args::try_parse_from(["main", "--format", "jpg"]).is_err());
+}
@@ -27,6 +27,11 @@ pub fn random_in_range(min: Float, max: Float) -> Self {
     pub fn grayscale(v: Float) -> Self {
         color_rgb(v, v, v)
     }
+
+    // relative luminance with Rec. 709 primaries
+    pub fn luminance(self) -> Float {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
 }
 
 impl From<(Float, Float, Float)> for ColorRgb {
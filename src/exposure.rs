@@ -0,0 +1,127 @@
+use crate::config::{Film, Float};
+
+// Photographic exposure settings, the shutter speed in seconds. Scenes using these should give radiance in cd/m²
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exposure {
+    pub f_stop: Float,
+    pub shutter_speed: Float,
+    pub iso: Float,
+}
+
+impl Exposure {
+    // exposure value at ISO 100, settings that let in the same amount of light share it
+    pub fn ev100(&self) -> Float {
+        (self.f_stop * self.f_stop / self.shutter_speed * 100. / self.iso).log2()
+    }
+
+    pub fn scale(&self) -> Float {
+        ev100_scale(self.ev100())
+    }
+}
+
+// The factor radiance is multiplied by before it's encoded. Following saturation-based ISO speed,
+// the brightest radiance the sensor can take without clipping maps to 1
+pub fn ev100_scale(ev100: Float) -> Float {
+    1. / (1.2 * ev100.exp2())
+}
+
+// How the film's mean radiance is turned into image brightness
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Metering {
+    // radiance as it is
+    #[default]
+    None,
+    Manual(Exposure),
+    // metered from the rendered film, then brightened by compensation stops
+    Auto { compensation: Float },
+}
+
+impl Metering {
+    pub fn scale(&self, film: &Film) -> Float {
+        match self {
+            Metering::None => 1.,
+            Metering::Manual(exposure) => exposure.scale(),
+            Metering::Auto { compensation } => ev100_scale(metered_ev100(film) - compensation),
+        }
+    }
+}
+
+// What a reflected light meter would read, they're calibrated with K = 12.5
+pub fn metered_ev100(film: &Film) -> Float {
+    (average_luminance(film) * 100. / 12.5).log2()
+}
+
+// log2 luminance covered by the histogram, in half-stop bins
+const HISTOGRAM_RANGE: (Float, Float) = (-16., 16.);
+const HISTOGRAM_BINS: usize = 64;
+// the darkest and brightest pixels are left out so dark corners or a visible light don't throw the average off
+const HISTOGRAM_PERCENTILES: (Float, Float) = (0.1, 0.9);
+
+// Geometric mean of the film's luminance, taken from a histogram of its pixel means. Black pixels are
+// left out altogether, they're the background or haven't found any light yet
+pub fn average_luminance(film: &Film) -> Float {
+    let (min, max) = HISTOGRAM_RANGE;
+    let bin_width = (max - min) / HISTOGRAM_BINS as Float;
+    let mut histogram = [0; HISTOGRAM_BINS];
+    for l in film.pix.iter().map(|sc| sc.mean().luminance()).filter(|&l| l > 0.) {
+        let bin = ((l.log2() - min) / bin_width).clamp(0., (HISTOGRAM_BINS - 1) as Float) as usize;
+        histogram[bin] += 1;
+    }
+
+    let total = histogram.iter().sum::<usize>() as Float;
+    if total == 0. {
+        return 1.;
+    }
+    let (low, high) = (HISTOGRAM_PERCENTILES.0 * total, HISTOGRAM_PERCENTILES.1 * total);
+    let (mut seen, mut sum, mut weight) = (0., 0., 0.);
+    for (i, &count) in histogram.iter().enumerate() {
+        // how many pixels of this bin fall between the percentiles
+        let inside = (seen + count as Float).min(high) - seen.max(low);
+        if inside > 0. {
+            sum += inside * (min + (i as Float + 0.5) * bin_width);
+            weight += inside;
+        }
+        seen += count as Float;
+    }
+    (sum / weight).exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{config::{Film, Float}, exposure::{average_luminance, ev100_scale, Exposure, Metering}};
+
+    #[test]
+    fn test_exposure() {
+        assert_abs_diff_eq!(Exposure { f_stop: 1., shutter_speed: 1., iso: 100. }.ev100(), 0.);
+        // sunny 16
+        assert_abs_diff_eq!(Exposure { f_stop: 16., shutter_speed: 0.01, iso: 100. }.ev100(), 14.64, epsilon = 0.01);
+        // a stop more light from the shutter makes up for a stop less sensitivity
+        let a = Exposure { f_stop: 4., shutter_speed: 0.02, iso: 200. };
+        let b = Exposure { shutter_speed: 0.04, iso: 100., ..a };
+        assert_abs_diff_eq!(a.scale(), b.scale(), epsilon = 1e-12);
+        assert_abs_diff_eq!(ev100_scale(a.ev100() + 1.), a.scale() / 2., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_auto_exposure() {
+        let mut film = Film::new((10, 10));
+        let l = (0.25 as Float).exp2();
+        for x in 0..10 {
+            for y in 0..10 {
+                let c = match (x, y) {
+                    (0, 0..5) => 0.,
+                    (9, 0..5) => 1000.,
+                    _ => l,
+                };
+                film.add_sample((x, y), (c, c, c).into());
+            }
+        }
+        // the black and blown out pixels don't count
+        assert_abs_diff_eq!(average_luminance(&film), l, epsilon = 1e-9);
+        let scale = Metering::Auto { compensation: 1. }.scale(&film);
+        assert_abs_diff_eq!(scale, 2. * ev100_scale((l * 8.).log2()), epsilon = 1e-9);
+        assert_eq!(Metering::None.scale(&film), 1.);
+    }
+}
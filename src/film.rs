@@ -257,8 +257,8 @@ fn test_sampling() {
         f.add_sample((0, 0), (0., 0.5, 1.).into());
         let v = f.to_rgb8(SampleCollector::gamma_corrected_mean);
         assert_eq!(v.len(), 3);
-        for c in 0..3 { 
-            assert_eq!(v[c], map_color_component(0.5));
+        for c in v { 
+            assert_eq!(c, map_color_component(0.5));
         }
     }
 }
\ No newline at end of file
@@ -1,22 +1,65 @@
-use crate::{config::Float, material::simple::Material, ray::Ray, vec3::{Point, Vec3}};
+use crate::{config::{Color, Float}, hit::sphere::sphere_uv, material::MaterialHandle, ray::Ray, vec3::{Point, Vec3}};
 
 pub mod sphere;
 pub mod quad;
-mod aabb;
+pub mod triangle;
+pub mod aabb;
 pub mod bvh;
+pub mod flat_bvh;
+pub mod wide_bvh;
 pub mod instance;
 
 #[derive(PartialEq, Debug)]
 pub struct HitRecord {
     pub t: Float,
-    pub material: Material,
+    pub material: MaterialHandle,
+    // faces the incoming ray, front_face tells whether that's the outside of the surface
     pub normal: Vec3,
+    pub front_face: bool,
     pub pos: Point,
-    pub uv: (Float, Float),
+    pub uv: Uv,
+    // interpolated from the vertices of meshes that have colors
+    pub vertex_color: Option<Color>,
+}
+
+impl HitRecord {
+    // the normal on the outside of the surface, which dielectrics need to tell entering from leaving
+    pub fn outward_normal(&self) -> Vec3 {
+        if self.front_face { self.normal } else { -self.normal }
+    }
+}
+
+// Texture coordinates of a hit. Spheres keep the hit point on their unit sphere and only work out the angles when a
+// material asks for them, as most candidate hits are never shaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Uv {
+    Coords(Float, Float),
+    Sphere(Vec3),
+}
+
+impl Uv {
+    pub fn coords(self) -> (Float, Float) {
+        match self {
+            Uv::Coords(u, v) => (u, v),
+            Uv::Sphere(p) => sphere_uv(p),
+        }
+    }
+}
+
+impl From<(Float, Float)> for Uv {
+    fn from((u, v): (Float, Float)) -> Self {
+        Uv::Coords(u, v)
+    }
 }
 
 pub trait Hit {
     fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord>;
+
+    // any-hit query for shadow rays and the like, which only need to know whether something is in the way.
+    // Implementors should override this when they can skip building the HitRecord or stop searching early.
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.hit(r, t_min, t_max).is_some()
+    }
 }
 
 impl<T: Hit> Hit for Vec<T> {
@@ -33,6 +76,10 @@ fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
 
         res
     }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.iter().any(|s| s.occluded(r, t_min, t_max))
+    }
 }
 
 // TODO originally this was intended to capture the idea of 'something that can produce a bounding volume',
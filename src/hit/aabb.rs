@@ -1,8 +1,9 @@
 use std::{mem::swap, ops::Index};
 
-use crate::{config::Float, ray::Ray, util::Interval, vec3::Point};
+use crate::{config::Float, ray::Ray, util::Interval, vec3::{Point, vec3}};
 
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct AABB {
     pub x: Interval,
@@ -57,6 +58,24 @@ fn pad_to_minimum(&mut self) {
         }
     }
 
+    pub fn surface_area(&self) -> Float {
+        let (x, y, z) = (self.x.length(), self.y.length(), self.z.length());
+        2. * (x * y + y * z + z * x)
+    }
+
+    // the i-th corner takes the max of x, y and z where bits 0, 1 and 2 of i are set
+    pub fn corners(&self) -> [Point; 8] {
+        std::array::from_fn(|i| vec3(
+            if i & 1 == 0 { self.x.min } else { self.x.max },
+            if i & 2 == 0 { self.y.min } else { self.y.max },
+            if i & 4 == 0 { self.z.min } else { self.z.max },
+        ))
+    }
+
+    pub fn centroid(&self) -> Point {
+        vec3((self.x.min + self.x.max) / 2., (self.y.min + self.y.max) / 2., (self.z.min + self.z.max) / 2.)
+    }
+
     pub fn longest_axis(&self) -> usize {
         let x_len = self.x.length();
         let y_len = self.y.length();
@@ -96,6 +115,54 @@ pub fn intersects(&self, r: Ray, mut t_min: Float, mut t_max: Float) -> bool {
     }
 }
 
+// N boxes stored per axis, so the slab test for all of them runs as one lane loop per axis that the compiler
+// turns into SIMD instructions. Unused lanes are infinitely far away and never hit.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WideAABB<const N: usize> {
+    pub min: [[Float; N]; 3],
+    pub max: [[Float; N]; 3],
+}
+
+impl<const N: usize> Default for WideAABB<N> {
+    fn default() -> Self {
+        WideAABB { min: [[Float::INFINITY; N]; 3], max: [[Float::INFINITY; N]; 3] }
+    }
+}
+
+impl<const N: usize> WideAABB<N> {
+    pub fn set(&mut self, lane: usize, aabb: AABB) {
+        for axis in 0..3 {
+            self.min[axis][lane] = aabb[axis].min;
+            self.max[axis][lane] = aabb[axis].max;
+        }
+    }
+
+    pub fn get(&self, lane: usize) -> AABB {
+        let interval = |axis: usize| (self.min[axis][lane], self.max[axis][lane]).into();
+        AABB { x: interval(0), y: interval(1), z: interval(2) }
+    }
+
+    // AABB::intersects for every lane, returning the distance the ray enters each box at, or infinity if it
+    // misses. Checking for a miss only after all axes gives the same answer, as the interval only ever shrinks.
+    #[inline(always)]
+    pub fn entry_distances(&self, r: Ray, t_min: Float, t_max: Float) -> [Float; N] {
+        let mut near = [t_min; N];
+        let mut far = [t_max; N];
+        for axis in 0..3 {
+            let (origin, inv_d) = (r.origin[axis], r.inv_direction[axis]);
+            for i in 0..N {
+                let t0 = (self.min[axis][i] - origin) * inv_d;
+                let t1 = (self.max[axis][i] - origin) * inv_d;
+                let (t0, t1) = if t1 < t0 { (t1, t0) } else { (t0, t1) };
+                near[i] = if t0 > near[i] { t0 } else { near[i] };
+                far[i] = if t1 < far[i] { t1 } else { far[i] };
+            }
+        }
+        std::array::from_fn(|i| if far[i] <= near[i] { Float::INFINITY } else { near[i] })
+    }
+}
+
 impl Index<usize> for AABB {
     type Output = Interval;
 
@@ -1,4 +1,7 @@
-use std::{fmt::Debug, mem::take};
+use std::{fmt::{Debug, Display}, mem::take, time::{Duration, Instant}};
+
+use clap::ValueEnum;
+use serde::Deserialize;
 
 use crate::{config::Float, hit::{Bound, Hit, HitRecord, aabb::AABB, sphere::Sphere}, ray::Ray};
 
@@ -66,6 +69,281 @@ pub fn from_slice(objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> Se
     }
 }
 
+// Leaf holding several objects, only built by BvhBuilder when its leaf size allows it
+pub struct BvhLeaf {
+    pub aabb: AABB,
+    pub objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>,
+}
+
+impl Hit for BvhLeaf {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        if !self.aabb.intersects(r, t_min, t_max) {
+            return None;
+        }
+
+        let mut res = None;
+        let mut cur_t_max = t_max;
+        for o in &self.objects {
+            if let Some(hit) = o.hit(r, t_min, cur_t_max) {
+                cur_t_max = hit.t;
+                res = Some(hit);
+            }
+        }
+        res
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.aabb.intersects(r, t_min, t_max) && self.objects.iter().any(|o| o.occluded(r, t_min, t_max))
+    }
+}
+
+impl Bound for BvhLeaf {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        self.aabb
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BvhSplit {
+    // split at the median along the longest axis, like Bvh::from_slice
+    Median,
+    // binned surface area heuristic
+    #[default]
+    Sah,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BvhLayout {
+    // tree of boxed nodes (Bvh and BvhLeaf)
+    Tree,
+    // nodes in a single Vec (FlatBvh)
+    #[default]
+    Flat,
+    // flat, with 4 or 8 children per node (WideBvh)
+    Wide4,
+    Wide8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BvhOptions {
+    pub split: BvhSplit,
+    pub layout: BvhLayout,
+    // number of buckets the SAH splits are evaluated at, per node
+    pub bins: usize,
+    // leaves may hold up to this many objects, as long as that's cheaper than splitting them
+    pub max_leaf_size: usize,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        // single object leaves rendered fastest for the random scene, larger leaves are worth a try for meshes
+        BvhOptions { split: BvhSplit::Sah, layout: BvhLayout::Flat, bins: 16, max_leaf_size: 1 }
+    }
+}
+
+// Summed over every tree built with the same builder. The cost is the expected cost of tracing a ray that
+// hits the root, assuming intersecting a node's box costs as much as intersecting an object.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub objects: usize,
+    pub max_depth: usize,
+    pub cost: Float,
+    pub build_time: Duration,
+}
+
+impl Display for BvhStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} objects in {} nodes and {} leaves, depth {}, SAH cost {:.2}, built in {:?}",
+            self.objects, self.nodes, self.leaves, self.max_depth, self.cost, self.build_time)
+    }
+}
+
+const TRAVERSAL_COST: Float = 1.;
+const INTERSECTION_COST: Float = 1.;
+pub const MAX_SAH_DEPTH: usize = 40;
+
+#[derive(Default)]
+pub struct BvhBuilder {
+    pub options: BvhOptions,
+    pub stats: BvhStats,
+}
+
+pub fn enclosing_bound(objects: &[Box<dyn AxisAlignedBound + Send + Sync>]) -> AABB {
+    objects.iter().skip(1).fold(objects[0].bound(), |aabb, o| AABB::enclosing(aabb, o.bound()))
+}
+
+impl BvhBuilder {
+    pub fn new(options: BvhOptions) -> Self {
+        BvhBuilder { options, stats: BvhStats::default() }
+    }
+
+    pub fn build(&mut self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> Box<dyn AxisAlignedBound + Send + Sync> {
+        assert!(!objects.is_empty(), "Cannot create BVH from an empty slice");
+        let start = Instant::now();
+
+        let root_area = enclosing_bound(objects).surface_area();
+        let (node, cost) = self.build_node(objects, 1);
+
+        self.stats.objects += objects.len();
+        self.stats.cost += cost / root_area;
+        self.stats.build_time += start.elapsed();
+        node
+    }
+
+    // convenience for scenes: no objects becomes an empty Vec, a single object doesn't need a tree
+    pub fn build_objects(&mut self, objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>) -> Box<dyn Hit + Send + Sync> {
+        if objects.is_empty() {
+            Box::new(Vec::<Sphere>::new())
+        } else {
+            self.build_layout(objects)
+        }
+    }
+
+    // builds a tree in the configured layout, or returns the object itself if there is only one. There is no bound
+    // for nothing at all, so empty meshes have to be rejected before they get here, like SceneFile::build does
+    pub fn build_layout(&mut self, mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>) -> Box<dyn AxisAlignedBound + Send + Sync> {
+        match objects.len() {
+            0 => panic!("Cannot create BVH from an empty slice"),
+            1 => objects.pop().unwrap(),
+            _ => match self.options.layout {
+                BvhLayout::Tree => self.build(&mut objects),
+                BvhLayout::Flat => Box::new(self.build_flat(&mut objects)),
+                BvhLayout::Wide4 => Box::new(self.build_wide::<4>(&mut objects)),
+                BvhLayout::Wide8 => Box::new(self.build_wide::<8>(&mut objects)),
+            },
+        }
+    }
+
+    // returns the node and its cost, weighted by surface area
+    fn build_node(&mut self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], depth: usize) -> (Box<dyn AxisAlignedBound + Send + Sync>, Float) {
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+
+        let aabb = enclosing_bound(objects);
+        if objects.len() == 1 {
+            self.stats.leaves += 1;
+            return (take(&mut objects[0]), INTERSECTION_COST * aabb.surface_area());
+        }
+
+        match self.split(objects, depth) {
+            Some((mid, _)) => {
+                self.stats.nodes += 1;
+                let (left_objects, right_objects) = objects.split_at_mut(mid);
+                let (left, left_cost) = self.build_node(left_objects, depth + 1);
+                let (right, right_cost) = self.build_node(right_objects, depth + 1);
+                (Box::new(Bvh { aabb, left, right }), TRAVERSAL_COST * aabb.surface_area() + left_cost + right_cost)
+            },
+            None => {
+                self.stats.leaves += 1;
+                let cost = INTERSECTION_COST * objects.len() as Float * aabb.surface_area();
+                (Box::new(BvhLeaf { aabb, objects: objects.iter_mut().map(take).collect() }), cost)
+            },
+        }
+    }
+
+    // Partitions the objects of a new node and returns where the right child's objects start along with the axis they
+    // were split on, or None if they should go into a leaf. SAH trees can get arbitrarily deep, so past MAX_SAH_DEPTH
+    // we fall back to median splits.
+    pub fn split(&self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], depth: usize) -> Option<(usize, usize)> {
+        match self.options.split {
+            BvhSplit::Sah if depth < MAX_SAH_DEPTH => self.sah_split(objects),
+            _ => (objects.len() > self.options.max_leaf_size.max(1)).then(|| Self::median_split(objects, enclosing_bound(objects))),
+        }
+    }
+
+    // like split, but leaves never get more than max_objects objects
+    pub fn split_at_most(&self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], depth: usize, max_objects: usize) -> Option<(usize, usize)> {
+        match objects.len() {
+            1 => None,
+            n if n > max_objects => Some(self.split(objects, depth).unwrap_or_else(|| Self::median_split(objects, enclosing_bound(objects)))),
+            _ => self.split(objects, depth),
+        }
+    }
+
+    fn median_split(objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], aabb: AABB) -> (usize, usize) {
+        let axis = aabb.longest_axis();
+        objects.sort_by(|a, b| (a.bound() as AABB)[axis].min.partial_cmp(&(b.bound() as AABB)[axis].min).unwrap());
+        (objects.len() / 2, axis)
+    }
+
+    // Buckets the object centroids along the axis they're most spread out on, and picks the bucket boundary
+    // minimizing the SAH cost. Returns None if a leaf is cheaper, partitions the objects otherwise.
+    fn sah_split(&self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> Option<(usize, usize)> {
+        let n = objects.len();
+        let centroid_bound = objects.iter().skip(1).fold(
+            AABB::from_points(objects[0].bound().centroid(), objects[0].bound().centroid()),
+            |aabb, o| AABB::enclosing(aabb, AABB::from_points(o.bound().centroid(), o.bound().centroid()))
+        );
+        let axis = centroid_bound.longest_axis();
+        let (min, extent) = (centroid_bound[axis].min, centroid_bound[axis].length());
+
+        // all centroids (nearly) in the same spot, no bucketing will separate them
+        if extent <= AABB::MIN_LENGTH {
+            return (n > self.options.max_leaf_size).then_some((n / 2, axis));
+        }
+
+        let bins = self.options.bins.max(2);
+        let bin_of = |o: &(dyn AxisAlignedBound + Send + Sync)| (((o.bound().centroid()[axis] - min) / extent * bins as Float) as usize).min(bins - 1);
+
+        let mut bin_bounds: Vec<Option<AABB>> = vec![None; bins];
+        let mut bin_counts = vec![0; bins];
+        for o in objects.iter() {
+            let b = bin_of(o.as_ref());
+            bin_counts[b] += 1;
+            bin_bounds[b] = Some(bin_bounds[b].map_or(o.bound(), |aabb| AABB::enclosing(aabb, o.bound())));
+        }
+
+        let grow = |acc: Option<AABB>, b: Option<AABB>| match (acc, b) {
+            (Some(a), Some(b)) => Some(AABB::enclosing(a, b)),
+            (a, b) => a.or(b),
+        };
+
+        // right to left sweep for the areas and counts to the right of every boundary, then left to right for the costs
+        let mut right_area = vec![0.; bins];
+        let mut right_count = vec![0; bins];
+        let (mut acc, mut count) = (None, 0);
+        for i in (1..bins).rev() {
+            acc = grow(acc, bin_bounds[i]);
+            count += bin_counts[i];
+            right_area[i] = acc.map_or(0., |a: AABB| a.surface_area());
+            right_count[i] = count;
+        }
+
+        let parent_area = enclosing_bound(objects).surface_area();
+        let (mut acc, mut count) = (None, 0);
+        let mut best: Option<(usize, Float)> = None;
+        for i in 1..bins {
+            acc = grow(acc, bin_bounds[i - 1]);
+            count += bin_counts[i - 1];
+            if count == 0 || right_count[i] == 0 { continue; }
+
+            let left_area = acc.map_or(0., |a: AABB| a.surface_area());
+            let cost = TRAVERSAL_COST + INTERSECTION_COST * (left_area * count as Float + right_area[i] * right_count[i] as Float) / parent_area;
+            if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+                best = Some((i, cost));
+            }
+        }
+
+        let (split_bin, cost) = best?;
+        if n <= self.options.max_leaf_size && cost >= INTERSECTION_COST * n as Float {
+            return None;
+        }
+
+        let mut mid = 0;
+        for i in 0..n {
+            if bin_of(objects[i].as_ref()) < split_bin {
+                objects.swap(i, mid);
+                mid += 1;
+            }
+        }
+        Some((mid, axis))
+    }
+}
+
 impl Hit for Bvh {
     fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         if !self.aabb.intersects(r, t_min, t_max) {
@@ -87,6 +365,10 @@ fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
             (None, None) => None,
         }
     }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.aabb.intersects(r, t_min, t_max) && (self.left.occluded(r, t_min, t_max) || self.right.occluded(r, t_min, t_max))
+    }
 }
 
 impl Bound for Bvh {
@@ -107,7 +389,7 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 }
 
 #[cfg(test)]
-use crate::{hit::sphere::sphere, material::simple::Material};
+use crate::{hit::sphere::sphere, material::NO_MATERIAL};
 
 #[test]
 fn test_aabb() {
@@ -122,12 +404,16 @@ fn test_aabb() {
     let aabb2 = AABB::from_points(a, c);
     assert!(aabb2.intersects(ray!((0.5, 0.5, -0.5) -> (0, 0, 1)), 0., Float::MAX));
     assert!(!aabb2.intersects(ray!((1.5, 1.5, -0.5) -> (0, 0, 1)), 0., Float::MAX));
+
+    let aabb3 = AABB::from_points(vec3!(0, 0, 0), vec3!(1, 2, 3));
+    assert_eq!(aabb3.surface_area(), 22.);
+    assert_eq!(aabb3.centroid(), vec3!(0.5, 1, 1.5));
 }
 
 #[test]
 fn test_bvh_hit() {
-    let s1 = sphere((0., 0., 0.), 1., Material::None);
-    let s2 = sphere((4., 0., 0.), 1., Material::None);
+    let s1 = sphere((0., 0., 0.), 1., NO_MATERIAL);
+    let s2 = sphere((4., 0., 0.), 1., NO_MATERIAL);
     let bvh = Bvh {
         aabb: AABB::enclosing(s1.bound(), s2.bound()),
         left: Box::new(s1),
@@ -150,10 +436,66 @@ fn test_bvh_hit() {
     let bvh2 = Bvh::from_slice(&mut [Box::new(s1), Box::new(s2)]);
     assert_eq!(bvh.aabb, bvh2.aabb);
 
-    let s3 = sphere((2., 2., 0.), 1., Material::None);
+    let s3 = sphere((2., 2., 0.), 1., NO_MATERIAL);
     let bvh3 = Bvh::from_slice(&mut [Box::new(s1), Box::new(s2), Box::new(s3)]);
     assert_eq!(bvh3.hit(r1, 0., Float::MAX), s1.hit(r1, 0., Float::MAX));
     assert_eq!(bvh3.hit(r2, 0., Float::MAX), s2.hit(r2, 0., Float::MAX));
     assert_eq!(bvh3.hit(r3, 0., Float::MAX), s3.hit(r3, 0., Float::MAX));
     assert!(bvh3.hit(ray!((2, 0, 0) -> (0, 0, 1)), 0., Float::MAX).is_none());
-}
\ No newline at end of file
+}
+#[test]
+fn test_bvh_occluded() {
+    use crate::{hit::{instance::Translate, triangle::TriangleMesh}, random::random_in_range, ray::ray, vec3::{random_unit_vector, vec3}};
+
+    let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = (0..20).map(|i| {
+        Box::new(sphere((random_in_range(-5., 5.), random_in_range(-5., 5.), random_in_range(-5., 5.)), 0.1 * i as Float + 0.1, NO_MATERIAL)) as Box<dyn AxisAlignedBound + Send + Sync>
+    }).collect();
+    let mesh = TriangleMesh::new(vec![vec3!(-3, -3, 0), vec3!(3, -3, 0), vec3!(0, 3, 0)], vec![[0, 1, 2]], NO_MATERIAL);
+    objects.push(Box::new(Translate { offset: vec3!(0, 0, 1), object: mesh.into_objects().pop().unwrap() }));
+    let bvh = Bvh::from_slice(&mut objects);
+
+    for _ in 0..1000 {
+        let r = ray(vec3(random_in_range(-8., 8.), random_in_range(-8., 8.), random_in_range(-8., 8.)), random_unit_vector(), 0.);
+        let t_max = random_in_range(0., 10.);
+        assert_eq!(bvh.occluded(r, 0.001, t_max), bvh.hit(r, 0.001, t_max).is_some());
+    }
+}
+
+#[test]
+fn test_bvh_builder() {
+    use crate::{random::random_in_range, ray::ray, vec3::{random_unit_vector, vec3}};
+
+    // a huge ground sphere among small ones, which the median split handles badly
+    let spheres: Vec<_> = std::iter::once(sphere((0., -1000., 0.), 1000., NO_MATERIAL))
+        .chain((0..200).map(|_| sphere((random_in_range(-10., 10.), 0.2, random_in_range(-10., 10.)), 0.2, NO_MATERIAL)))
+        .collect();
+    let objects = || spheres.iter().map(|&s| Box::new(s) as Box<dyn AxisAlignedBound + Send + Sync>).collect::<Vec<_>>();
+
+    let mut costs = Vec::new();
+    for split in [BvhSplit::Median, BvhSplit::Sah] {
+        for max_leaf_size in [1, 4] {
+            let mut builder = BvhBuilder::new(BvhOptions { split, bins: 16, max_leaf_size, ..BvhOptions::default() });
+            let bvh = builder.build(&mut objects());
+            assert_eq!(builder.stats.objects, spheres.len());
+            assert!(builder.stats.leaves <= spheres.len() && builder.stats.nodes < spheres.len());
+            if max_leaf_size == 1 {
+                assert_eq!(builder.stats.leaves, spheres.len());
+            }
+
+            for _ in 0..200 {
+                let r = ray(vec3(random_in_range(-12., 12.), random_in_range(0., 5.), random_in_range(-12., 12.)), random_unit_vector(), 0.);
+                assert_eq!(bvh.hit(r, 0.001, Float::INFINITY), spheres.hit(r, 0.001, Float::INFINITY));
+                assert_eq!(bvh.occluded(r, 0.001, 2.), spheres.occluded(r, 0.001, 2.));
+            }
+            costs.push(builder.stats.cost);
+        }
+    }
+    assert!(costs[2] < costs[0] && costs[3] < costs[1]);
+
+    // identical objects can't be separated, but still have to respect the leaf size
+    let mut builder = BvhBuilder::new(BvhOptions { split: BvhSplit::Sah, bins: 8, max_leaf_size: 2, ..BvhOptions::default() });
+    let mut same: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = (0..5).map(|_| Box::new(sphere((0., 0., 0.), 1., NO_MATERIAL)) as Box<dyn AxisAlignedBound + Send + Sync>).collect();
+    let bvh = builder.build(&mut same);
+    assert!(bvh.hit(ray!((0, 0, -5) -> (0, 0, 1)), 0., Float::INFINITY).is_some());
+    assert_eq!(builder.stats.leaves, 3);
+}
@@ -0,0 +1,205 @@
+use std::mem::take;
+
+use crate::{
+    config::Float, hit::{Bound, Hit, HitRecord, aabb::AABB, bvh::{AxisAlignedBound, BvhBuilder, BvhOptions, BvhSplit, MAX_SAH_DEPTH, enclosing_bound}}, ray::Ray
+};
+
+// Linearized version of Bvh: nodes live in one Vec in depth first order, so the left child of a node is the node
+// right after it and only the right child needs an index. Leaves refer to a range of the objects Vec, which is
+// sorted so every leaf's objects are contiguous. Traversal visits the child on the near side of the split axis
+// first, so the far child can often be skipped once something closer has been hit.
+pub struct FlatBvh {
+    pub nodes: Vec<FlatBvhNode>,
+    pub objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlatBvhNode {
+    pub aabb: AABB,
+    // first object for leaves, right child for inner nodes
+    pub offset: u32,
+    // zero for inner nodes
+    pub count: u16,
+    pub axis: u8,
+}
+
+// Traversal pushes at most one node per level. Past MAX_SAH_DEPTH the builder only does median splits, which halve
+// the objects every level, and offsets are u32, so that's at most another 32 levels
+const STACK_SIZE: usize = MAX_SAH_DEPTH + u32::BITS as usize;
+
+impl FlatBvh {
+    // same split as Bvh::from_slice
+    pub fn from_slice(objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> Self {
+        BvhBuilder::new(BvhOptions { split: BvhSplit::Median, max_leaf_size: 1, ..BvhOptions::default() }).build_flat(objects)
+    }
+
+    // builds the nodes for objects, which start at index first in the final objects Vec
+    pub fn build_nodes(&mut self, builder: &mut BvhBuilder, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], first: usize, depth: usize) {
+        builder.stats.max_depth = builder.stats.max_depth.max(depth);
+        assert!(depth <= STACK_SIZE, "FlatBvh is deeper than its traversal stack");
+        let aabb = enclosing_bound(objects);
+
+        // leaves count their objects in a u16
+        match builder.split_at_most(objects, depth, u16::MAX as usize) {
+            Some((mid, axis)) => {
+                builder.stats.nodes += 1;
+                let index = self.nodes.len();
+                self.nodes.push(FlatBvhNode { aabb, offset: 0, count: 0, axis: axis as u8 });
+
+                let (left, right) = objects.split_at_mut(mid);
+                self.build_nodes(builder, left, first, depth + 1);
+                self.nodes[index].offset = self.nodes.len() as u32;
+                self.build_nodes(builder, right, first + mid, depth + 1);
+            },
+            None => {
+                builder.stats.leaves += 1;
+                self.nodes.push(FlatBvhNode { aabb, offset: first as u32, count: objects.len() as u16, axis: 0 });
+            },
+        }
+    }
+}
+
+impl BvhBuilder {
+    pub fn build_flat(&mut self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> FlatBvh {
+        assert!(!objects.is_empty(), "Cannot create BVH from an empty slice");
+        let start = std::time::Instant::now();
+
+        let mut bvh = FlatBvh { nodes: Vec::new(), objects: Vec::new() };
+        bvh.build_nodes(self, objects, 0, 1);
+        bvh.objects = objects.iter_mut().map(take).collect();
+
+        self.stats.objects += objects.len();
+        self.stats.cost += bvh.cost();
+        self.stats.build_time += start.elapsed();
+        bvh
+    }
+}
+
+impl FlatBvh {
+    // same cost model as BvhBuilder, but computed from the finished nodes
+    fn cost(&self) -> Float {
+        let root_area = self.nodes[0].aabb.surface_area();
+        self.nodes.iter().map(|n| n.aabb.surface_area() / root_area * if n.count == 0 { 1. } else { n.count as Float }).sum()
+    }
+
+    // visits the leaves whose box the ray passes through within t_min and the current value of t_max, in near to
+    // far order, stopping as soon as visit returns true. visit can shrink t_max to skip boxes behind a hit.
+    #[inline(always)]
+    fn traverse(&self, r: Ray, t_min: Float, t_max: &mut Float, mut visit: impl FnMut(&[Box<dyn AxisAlignedBound + Send + Sync>], &mut Float) -> bool) {
+        let dir_is_neg = [r.inv_direction.x < 0., r.inv_direction.y < 0., r.inv_direction.z < 0.];
+        let mut stack = [0u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+            if node.aabb.intersects(r, t_min, *t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    if visit(&self.objects[first..first + node.count as usize], t_max) {
+                        return;
+                    }
+                } else if dir_is_neg[node.axis as usize] {
+                    stack[stack_len] = current as u32 + 1;
+                    stack_len += 1;
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack[stack_len] = node.offset;
+                    stack_len += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+
+            if stack_len == 0 {
+                return;
+            }
+            stack_len -= 1;
+            current = stack[stack_len] as usize;
+        }
+    }
+}
+
+impl Hit for FlatBvh {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut res = None;
+        let mut t_max = t_max;
+        self.traverse(r, t_min, &mut t_max, |objects, t_max| {
+            for o in objects {
+                if let Some(hit) = o.hit(r, t_min, *t_max) {
+                    *t_max = hit.t;
+                    res = Some(hit);
+                }
+            }
+            false
+        });
+        res
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        let mut occluded = false;
+        let mut t_max = t_max;
+        self.traverse(r, t_min, &mut t_max, |objects, t_max| {
+            occluded = objects.iter().any(|o| o.occluded(r, t_min, *t_max));
+            occluded
+        });
+        occluded
+    }
+}
+
+impl Bound for FlatBvh {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        self.nodes[0].aabb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Float, hit::{Hit, bvh::{AxisAlignedBound, Bvh, BvhBuilder, BvhOptions, BvhSplit, MAX_SAH_DEPTH}, flat_bvh::FlatBvh, sphere::{Sphere, sphere}, triangle::TriangleMesh},
+        material::NO_MATERIAL, random::random_in_range, ray::ray, vec3::{random_unit_vector, vec3}
+    };
+
+    fn objects(spheres: &[Sphere]) -> Vec<Box<dyn AxisAlignedBound + Send + Sync>> {
+        let mesh = TriangleMesh::new(vec![vec3!(-3, -3, 0), vec3!(3, -3, 0), vec3!(0, 3, 0), vec3!(0, 0, 4)], vec![[0, 1, 2], [0, 1, 3], [1, 2, 3]], NO_MATERIAL);
+        spheres.iter().map(|&s| Box::new(s) as Box<dyn AxisAlignedBound + Send + Sync>).chain(mesh.into_objects()).collect()
+    }
+
+    #[test]
+    fn test_flat_bvh() {
+        let spheres: Vec<_> = std::iter::once(sphere((0., -1000., 0.), 1000., NO_MATERIAL))
+            .chain((0..100).map(|i| sphere((random_in_range(-8., 8.), random_in_range(0., 4.), random_in_range(-8., 8.)), 0.05 * (i % 7) as Float + 0.1, NO_MATERIAL)))
+            .collect();
+
+        let bvh = Bvh::from_slice(&mut objects(&spheres));
+        let flat = FlatBvh::from_slice(&mut objects(&spheres));
+        let mut builder = BvhBuilder::new(BvhOptions { split: BvhSplit::Sah, bins: 8, max_leaf_size: 4, ..BvhOptions::default() });
+        let flat_sah = builder.build_flat(&mut objects(&spheres));
+        assert_eq!(builder.stats.objects, spheres.len() + 3);
+        assert!(builder.stats.leaves < builder.stats.objects);
+
+        for _ in 0..500 {
+            let r = ray(vec3(random_in_range(-10., 10.), random_in_range(0., 6.), random_in_range(-10., 10.)), random_unit_vector(), 0.);
+            let expected = bvh.hit(r, 0.001, Float::INFINITY);
+            assert_eq!(flat.hit(r, 0.001, Float::INFINITY), expected);
+            assert_eq!(flat_sah.hit(r, 0.001, Float::INFINITY).map(|h| h.t), expected.as_ref().map(|h| h.t));
+            assert_eq!(flat.occluded(r, 0.001, 3.), bvh.occluded(r, 0.001, 3.));
+            assert_eq!(flat_sah.occluded(r, 0.001, 3.), bvh.occluded(r, 0.001, 3.));
+        }
+    }
+
+    #[test]
+    fn test_flat_bvh_depth() {
+        // spacing the spheres out exponentially makes SAH peel off one sphere per level, deep enough to reach the
+        // median split fallback
+        let spheres: Vec<_> = (0..60).map(|i| sphere(((32. as Float).powi(i), 0., 0.), 0.1, NO_MATERIAL)).collect();
+        let mut builder = BvhBuilder::new(BvhOptions { split: BvhSplit::Sah, max_leaf_size: 1, ..BvhOptions::default() });
+        let flat = builder.build_flat(&mut objects(&spheres));
+        assert!(builder.stats.max_depth > MAX_SAH_DEPTH);
+
+        let r = ray!((32, 0, -5) -> (0, 0, 1));
+        assert_eq!(flat.hit(r, 0.001, Float::INFINITY), Bvh::from_slice(&mut objects(&spheres)).hit(r, 0.001, Float::INFINITY));
+    }
+}
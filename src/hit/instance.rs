@@ -1,9 +1,20 @@
-use crate::{config::Float, hit::{bvh::AxisAlignedBound, aabb::AABB, Bound, Hit, HitRecord}, ray::Ray, vec3::Vec3};
+use std::sync::Arc;
+
+use crate::{
+    animation::{Interpolate, Keyframes, TransformKey}, config::{Float, PI}, hit::{Bound, Hit, HitRecord, aabb::AABB, bvh::{AxisAlignedBound, BvhBuilder}},
+    material::MaterialHandle, ray::Ray, transform::Transform, vec3::{Vec3, vec3}
+};
 
 #[inline(always)]
 fn translate_hit(r: Ray, offset: Vec3, object: &dyn Hit, t_min: Float, t_max: Float) -> Option<HitRecord> {
     let moved_ray = Ray { origin: r.origin - offset, direction: r.direction, inv_direction: r.inv_direction, time: r.time };
-    object.hit(moved_ray, t_min, t_max).and_then(|mut hit| { hit.pos += offset; Some(hit)})
+    object.hit(moved_ray, t_min, t_max).map(|mut hit| { hit.pos += offset; hit })
+}
+
+#[inline(always)]
+fn translate_occluded(r: Ray, offset: Vec3, object: &dyn Hit, t_min: Float, t_max: Float) -> bool {
+    let moved_ray = Ray { origin: r.origin - offset, direction: r.direction, inv_direction: r.inv_direction, time: r.time };
+    object.occluded(moved_ray, t_min, t_max)
 }
 
 #[inline(always)]
@@ -16,6 +27,15 @@ fn translate_bound(offset: Vec3, object: &dyn Bound<HitType=AABB>) -> AABB {
     }
 }
 
+#[inline(always)]
+fn transform_hit(r: Ray, transform: &Transform, object: &dyn Hit, t_min: Float, t_max: Float) -> Option<HitRecord> {
+    object.hit(transform.inverse_ray(r), t_min, t_max).map(|mut hit| {
+        hit.pos = transform.point(hit.pos);
+        hit.normal = transform.normal(hit.normal);
+        hit
+    })
+}
+
 pub struct Translate {
     pub offset: Vec3,
     pub object: Box<dyn AxisAlignedBound + Send + Sync>,
@@ -25,6 +45,10 @@ impl Hit for Translate {
     fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         translate_hit(r, self.offset, self.object.as_ref(), t_min, t_max)
     }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        translate_occluded(r, self.offset, self.object.as_ref(), t_min, t_max)
+    }
 }
 
 impl Bound for Translate {
@@ -41,10 +65,19 @@ pub struct Animate {
     pub object: Box<dyn AxisAlignedBound + Send + Sync>,
 }
 
+impl Animate {
+    fn offset(&self, time: Float) -> Vec3 {
+        self.offset_start + (self.interpolation)(time) * (self.offset_end - self.offset_start)
+    }
+}
+
 impl Hit for Animate {
     fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
-        let offset = self.offset_start + (self.interpolation)(r.time) * (self.offset_end - self.offset_start);
-        translate_hit(r, offset, self.object.as_ref(), t_min, t_max)
+        translate_hit(r, self.offset(r.time), self.object.as_ref(), t_min, t_max)
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        translate_occluded(r, self.offset(r.time), self.object.as_ref(), t_min, t_max)
     }
 }
 
@@ -57,31 +90,170 @@ fn bound(&self) -> AABB {
     }
 }
 
+// Rotates, scales and otherwise transforms an object given in its own space
+pub struct Transformed {
+    pub transform: Transform,
+    pub object: Box<dyn AxisAlignedBound + Send + Sync>,
+}
+
+impl Hit for Transformed {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        transform_hit(r, &self.transform, self.object.as_ref(), t_min, t_max)
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.object.occluded(self.transform.inverse_ray(r), t_min, t_max)
+    }
+}
+
+impl Bound for Transformed {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        self.transform.bound(self.object.bound())
+    }
+}
+
+// Object moving through keyframed transforms, evaluated at the time of every ray. The bound covers the whole motion,
+// so the BVH sees where the object could be at any time during the shutter.
+pub struct Keyframed {
+    pub keys: Keyframes<TransformKey>,
+    pub object: Box<dyn AxisAlignedBound + Send + Sync>,
+    pub aabb: AABB,
+}
+
+impl Keyframed {
+    pub fn new(keys: Keyframes<TransformKey>, object: Box<dyn AxisAlignedBound + Send + Sync>) -> Self {
+        for axis in 0..3 {
+            let first = keys.keys()[0].1.scale[axis];
+            assert!(keys.keys().iter().all(|(_, k)| k.scale[axis] * first > 0.), "Keyframed scale factors must not be zero or change sign");
+        }
+        let aabb = motion_bound(&keys, object.bound());
+        Keyframed { keys, object, aabb }
+    }
+}
+
+// Rotation sweeps the corners of the object's box along arcs, so the transformed boxes at the keys aren't enough.
+// Every segment between keys is sampled at least every 1/16th of a half turn, and the boxes are padded by how far
+// the motion can stray from the straight line between samples. For p(t) = R(t) S(t) x + T(t) that's at most
+// max|p''| / 8, with |p''| <= w^2 |S x| + 2 w |S' x| for angular velocity w, and T linear.
+fn motion_bound(keys: &Keyframes<TransformKey>, aabb: AABB) -> AABB {
+    let keys = keys.keys();
+    let corners = aabb.corners();
+    let scaled = |scale: Vec3, c: Vec3| vec3(scale.x * c.x, scale.y * c.y, scale.z * c.z).length();
+
+    let mut bound = keys[0].1.transform().bound(aabb);
+    for pair in keys.windows(2) {
+        let ((_, a), (_, b)) = (pair[0], pair[1]);
+        let angle = a.rotation.angle_to(b.rotation);
+        let steps = (angle / (PI / 16.)).ceil().max(1.) as usize;
+
+        let (w, ds) = (angle / steps as Float, (b.scale - a.scale) / steps as Float);
+        let radius = corners.iter().map(|&c| scaled(a.scale, c).max(scaled(b.scale, c))).fold(0., Float::max);
+        let stretch = corners.iter().map(|&c| scaled(ds, c)).fold(0., Float::max);
+        let pad = (w * w * radius + 2. * w * stretch) / 8.;
+
+        // the box around two consecutive padded samples covers the motion in between
+        for i in 0..=steps {
+            let sample = TransformKey::interpolate(a, b, i as Float / steps as Float).transform().bound(aabb);
+            let padded = AABB::new((sample.x.min - pad, sample.x.max + pad).into(), (sample.y.min - pad, sample.y.max + pad).into(), (sample.z.min - pad, sample.z.max + pad).into());
+            bound = AABB::enclosing(bound, padded);
+        }
+    }
+    bound
+}
+
+impl Hit for Keyframed {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        transform_hit(r, &self.keys.at(r.time).transform(), self.object.as_ref(), t_min, t_max)
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.object.occluded(self.keys.at(r.time).transform().inverse_ray(r), t_min, t_max)
+    }
+}
+
+impl Bound for Keyframed {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        self.aabb
+    }
+}
+
+// Bottom level of a two level acceleration structure: objects with their own BVH, built once and shared by any
+// number of Instances. The top level is just a BVH over the instances, built like any other.
+#[derive(Clone)]
+pub struct Blas {
+    pub objects: Arc<dyn AxisAlignedBound + Send + Sync>,
+}
+
+impl Blas {
+    pub fn new(builder: &mut BvhBuilder, objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>) -> Self {
+        Blas { objects: Arc::from(builder.build_layout(objects)) }
+    }
+}
+
+// A placement of a Blas, optionally with all its objects' materials replaced
+pub struct Instance {
+    pub blas: Blas,
+    pub transform: Transform,
+    pub material: Option<MaterialHandle>,
+}
+
+impl Hit for Instance {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        transform_hit(r, &self.transform, self.blas.objects.as_ref(), t_min, t_max).map(|mut hit| {
+            if let Some(material) = self.material { (hit.material, hit.vertex_color) = (material, None); }
+            hit
+        })
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.blas.objects.occluded(self.transform.inverse_ray(r), t_min, t_max)
+    }
+}
+
+impl Bound for Instance {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        self.transform.bound(self.blas.objects.bound())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{config::Float, hit::{instance::{Animate, Translate}, sphere::sphere, Hit}, material::simple::lambertian, ray::ray};
+    use std::sync::Arc;
+
+    use approx::assert_abs_diff_eq;
+
+    use crate::{
+        animation::{Keyframes, TransformKey}, config::Float, hit::{Bound, Hit, bvh::{AxisAlignedBound, BvhBuilder}, instance::{Animate, Blas, Instance, Keyframed, Transformed, Translate}, sphere::sphere},
+        material::{MaterialRepository, NO_MATERIAL, simple::{lambertian, metal}}, ray::ray, transform::{Quaternion, Transform}, vec3::vec3
+    };
 
     #[test]
     fn test_translate() {
-        let s = sphere((0., 0., -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
+        let s = sphere((0., 0., -1.), 0.5, NO_MATERIAL);
         let t = Translate { offset: vec3!(0., 1., 0.), object: Box::new(s) };
-        let s2 = sphere((0., 1., -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
+        let s2 = sphere((0., 1., -1.), 0.5, NO_MATERIAL);
 
         let r = ray!((0, 1, 0) -> (0, 0, -1));
         assert_eq!(t.hit(r, 0.001, Float::INFINITY), s2.hit(r, 0.001, Float::INFINITY));
+        assert!(t.occluded(r, 0.001, Float::INFINITY));
+        assert!(!t.occluded(r, 0.001, 0.25));
+        assert!(!t.occluded(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY));
     }
 
     #[test]
     fn test_animate() {
-        let s = sphere((0., 0., -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
+        let s = sphere((0., 0., -1.), 0.5, NO_MATERIAL);
         let a = Animate {
             offset_start: vec3!(0., 0., 0.),
             offset_end: vec3!(0., 1., 0.),
             interpolation: Box::new(|t| t),
             object: Box::new(s)
         };
-        let s2 = sphere((0., 0.5, -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
-        let s3 = sphere((0., 1., -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
+        let s2 = sphere((0., 0.5, -1.), 0.5, NO_MATERIAL);
+        let s3 = sphere((0., 1., -1.), 0.5, NO_MATERIAL);
 
         let r = ray!((0, 0, 0) -> (0, 0, -1));
         let r2 = ray(vec3!(0., 0.5, 0.), vec3!(0., 0., -1.), 0.5);
@@ -90,5 +262,78 @@ fn test_animate() {
         assert_eq!(a.hit(r, 0.001, Float::INFINITY), s.hit(r, 0.001, Float::INFINITY));
         assert_eq!(a.hit(r2, 0.001, Float::INFINITY), s2.hit(r2, 0.001, Float::INFINITY));
         assert_eq!(a.hit(r3, 0.001, Float::INFINITY), s3.hit(r3, 0.001, Float::INFINITY));
+        assert!(a.occluded(r2, 0.001, Float::INFINITY));
+        assert!(!a.occluded(ray(vec3!(0., 0., 0.), vec3!(0., 0., -1.), 1.0), 0.001, Float::INFINITY));
+    }
+
+    #[test]
+    fn test_instance() {
+        let mut materials = MaterialRepository::new();
+        let (red, shiny) = (materials.add(lambertian((0.7, 0.3, 0.3))), materials.add(metal((0.8, 0.6, 0.2), 0.)));
+        let spheres = [sphere((0., 0., -1.), 0.5, red), sphere((2., 0., -1.), 0.5, red)];
+        let blas = Blas::new(&mut BvhBuilder::default(), spheres.iter().map(|&s| Box::new(s) as Box<dyn AxisAlignedBound + Send + Sync>).collect());
+
+        let plain = Instance { blas: blas.clone(), transform: Transform::translate(vec3!(0, 1, 0)), material: None };
+        let gold = Instance { blas: blas.clone(), transform: Transform::translate(vec3!(0, -1, 0)), material: Some(shiny) };
+        // both instances share the objects
+        assert_eq!(Arc::strong_count(&blas.objects), 3);
+
+        let r = ray!((2, 1, 0) -> (0, 0, -1));
+        assert_eq!(plain.hit(r, 0.001, Float::INFINITY), sphere((2., 1., -1.), 0.5, red).hit(r, 0.001, Float::INFINITY));
+        assert!(gold.hit(r, 0.001, Float::INFINITY).is_none());
+        assert!(plain.occluded(r, 0.001, Float::INFINITY));
+
+        let hit = gold.hit(ray!((0, -1, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        assert_eq!(hit.material, shiny);
+        assert_eq!(hit.pos, vec3!(0, -1, -0.5));
+        assert_eq!(gold.bound().y, (-1.5, -0.5).into());
+    }
+
+    #[test]
+    fn test_transformed() {
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
+        // an ellipsoid twice as wide as it is high, standing on its side at x = 5
+        let t = Transformed { transform: Transform::translate(vec3!(5, 0, 0)) * Transform::rotate(vec3!(0, 0, 1), 90.) * Transform::scale(vec3!(2, 1, 1)).unwrap(), object: Box::new(s) };
+
+        let hit = t.hit(ray!((5, -5, 0) -> (0, 1, 0)), 0.001, Float::INFINITY).unwrap();
+        assert_abs_diff_eq!(hit.t, 3., epsilon = 1e-12);
+        assert_abs_diff_eq!(hit.normal.y, -1., epsilon = 1e-12);
+        assert!(t.hit(ray!((6.5, -5, 0) -> (0, 1, 0)), 0.001, Float::INFINITY).is_none());
+
+        // off axis the normal is the ellipsoid's, not the sphere's
+        let hit = t.hit(ray!((5.5, 0, 5) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        let expected = vec3(hit.pos.x - 5., hit.pos.y / 4., hit.pos.z).normalize();
+        assert_abs_diff_eq!((hit.normal - expected).length(), 0., epsilon = 1e-12);
+        assert!(t.occluded(ray!((5.5, 0, 5) -> (0, 0, -1)), 0.001, Float::INFINITY));
+
+        let bound = t.bound();
+        assert_abs_diff_eq!(bound.x.min, 4., epsilon = 1e-12);
+        assert_abs_diff_eq!(bound.y.max, 2., epsilon = 1e-12);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_keyframed() {
+        // a wheel spinning a full turn around z while it moves right and grows
+        let wheel = sphere((2., 0., 0.), 0.5, NO_MATERIAL);
+        let key = |turns: Float, x: Float, size: Float| TransformKey {
+            translation: vec3(x, 0., 0.), rotation: Quaternion::from_axis_angle(vec3!(0, 0, 1), 360. * turns), scale: vec3(size, size, size)
+        };
+        let keys = Keyframes::new(vec![(0., key(0., 0., 1.)), (0.5, key(0.5, 1., 1.5)), (1., key(1., 4., 1.))]);
+        let k = Keyframed::new(keys.clone(), Box::new(wheel));
+
+        for i in 0..=100 {
+            let time = i as Float / 100.;
+            let b = keys.at(time).transform().bound(wheel.bound());
+            let bound = k.bound();
+            assert!(bound.x.min <= b.x.min && b.x.max <= bound.x.max && bound.y.min <= b.y.min && b.y.max <= bound.y.max, "{time}");
+        }
+
+        // halfway through, the sphere is on the other side of the center at 1.5 times the size
+        let r = ray(vec3!(-2, 0, 5), vec3!(0, 0, -1), 0.5);
+        let hit = k.hit(r, 0.001, Float::INFINITY).unwrap();
+        assert_abs_diff_eq!(hit.t, 5. - 0.75, epsilon = 1e-9);
+        assert_abs_diff_eq!(hit.normal.z, 1., epsilon = 1e-9);
+        assert!(k.hit(ray(vec3!(-2, 0, 5), vec3!(0, 0, -1), 0.), 0.001, Float::INFINITY).is_none());
+        assert!(k.occluded(r, 0.001, Float::INFINITY));
+    }
+}
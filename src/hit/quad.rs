@@ -1,25 +1,45 @@
 use approx::assert_ulps_eq;
 
-use crate::{config::Float, hit::{Bound, Hit, HitRecord, aabb::AABB}, material::simple::Material, ray::Ray, texture::UV, vec3::{Point, Vec3, cross, dot}};
+use crate::{config::Float, hit::{Bound, Hit, HitRecord, aabb::AABB}, material::{MaterialHandle, NO_MATERIAL}, ray::Ray, texture::UV, vec3::{Point, Vec3, cross, dot}};
 
 #[derive(Clone, Copy, Default)]
 pub struct Quad {
     pub origin: Point,
     pub u: Vec3,
     pub v: Vec3,
-    pub material: Material,
+    pub material: MaterialHandle,
 }
 
-pub fn quad(origin: (Float, Float, Float), u: (Float, Float, Float), v: (Float, Float, Float), material: Material) -> Quad {
+pub fn quad(origin: (Float, Float, Float), u: (Float, Float, Float), v: (Float, Float, Float), material: MaterialHandle) -> Quad {
     Quad { origin: origin.into(), u: u.into(), v: v.into(), material }
 }
 
+// the six sides of the box spanned by two opposite corners
+pub fn quad_box(a: (Float, Float, Float), b: (Float, Float, Float), material: MaterialHandle) -> Vec<Quad> {
+    let min = (a.0.min(b.0), a.1.min(b.1), a.2.min(b.2));
+    let max = (a.0.max(b.0), a.1.max(b.1), a.2.max(b.2));
+    let dx = (max.0 - min.0, 0., 0.);
+    let dy = (0., max.1 - min.1, 0.);
+    let dz = (0., 0., max.2 - min.2);
+
+    vec![
+        quad((min.0, min.1, max.2), dx, dy, material),
+        quad((max.0, min.1, max.2), (-dz.0, -dz.1, -dz.2), dy, material),
+        quad((max.0, min.1, min.2), (-dx.0, -dx.1, -dx.2), dy, material),
+        quad((min.0, min.1, min.2), dz, dy, material),
+        quad((min.0, max.1, max.2), dx, (-dz.0, -dz.1, -dz.2), material),
+        quad((min.0, min.1, min.2), dx, dz, material),
+    ]
+}
+
 impl Hit for Quad {
     fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
         // TODO maybe cache these?
         let n = cross(self.u, self.v);
         let normal = n.normalize();
-        let normal = if dot(normal, r.direction) < 0. { normal } else { -normal };
+        // the front face is the one u and v wind counter-clockwise on, like with triangles
+        let front_face = dot(normal, r.direction) < 0.;
+        let normal = if front_face { normal } else { -normal };
         let d = dot(normal, self.origin); // TODO is it really worth using this as an intermediate?
 
         let denom = dot(normal, r.direction);
@@ -38,7 +58,7 @@ fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
             return None;
         }
 
-        Some(HitRecord { t, material: self.material, normal, pos, uv })
+        Some(HitRecord { t, material: self.material, normal, front_face, pos, uv: uv.into(), vertex_color: None })
     }
 }
 
@@ -60,7 +80,7 @@ fn bound(&self) -> AABB {
 
 #[test]
 fn test_quad_hit() {
-    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), Material::None);
+    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), NO_MATERIAL);
     let ray = ray!((0.5, 0.5, -1.) -> (0., 0., 1.));
     let hit = q.hit(ray, 0., Float::INFINITY);
     assert!(hit.is_some());
@@ -68,10 +88,11 @@ fn test_quad_hit() {
     assert_ulps_eq!(hit_record.t, 1.);
     assert_eq!(hit_record.pos, (0.5, 0.5, 0.).into());
     assert_eq!(hit_record.normal, (0., 0., -1.).into());
-    assert_ulps_eq!(hit_record.uv.0, 0.5);
-    assert_ulps_eq!(hit_record.uv.1, 0.5);
+    assert!(!hit_record.front_face);
+    assert_ulps_eq!(hit_record.uv.coords().0, 0.5);
+    assert_ulps_eq!(hit_record.uv.coords().1, 0.5);
 
-    let q = quad((-1., 0., 0.), (0., 2., 0.), (2.0, 0., -2.0), Material::None);
+    let q = quad((-1., 0., 0.), (0., 2., 0.), (2.0, 0., -2.0), NO_MATERIAL);
     let ray = ray!((0, 1, 0) -> (0, 0, -1));
     let hit = q.hit(ray, 0., Float::INFINITY);
     assert!(hit.is_some());
@@ -79,8 +100,8 @@ fn test_quad_hit() {
     assert_ulps_eq!(hit_record.t, 1.);
     assert_eq!(hit_record.pos, (0., 1., -1.).into());
     assert_eq!(hit_record.normal, Vec3::from((1., 0., 1.)).normalize());
-    assert_ulps_eq!(hit_record.uv.0, 0.5);
-    assert_ulps_eq!(hit_record.uv.1, 0.5);
+    assert_ulps_eq!(hit_record.uv.coords().0, 0.5);
+    assert_ulps_eq!(hit_record.uv.coords().1, 0.5);
 
     // test ray through plane but not through quad
     let ray = ray!((0.5, 0.5, 1.) -> (0., 1., -1.));
@@ -99,8 +120,29 @@ fn test_quad_hit() {
     assert!(q.hit(ray, 0., Float::INFINITY).is_none());
 }
 
+#[test]
+fn test_quad_box() {
+    let sides = quad_box((1., 1., 1.), (-1., 0., -2.), NO_MATERIAL);
+    assert_eq!(sides.len(), 6);
+
+    let bound = sides.iter().fold(sides[0].bound(), |aabb, q| AABB::enclosing(aabb, q.bound()));
+    approx::assert_abs_diff_eq!(bound.x.min, -1., epsilon = AABB::MIN_LENGTH);
+    approx::assert_abs_diff_eq!(bound.x.max, 1., epsilon = AABB::MIN_LENGTH);
+    approx::assert_abs_diff_eq!(bound.y.min, 0., epsilon = AABB::MIN_LENGTH);
+    approx::assert_abs_diff_eq!(bound.y.max, 1., epsilon = AABB::MIN_LENGTH);
+    approx::assert_abs_diff_eq!(bound.z.min, -2., epsilon = AABB::MIN_LENGTH);
+    approx::assert_abs_diff_eq!(bound.z.max, 1., epsilon = AABB::MIN_LENGTH);
+
+    // every axis aligned ray through the center leaves through two sides
+    for dir in [(1., 0., 0.), (0., 1., 0.), (0., 0., 1.), (-1., 0., 0.), (0., -1., 0.), (0., 0., -1.)] {
+        let r = crate::ray::ray((0., 0.5, -0.5).into(), dir.into(), 0.);
+        assert_eq!(sides.iter().filter(|q| q.hit(r, 0., Float::INFINITY).is_some()).count(), 1);
+        assert_eq!(sides.iter().filter(|q| q.hit(r, Float::NEG_INFINITY, Float::INFINITY).is_some()).count(), 2);
+    }
+}
+
 fn test_quad_uv() {
-    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), Material::None);
+    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), NO_MATERIAL);
     let uv = q.uv((0.5, 0.5, 0.).into());
     assert_ulps_eq!(uv.0, 0.5);
     assert_ulps_eq!(uv.1, 0.5);
@@ -109,14 +151,14 @@ fn test_quad_uv() {
     assert_ulps_eq!(uv.0, 1.);
     assert_ulps_eq!(uv.1, 1.);
 
-    let q = quad((0., 0., 0.), (0., 2., 0.), (-1., 0., 0.), Material::None);
+    let q = quad((0., 0., 0.), (0., 2., 0.), (-1., 0., 0.), NO_MATERIAL);
     let uv = q.uv((-1.7, -1., 0.).into());
     assert_ulps_eq!(uv.0, -0.5);
     assert_ulps_eq!(uv.1, 1.7);
 }
 
 fn test_quad_bound() {
-    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), Material::None);
+    let q = quad((0., 0., 0.), (1., 0., 0.), (0., 1., 0.), NO_MATERIAL);
     let bound = q.bound();
     assert_ulps_eq!(bound.x.min, 0.);
     assert_ulps_eq!(bound.x.max, 1.);
@@ -125,7 +167,7 @@ fn test_quad_bound() {
     assert_ulps_eq!(bound.z.min, -AABB::MIN_LENGTH / 2.);
     assert_ulps_eq!(bound.z.max, AABB::MIN_LENGTH / 2.);
 
-    let q = quad((-1., -1., -1.), (2., 0., 0.), (0., 2., 0.), Material::None);
+    let q = quad((-1., -1., -1.), (2., 0., 0.), (0., 2., 0.), NO_MATERIAL);
     let bound = q.bound();
     assert_ulps_eq!(bound.x.min, -1.);
     assert_ulps_eq!(bound.x.max, 1.);
@@ -134,7 +176,7 @@ fn test_quad_bound() {
     assert_ulps_eq!(bound.z.min, -1.);
     assert_ulps_eq!(bound.z.max, -1.);
 
-    let q = quad((-1., -1., 1.), (2., 0., -1.), (0., 2., -1.), Material::None);
+    let q = quad((-1., -1., 1.), (2., 0., -1.), (0., 2., -1.), NO_MATERIAL);
     let bound = q.bound();
     assert_ulps_eq!(bound.x.min, -1.);
     assert_ulps_eq!(bound.x.max, 1.);
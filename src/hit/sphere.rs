@@ -1,21 +1,21 @@
 
 use crate::{
-    config::{Float, PI}, hit::{Bound, Hit, HitRecord, aabb::AABB}, material::simple::Material, ray::Ray, texture::UV, vec3::{Point, dot}
+    config::{Float, PI}, hit::{Bound, Hit, HitRecord, Uv, aabb::AABB}, material::MaterialHandle, ray::Ray, texture::UV, vec3::{Point, Vec3, dot}
 };
 
 #[derive(Clone, Copy, Default)]
 pub struct Sphere {
     pub center: Point,
     pub radius: Float,
-    pub material: Material,
+    pub material: MaterialHandle,
 }
 
-pub fn sphere(center: (Float, Float, Float), radius: Float, material: Material) -> Sphere {
+pub fn sphere(center: (Float, Float, Float), radius: Float, material: MaterialHandle) -> Sphere {
     Sphere { center: center.into(), radius, material }
 }
 
-impl Hit for Sphere {
-    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+impl Sphere {
+    fn root(&self, r: Ray, t_min: Float, t_max: Float) -> Option<Float> {
         let oc = r.origin - self.center;
         let a = r.direction.length_squared();
         let half_b = dot(oc, r.direction);
@@ -32,24 +32,35 @@ fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
                 return None;
             }
         }
+        Some(root)
+    }
+}
 
+impl Hit for Sphere {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let root = self.root(r, t_min, t_max)?;
         let pos = r.at(root);
-        let normal = (pos - self.center) / self.radius;
-        let uv = match self.material {
-            Material::LambertianTexture { texture: _ } => self.uv(pos),
-            _ => (0.0, 0.0),
-        };
+        let outward = (pos - self.center) / self.radius;
+        let front_face = dot(outward, r.direction) < 0.;
+        let normal = if front_face { outward } else { -outward };
+        Some(HitRecord { t: root, material: self.material, normal, front_face, pos, uv: Uv::Sphere(outward), vertex_color: None })
+    }
 
-        Some(HitRecord { t: root, material: self.material, normal, pos, uv })
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.root(r, t_min, t_max).is_some()
     }
 }
 
+// texture coordinates of a point on the unit sphere
+pub fn sphere_uv(p: Vec3) -> (Float, Float) {
+    let phi = p.z.atan2(p.x);
+    let theta = p.y.asin();
+    (1. - (phi + PI) / (2. * PI), (theta + PI / 2.) / PI)
+}
+
 impl UV for Sphere {
     fn uv(&self, pos: Point) -> (Float, Float) {
-        let pos = (pos - self.center) / self.radius;
-        let phi = pos.z.atan2(pos.x);
-        let theta = pos.y.asin();
-        (1. - (phi + PI) / (2. * PI), (theta + PI / 2.) / PI)
+        sphere_uv((pos - self.center) / self.radius)
     }
 }
 
@@ -80,14 +91,14 @@ fn bound(&self) -> AABB {
 
 #[cfg(test)]
 mod tests {
-    use crate::{color::color_rgb, config::Color, texture::load_image_linear};
+    use crate::{color::color_rgb, config::Color, hit::instance::Transformed, material::NO_MATERIAL, texture::load_image_linear, transform::Transform};
 
     use super::*;
     use approx::assert_ulps_eq;
 
     #[test]
     fn test_hit_sphere() {
-        let s = sphere((0., 0., 0.), 1., Material::None);
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
 
         assert!(s.hit(ray!((-10, 0, 0) -> (1., 0., 0.)), 0., Float::INFINITY).is_some());
 
@@ -97,12 +108,17 @@ fn test_hit_sphere() {
 
         assert!(s.hit(ray!((-2, 0, 0) -> (1, 0, 0)), 0., Float::MAX).unwrap().normal == vec3!(-1, 0, 0));
         assert!(s.hit(ray!((2, 0, 0) -> (-1, 0, 0)), 0., Float::MAX).unwrap().normal == vec3!(1, 0, 0));
-        assert!(s.hit(ray!((0, 0, 0) -> (1, 0, 0)), 0., Float::MAX).unwrap().normal == vec3!(1, 0, 0));
+        assert!(s.hit(ray!((2, 0, 0) -> (-1, 0, 0)), 0., Float::MAX).unwrap().front_face);
+
+        // from the inside the normal still faces the ray
+        let hit = s.hit(ray!((0, 0, 0) -> (1, 0, 0)), 0., Float::MAX).unwrap();
+        assert!(hit.normal == vec3!(-1, 0, 0) && !hit.front_face);
+        assert_eq!(hit.outward_normal(), vec3!(1, 0, 0));
     }
 
     #[test]
     fn test_uv_sphere() {
-        let s = sphere((0., 0., 0.), 1., Material::None);
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
 
         let pos = vec3!(1., 0., 0.);
         let (u, v) = s.uv(pos);
@@ -133,6 +149,15 @@ fn test_uv_sphere() {
         let (u, v) = s.uv(pos);
         assert_ulps_eq!(u, 0.75);
         assert_ulps_eq!(v, 0.5);
+
+        // hits work out the same coordinates when asked, from the sphere's own space even if it is transformed
+        let s = sphere((1., 2., 3.), 2., NO_MATERIAL);
+        let hit = s.hit(ray!((1, 2, 10) -> (0, 0, -1)), 0., Float::INFINITY).unwrap();
+        assert_eq!(hit.uv.coords(), s.uv(hit.pos));
+        let rotated = Transformed { transform: Transform::rotate(vec3!(0, 1, 0), 90.), object: Box::new(s) };
+        let hit = rotated.hit(ray!((10, 2, -1) -> (-1, 0, 0)), 0., Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.uv.coords().0, 0.25);
+        assert_ulps_eq!(hit.uv.coords().1, 0.5);
     }
 
     fn pixel_to_color(pixel: image::Rgb<u8>) -> Color {
@@ -144,7 +169,7 @@ fn test_textured_sphere() {
         let mut repo = crate::texture::TextureRepository::new();
         let idx = repo.load_texture("res/earthmap.jpg");
         let img = load_image_linear("res/earthmap.jpg");
-        let s = sphere((0., 0., 0.), 1., crate::material::simple::lambertian_texture(idx));
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
 
         let pos = vec3!(-1., 0., 0.);
         let color = repo.texture_value(idx, s.uv(pos), pos);
@@ -155,7 +180,7 @@ fn test_textured_sphere() {
 
     #[test]
     fn test_bound_sphere() {
-        let s = sphere((0., 0., 0.), 1., Material::None);
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
         let aabb = s.bound();
 
         assert_ulps_eq!(aabb.x.min, -1.);
@@ -168,8 +193,8 @@ fn test_bound_sphere() {
 
     #[test]
     fn test_hit_vec() {
-        let s1 = sphere((1., 0., 0.), 1., Material::None);
-        let s2 = sphere((-1., 0., 0.), 1., Material::None);
+        let s1 = sphere((1., 0., 0.), 1., NO_MATERIAL);
+        let s2 = sphere((-1., 0., 0.), 1., NO_MATERIAL);
         let v = vec![s1, s2];
 
         assert_eq!(v.hit(ray!((3, 0, 0) -> (-1, 0, 0)), 0., Float::MAX).unwrap().t, 1.);
@@ -178,8 +203,8 @@ fn test_hit_vec() {
 
     #[test]
     fn test_bound_vec() {
-        let s1 = sphere((1., 0., 0.), 1., Material::None);
-        let s2 = sphere((-1., 0., 0.), 1., Material::None);
+        let s1 = sphere((1., 0., 0.), 1., NO_MATERIAL);
+        let s2 = sphere((-1., 0., 0.), 1., NO_MATERIAL);
 
         let v = vec![s1, s2];
         let aabb = v.bound();
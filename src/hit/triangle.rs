@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use crate::{config::{Color, Float}, hit::{Bound, Hit, HitRecord, aabb::AABB, bvh::AxisAlignedBound}, material::MaterialHandle, ray::Ray, vec3::{Point, Vec3, cross, dot, vec3}};
+
+// Vertex data is shared between all triangles of a mesh, each Triangle only knows its mesh and its index.
+// Normals face the incoming ray. front_face follows the winding order (counter-clockwise is the front face) or the
+// vertex normals if present, so just like with Sphere, a closed mesh can tell its inside from its outside.
+// Vertex colors, if present, replace the color of a Lambertian or the base color of a Principled material.
+pub struct TriangleMesh {
+    pub positions: Vec<Point>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<(Float, Float)>>,
+    pub colors: Option<Vec<Color>>,
+    pub indices: Vec<[usize; 3]>,
+    pub material: MaterialHandle,
+}
+
+impl TriangleMesh {
+    pub fn new(positions: Vec<Point>, indices: Vec<[usize; 3]>, material: MaterialHandle) -> Self {
+        TriangleMesh { positions, normals: None, uvs: None, colors: None, indices, material }
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn triangles(self) -> Vec<Triangle> {
+        let mesh = Arc::new(self);
+        (0..mesh.len()).map(|index| Triangle { mesh: mesh.clone(), index }).collect()
+    }
+
+    // convenience for feeding a mesh straight into Bvh::from_slice
+    pub fn into_objects(self) -> Vec<Box<dyn AxisAlignedBound + Send + Sync>> {
+        self.triangles().into_iter().map(|t| Box::new(t) as Box<dyn AxisAlignedBound + Send + Sync>).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub mesh: Arc<TriangleMesh>,
+    pub index: usize,
+}
+
+impl Triangle {
+    fn vertices(&self) -> (Point, Point, Point) {
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+        (self.mesh.positions[i0], self.mesh.positions[i1], self.mesh.positions[i2])
+    }
+
+    // Möller-Trumbore, returns t and the barycentric coordinates of the second and third vertex
+    fn intersect(&self, r: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
+        let (p0, p1, p2) = self.vertices();
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+
+        let p = cross(r.direction, e2);
+        // det scales with the square of the triangle's size, so only an exactly parallel ray is rejected here.
+        // Nearly parallel ones fail the barycentric tests below
+        let det = dot(e1, p);
+        if det == 0. {
+            return None;
+        }
+        let inv_det = 1. / det;
+
+        let s = r.origin - p0;
+        let b1 = dot(s, p) * inv_det;
+        if !(0. ..=1.).contains(&b1) {
+            return None;
+        }
+
+        let q = cross(s, e1);
+        let b2 = dot(r.direction, q) * inv_det;
+        if b2 < 0. || b1 + b2 > 1. {
+            return None;
+        }
+
+        let t = dot(e2, q) * inv_det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+        Some((t, b1, b2))
+    }
+}
+
+impl Hit for Triangle {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let (t, b1, b2) = self.intersect(r, t_min, t_max)?;
+        let b0 = 1. - b1 - b2;
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+
+        let outward = match &self.mesh.normals {
+            Some(n) => (b0 * n[i0] + b1 * n[i1] + b2 * n[i2]).normalize(),
+            None => {
+                let (p0, p1, p2) = self.vertices();
+                cross(p1 - p0, p2 - p0).normalize()
+            },
+        };
+
+        let uv = match &self.mesh.uvs {
+            Some(uv) => (
+                b0 * uv[i0].0 + b1 * uv[i1].0 + b2 * uv[i2].0,
+                b0 * uv[i0].1 + b1 * uv[i1].1 + b2 * uv[i2].1,
+            ),
+            None => (b1, b2),
+        };
+
+        let front_face = dot(outward, r.direction) < 0.;
+        let normal = if front_face { outward } else { -outward };
+
+        let vertex_color = self.mesh.colors.as_ref().map(|c| b0 * c[i0] + b1 * c[i1] + b2 * c[i2]);
+        Some(HitRecord { t, material: self.mesh.material, normal, front_face, pos: r.at(t), uv: uv.into(), vertex_color })
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(r, t_min, t_max).is_some()
+    }
+}
+
+impl Bound for Triangle {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        let (p0, p1, p2) = self.vertices();
+        let min = vec3(p0.x.min(p1.x).min(p2.x), p0.y.min(p1.y).min(p2.y), p0.z.min(p1.z).min(p2.z));
+        let max = vec3(p0.x.max(p1.x).max(p2.x), p0.y.max(p1.y).max(p2.y), p0.z.max(p1.z).max(p2.z));
+        AABB::from_points(min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_ulps_eq;
+
+    use crate::{color::color_rgb, config::Float, hit::{Bound, Hit, bvh::Bvh, sphere::sphere, triangle::TriangleMesh}, material::{NO_MATERIAL, Scatter, simple::{lambertian, metal}}, random::random_floats, scene::Scene};
+
+    fn quad_mesh() -> TriangleMesh {
+        TriangleMesh::new(
+            vec![vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(1, 1, 0), vec3!(0, 1, 0)],
+            vec![[0, 1, 2], [0, 2, 3]],
+            NO_MATERIAL
+        )
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let tris = quad_mesh().triangles();
+
+        let r = ray!((0.75, 0.25, 1) -> (0, 0, -1));
+        let hit = tris[0].hit(r, 0., Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 1.);
+        assert_eq!(hit.pos, vec3!(0.75, 0.25, 0));
+        assert_eq!(hit.normal, vec3!(0, 0, 1));
+        assert!(hit.front_face);
+        assert!(tris[1].hit(r, 0., Float::INFINITY).is_none());
+
+        // back face hits turn the normal towards the ray, the winding order decides front_face
+        let r = ray!((0.25, 0.75, -1) -> (0, 0, 1));
+        assert!(tris[0].hit(r, 0., Float::INFINITY).is_none());
+        let hit = tris[1].hit(r, 0., Float::INFINITY).unwrap();
+        assert_eq!(hit.normal, vec3!(0, 0, -1));
+        assert!(!hit.front_face);
+        assert_eq!(hit.outward_normal(), vec3!(0, 0, 1));
+
+        // outside, parallel and out of range
+        assert!(tris[0].hit(ray!((2, 0.5, 1) -> (0, 0, -1)), 0., Float::INFINITY).is_none());
+        assert!(tris[0].hit(ray!((0.75, 0.25, 0) -> (1, 0, 0)), 0., Float::INFINITY).is_none());
+        assert!(tris[0].hit(ray!((0.75, 0.25, 1) -> (0, 0, -1)), 0., 0.5).is_none());
+
+        // tiny triangles still get hit
+        let mesh = TriangleMesh::new(vec![vec3!(0, 0, 0), vec3!(1e-9, 0, 0), vec3!(0, 1e-9, 0)], vec![[0, 1, 2]], NO_MATERIAL);
+        let hit = mesh.triangles()[0].hit(ray!((2.5e-10, 2.5e-10, 1) -> (0, 0, -1)), 0., Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 1.);
+    }
+
+    #[test]
+    fn test_triangle_shading() {
+        let mut mesh = quad_mesh();
+        mesh.normals = Some(vec![vec3!(-1, 0, 1).normalize(), vec3!(1, 0, 1).normalize(), vec3!(1, 0, 1).normalize(), vec3!(-1, 0, 1).normalize()]);
+        mesh.uvs = Some(vec![(0., 0.), (1., 0.), (1., 1.), (0., 1.)]);
+        let tris = mesh.triangles();
+
+        let hit = tris[0].hit(ray!((0.5, 0.25, 1) -> (0, 0, -1)), 0., Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.normal.x, 0.);
+        assert_ulps_eq!(hit.normal.z, 1.);
+        assert_ulps_eq!(hit.uv.coords().0, 0.5);
+        assert_ulps_eq!(hit.uv.coords().1, 0.25);
+
+        let hit = tris[1].hit(ray!((0.25, 0.75, 1) -> (0, 0, -1)), 0., Float::INFINITY).unwrap();
+        assert!(hit.normal.x < 0.);
+        assert_ulps_eq!(hit.normal.length(), 1.);
+        assert_ulps_eq!(hit.uv.coords().0, 0.25);
+        assert_ulps_eq!(hit.uv.coords().1, 0.75);
+    }
+
+    #[test]
+    fn test_triangle_vertex_colors() {
+        let colors = vec![color_rgb(1., 0., 0.), color_rgb(0., 1., 0.), color_rgb(0., 1., 0.), color_rgb(1., 0., 0.)];
+
+        let mut scene = Scene::default();
+        let matte = scene.material_repository.add(lambertian((0.5, 0.5, 0.5)));
+        let tris = TriangleMesh { colors: Some(colors), material: matte, ..quad_mesh() }.triangles();
+        let r = ray!((0.5, 0.25, 1) -> (0, 0, -1));
+        let hit = tris[0].hit(r, 0., Float::INFINITY).unwrap();
+        assert_eq!(hit.vertex_color, Some(color_rgb(0.5, 0.5, 0.)));
+        let sample = scene.material_repository.material(matte).sample(&scene, &hit, -r.direction, random_floats()).unwrap();
+        assert_eq!(sample.weight, color_rgb(0.5, 0.5, 0.));
+
+        // only lambertian materials pick up vertex colors
+        let sample = metal((0.5, 0.5, 0.5), 0.).sample(&scene, &hit, -r.direction, random_floats()).unwrap();
+        assert_ulps_eq!(sample.weight, color_rgb(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn test_triangle_bound() {
+        let mesh = TriangleMesh::new(vec![vec3!(0, 0, 0), vec3!(2, -1, 0), vec3!(1, 3, 0)], vec![[0, 1, 2]], NO_MATERIAL);
+        let aabb = mesh.triangles()[0].bound();
+        assert_ulps_eq!(aabb.x.min, 0.);
+        assert_ulps_eq!(aabb.x.max, 2.);
+        assert_ulps_eq!(aabb.y.min, -1.);
+        assert_ulps_eq!(aabb.y.max, 3.);
+        assert!(aabb.z.length() > 0.);
+    }
+
+    #[test]
+    fn test_triangle_bvh() {
+        let mut objects = quad_mesh().into_objects();
+        objects.push(Box::new(sphere((0.5, 0.5, -2.), 0.5, NO_MATERIAL)));
+        let bvh = Bvh::from_slice(&mut objects);
+
+        assert_ulps_eq!(bvh.hit(ray!((0.5, 0.5, 1) -> (0, 0, -1)), 0., Float::INFINITY).unwrap().t, 1.);
+        assert_ulps_eq!(bvh.hit(ray!((0.5, 0.5, -4) -> (0, 0, 1)), 0., Float::INFINITY).unwrap().t, 1.5);
+        assert!(bvh.hit(ray!((3, 0.5, 1) -> (0, 0, -1)), 0., Float::INFINITY).is_none());
+    }
+}
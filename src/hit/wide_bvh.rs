@@ -0,0 +1,265 @@
+use std::{mem::{MaybeUninit, take}, ops::Range};
+
+use crate::{
+    config::Float, hit::{Bound, Hit, HitRecord, aabb::{AABB, WideAABB}, bvh::{AxisAlignedBound, BvhBuilder, MAX_SAH_DEPTH, enclosing_bound}}, ray::Ray
+};
+
+// BVH with up to N children per node, collapsed from the binary tree BvhBuilder would build: a node takes the
+// children of its largest children until it has N of them. All child boxes of a node are tested at once with
+// WideAABB. Like FlatBvh, the nodes live in one Vec and leaves refer to a range of the sorted objects.
+pub struct WideBvh<const N: usize> {
+    pub nodes: Vec<WideBvhNode<N>>,
+    pub objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>>,
+}
+
+pub type Bvh4 = WideBvh<4>;
+pub type Bvh8 = WideBvh<8>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WideBvhNode<const N: usize> {
+    pub bounds: WideAABB<N>,
+    // node index for inner children, first object for leaves
+    pub children: [u32; N],
+    // zero for inner children and unused lanes
+    pub counts: [u16; N],
+}
+
+// A child of a node under construction, along with how the binary builder splits it, None for leaves
+#[derive(Clone)]
+struct Child {
+    range: Range<usize>,
+    aabb: AABB,
+    depth: usize,
+    split: Option<usize>,
+}
+
+// The binary tree these are collapsed from is at most as deep as FlatBvh's (see there), and every node on the way
+// down leaves at most N - 1 siblings on the stack
+const MAX_CHILDREN: usize = 8;
+const MAX_DEPTH: usize = MAX_SAH_DEPTH + u32::BITS as usize;
+const STACK_SIZE: usize = MAX_DEPTH * MAX_CHILDREN;
+
+impl<const N: usize> WideBvh<N> {
+    fn child(builder: &mut BvhBuilder, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], range: Range<usize>, depth: usize) -> Child {
+        builder.stats.max_depth = builder.stats.max_depth.max(depth);
+        assert!(depth <= MAX_DEPTH, "WideBvh is deeper than its traversal stack");
+        let aabb = enclosing_bound(&objects[range.clone()]);
+        // leaves count their objects in a u16
+        let split = builder.split_at_most(&mut objects[range.clone()], depth, u16::MAX as usize).map(|(mid, _)| range.start + mid);
+        Child { range, aabb, depth, split }
+    }
+
+    // adds the node for child and everything below it, returns its index
+    fn build_node(&mut self, builder: &mut BvhBuilder, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>], child: Child) -> u32 {
+        builder.stats.nodes += 1;
+        let index = self.nodes.len();
+        self.nodes.push(WideBvhNode { bounds: WideAABB::default(), children: [0; N], counts: [0; N] });
+
+        // keep replacing the largest child that isn't a leaf by its own children
+        let mut children = vec![child];
+        while children.len() < N {
+            let Some((i, mid)) = children.iter().enumerate()
+                .filter_map(|(i, c)| c.split.map(|mid| (i, mid, c.aabb.surface_area())))
+                .max_by(|a, b| a.2.total_cmp(&b.2))
+                .map(|(i, mid, _)| (i, mid))
+            else { break };
+
+            let Child { range, depth, .. } = children[i].clone();
+            children[i] = Self::child(builder, objects, range.start..mid, depth + 1);
+            children.push(Self::child(builder, objects, mid..range.end, depth + 1));
+        }
+
+        for (lane, child) in children.into_iter().enumerate() {
+            self.nodes[index].bounds.set(lane, child.aabb);
+            if child.split.is_some() {
+                self.nodes[index].children[lane] = self.build_node(builder, objects, child);
+            } else {
+                builder.stats.leaves += 1;
+                self.nodes[index].children[lane] = child.range.start as u32;
+                self.nodes[index].counts[lane] = child.range.len() as u16;
+            }
+        }
+        index as u32
+    }
+
+    // same cost model as BvhBuilder, with all child boxes of a node counting as a single test
+    fn cost(&self) -> Float {
+        let root_area = self.bound().surface_area();
+        self.nodes.iter().map(|n| {
+            let area = |lane| n.bounds.get(lane).surface_area();
+            let node_area = (0..N).filter(|&lane| n.bounds.min[0][lane] < Float::INFINITY)
+                .fold(None, |aabb: Option<AABB>, lane| Some(aabb.map_or(n.bounds.get(lane), |aabb| AABB::enclosing(aabb, n.bounds.get(lane)))))
+                .map_or(0., |aabb| aabb.surface_area());
+            node_area + (0..N).filter(|&lane| n.counts[lane] > 0).map(|lane| area(lane) * n.counts[lane] as Float).sum::<Float>()
+        }).sum::<Float>() / root_area
+    }
+
+    // visits the leaves whose box the ray passes through within t_min and the current value of t_max, nearest box
+    // first, stopping as soon as visit returns true. visit can shrink t_max to skip boxes behind a hit.
+    #[inline(always)]
+    fn traverse(&self, r: Ray, t_min: Float, t_max: &mut Float, mut visit: impl FnMut(&[Box<dyn AxisAlignedBound + Send + Sync>], &mut Float) -> bool) {
+        // entry distance, child and count of the boxes still to visit. The stack is large enough that zeroing it
+        // costs more than the traversal itself, and everything below stack_len has been written.
+        let mut stack = [const { MaybeUninit::<(Float, u32, u16)>::uninit() }; STACK_SIZE];
+        stack[0].write((t_min, 0, 0));
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (entry, child, count) = unsafe { stack[stack_len].assume_init() };
+            // the same test the box got when it was pushed would fail now
+            if entry >= *t_max {
+                continue;
+            }
+
+            if count > 0 {
+                let first = child as usize;
+                if visit(&self.objects[first..first + count as usize], t_max) {
+                    return;
+                }
+                continue;
+            }
+
+            let node = &self.nodes[child as usize];
+            let entries = node.bounds.entry_distances(r, t_min, *t_max);
+            // push the far boxes first, so the nearest gets popped next
+            let start = stack_len;
+            for (lane, &entry) in entries.iter().enumerate() {
+                if entry < Float::INFINITY {
+                    let mut i = stack_len;
+                    while i > start && unsafe { stack[i - 1].assume_init().0 } < entry {
+                        stack[i] = stack[i - 1];
+                        i -= 1;
+                    }
+                    stack[i].write((entry, node.children[lane], node.counts[lane]));
+                    stack_len += 1;
+                }
+            }
+        }
+    }
+}
+
+impl BvhBuilder {
+    pub fn build_wide<const N: usize>(&mut self, objects: &mut [Box<dyn AxisAlignedBound + Send + Sync>]) -> WideBvh<N> {
+        assert!(!objects.is_empty(), "Cannot create BVH from an empty slice");
+        assert!((2..=MAX_CHILDREN).contains(&N), "BVH nodes need between 2 and {MAX_CHILDREN} children");
+        let start = std::time::Instant::now();
+
+        let mut bvh = WideBvh { nodes: Vec::new(), objects: Vec::new() };
+        let root = WideBvh::<N>::child(self, objects, 0..objects.len(), 1);
+        bvh.build_node(self, objects, root);
+        bvh.objects = objects.iter_mut().map(take).collect();
+
+        self.stats.objects += objects.len();
+        self.stats.cost += bvh.cost();
+        self.stats.build_time += start.elapsed();
+        bvh
+    }
+}
+
+impl<const N: usize> Hit for WideBvh<N> {
+    fn hit(&self, r: Ray, t_min: Float, t_max: Float) -> Option<HitRecord> {
+        let mut res = None;
+        let mut t_max = t_max;
+        self.traverse(r, t_min, &mut t_max, |objects, t_max| {
+            for o in objects {
+                if let Some(hit) = o.hit(r, t_min, *t_max) {
+                    *t_max = hit.t;
+                    res = Some(hit);
+                }
+            }
+            false
+        });
+        res
+    }
+
+    fn occluded(&self, r: Ray, t_min: Float, t_max: Float) -> bool {
+        let mut occluded = false;
+        let mut t_max = t_max;
+        self.traverse(r, t_min, &mut t_max, |objects, t_max| {
+            occluded = objects.iter().any(|o| o.occluded(r, t_min, *t_max));
+            occluded
+        });
+        occluded
+    }
+}
+
+impl<const N: usize> Bound for WideBvh<N> {
+    type HitType = AABB;
+    fn bound(&self) -> AABB {
+        let bounds = &self.nodes[0].bounds;
+        (1..N).filter(|&lane| bounds.min[0][lane] < Float::INFINITY).fold(bounds.get(0), |aabb, lane| AABB::enclosing(aabb, bounds.get(lane)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Float, hit::{Bound, Hit, aabb::{AABB, WideAABB}, bvh::{AxisAlignedBound, Bvh, BvhBuilder, BvhOptions, BvhSplit, MAX_SAH_DEPTH}, sphere::{Sphere, sphere}, triangle::TriangleMesh},
+        material::NO_MATERIAL, random::random_in_range, ray::ray, vec3::{random_unit_vector, vec3}
+    };
+
+    fn objects(spheres: &[Sphere]) -> Vec<Box<dyn AxisAlignedBound + Send + Sync>> {
+        let mesh = TriangleMesh::new(vec![vec3!(-3, -3, 0), vec3!(3, -3, 0), vec3!(0, 3, 0), vec3!(0, 0, 4)], vec![[0, 1, 2], [0, 1, 3], [1, 2, 3]], NO_MATERIAL);
+        spheres.iter().map(|&s| Box::new(s) as Box<dyn AxisAlignedBound + Send + Sync>).chain(mesh.into_objects()).collect()
+    }
+
+    #[test]
+    fn test_wide_aabb() {
+        let boxes = [AABB::from_points(vec3!(0, 0, 0), vec3!(1, 1, 1)), AABB::from_points(vec3!(0, 0, 2), vec3!(1, 1, 3)), AABB::from_points(vec3!(2, 0, 0), vec3!(3, 1, 1))];
+        let mut wide = WideAABB::<4>::default();
+        for (lane, &aabb) in boxes.iter().enumerate() {
+            wide.set(lane, aabb);
+            assert_eq!(wide.get(lane), aabb);
+        }
+
+        // including rays along the box faces, where the slab test divides zero by zero
+        for r in [ray!((0.5, 0.5, -1) -> (0, 0, 1)), ray!((0, 0.5, -1) -> (0, 0, 1)), ray!((2.5, 0.5, 0.5) -> (-1, 0, 0)), ray!((0, 0, 0) -> (1, 1, 1))] {
+            let entries = wide.entry_distances(r, 0.001, Float::INFINITY);
+            for (lane, aabb) in boxes.iter().enumerate() {
+                assert_eq!(entries[lane] < Float::INFINITY, aabb.intersects(r, 0.001, Float::INFINITY));
+            }
+            assert_eq!(entries[3], Float::INFINITY);
+        }
+        assert_eq!(wide.entry_distances(ray!((0.5, 0.5, -1) -> (0, 0, 1)), 0.001, Float::INFINITY)[..2], [1., 3.]);
+    }
+
+    #[test]
+    fn test_wide_bvh() {
+        let spheres: Vec<_> = std::iter::once(sphere((0., -1000., 0.), 1000., NO_MATERIAL))
+            .chain((0..200).map(|i| sphere((random_in_range(-8., 8.), random_in_range(0., 4.), random_in_range(-8., 8.)), 0.05 * (i % 7) as Float + 0.1, NO_MATERIAL)))
+            .collect();
+
+        for options in [BvhOptions { split: BvhSplit::Median, ..BvhOptions::default() }, BvhOptions { max_leaf_size: 4, ..BvhOptions::default() }] {
+            let bvh = BvhBuilder::new(options).build(&mut objects(&spheres));
+            let mut builder = BvhBuilder::new(options);
+            let bvh4 = builder.build_wide::<4>(&mut objects(&spheres));
+            assert_eq!(builder.stats.objects, spheres.len() + 3);
+            assert!(builder.stats.nodes < builder.stats.leaves);
+            let bvh8 = BvhBuilder::new(options).build_wide::<8>(&mut objects(&spheres));
+            assert!(bvh8.nodes.len() < bvh4.nodes.len());
+            assert_eq!(bvh4.bound(), bvh.bound());
+
+            for _ in 0..500 {
+                let r = ray(vec3(random_in_range(-10., 10.), random_in_range(0., 6.), random_in_range(-10., 10.)), random_unit_vector(), 0.);
+                let expected = bvh.hit(r, 0.001, Float::INFINITY);
+                assert_eq!(bvh4.hit(r, 0.001, Float::INFINITY), expected);
+                assert_eq!(bvh8.hit(r, 0.001, Float::INFINITY), expected);
+                assert_eq!(bvh4.occluded(r, 0.001, 3.), bvh.occluded(r, 0.001, 3.));
+                assert_eq!(bvh8.occluded(r, 0.001, 3.), bvh.occluded(r, 0.001, 3.));
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_bvh_depth() {
+        // same exponentially spaced spheres as test_flat_bvh_depth
+        let spheres: Vec<_> = (0..60).map(|i| sphere(((32. as Float).powi(i), 0., 0.), 0.1, NO_MATERIAL)).collect();
+        let mut builder = BvhBuilder::new(BvhOptions { split: BvhSplit::Sah, max_leaf_size: 1, ..BvhOptions::default() });
+        let bvh4 = builder.build_wide::<4>(&mut objects(&spheres));
+        assert!(builder.stats.max_depth > MAX_SAH_DEPTH);
+
+        let r = ray!((32, 0, -5) -> (0, 0, 1));
+        assert_eq!(bvh4.hit(r, 0.001, Float::INFINITY), Bvh::from_slice(&mut objects(&spheres)).hit(r, 0.001, Float::INFINITY));
+    }
+}
@@ -0,0 +1,37 @@
+use std::{error::Error, fmt::{Display, Formatter}, path::{Path, PathBuf}, str::FromStr};
+
+pub mod lens;
+pub mod obj;
+pub mod ply;
+pub mod scene;
+
+// Errors carry the file and (1-based) line they were found on, so a broken asset can be fixed without
+// having to guess where the importer gave up.
+#[derive(Debug)]
+pub struct ImportError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ImportError {
+    pub fn new(path: &Path, line: Option<usize>, message: impl Into<String>) -> Self {
+        ImportError { path: path.to_path_buf(), line, message: message.into() }
+    }
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl Error for ImportError {}
+
+fn parse_value<T: FromStr>(token: Option<&str>, what: &str, path: &Path, line: usize) -> Result<T, ImportError> {
+    let token = token.ok_or_else(|| ImportError::new(path, Some(line), format!("missing {what}")))?;
+    token.parse().map_err(|_| ImportError::new(path, Some(line), format!("invalid {what} '{token}'")))
+}
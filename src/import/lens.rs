@@ -0,0 +1,78 @@
+use std::{fs::read_to_string, path::Path};
+
+use crate::{camera::realistic::LensElement, import::{ImportError, parse_value}};
+
+// Lens prescriptions in pbrt's tabular format: one surface per line from the front of the lens to the film, with
+// the radius of curvature, thickness, index of refraction and aperture diameter in millimeters. The aperture
+// stop has a radius of 0, '#' starts a comment.
+pub fn load_lens(path: impl AsRef<Path>) -> Result<Vec<LensElement>, ImportError> {
+    let path = path.as_ref();
+    let source = read_to_string(path).map_err(|e| ImportError::new(path, None, format!("cannot read file: {e}")))?;
+    parse_lens(&source, path)
+}
+
+fn parse_lens(source: &str, path: &Path) -> Result<Vec<LensElement>, ImportError> {
+    let mut elements = Vec::new();
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_nr = line_idx + 1;
+        let mut tokens = line.split('#').next().unwrap_or("").split_whitespace().peekable();
+        if tokens.peek().is_none() {
+            continue;
+        }
+
+        let element = LensElement {
+            radius: parse_value(tokens.next(), "radius", path, line_nr)?,
+            thickness: parse_value(tokens.next(), "thickness", path, line_nr)?,
+            ior: parse_value(tokens.next(), "index of refraction", path, line_nr)?,
+            aperture: parse_value(tokens.next(), "aperture", path, line_nr)?,
+        };
+        if let Some(token) = tokens.next() {
+            return Err(ImportError::new(path, Some(line_nr), format!("unexpected '{token}' after the aperture")));
+        }
+        if element.thickness < 0. || element.ior < 0. || element.aperture <= 0. {
+            return Err(ImportError::new(path, Some(line_nr), "expected a positive aperture and no negative thickness or index of refraction"));
+        }
+        elements.push(element);
+    }
+
+    if elements.is_empty() {
+        return Err(ImportError::new(path, None, "lens has no elements"));
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::camera::realistic::LensElement;
+    use super::{load_lens, parse_lens};
+
+    #[test]
+    fn test_lens() {
+        let source = "
+            # a single biconvex lens behind the stop
+            0 2 0 10    # stop
+            20 4 1.5 12
+            -40 0 1 12
+        ";
+        let elements = parse_lens(source, Path::new("test.dat")).unwrap();
+        assert_eq!(elements, vec![
+            LensElement { radius: 0., thickness: 2., ior: 0., aperture: 10. },
+            LensElement { radius: 20., thickness: 4., ior: 1.5, aperture: 12. },
+            LensElement { radius: -40., thickness: 0., ior: 1., aperture: 12. },
+        ]);
+
+        assert_eq!(load_lens("res/lenses/dgauss50.dat").unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_lens_errors() {
+        let error = |source: &str| parse_lens(source, Path::new("test.dat")).unwrap_err().to_string();
+        assert_eq!(error("# nothing here\n"), "test.dat: lens has no elements");
+        assert_eq!(error("1 2 3 4\n1 2 3\n"), "test.dat:2: missing aperture");
+        assert_eq!(error("1 2 x 4\n"), "test.dat:1: invalid index of refraction 'x'");
+        assert_eq!(error("1 2 3 4 5\n"), "test.dat:1: unexpected '5' after the aperture");
+        assert_eq!(error("1 2 3 0\n"), "test.dat:1: expected a positive aperture and no negative thickness or index of refraction");
+    }
+}
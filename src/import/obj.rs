@@ -0,0 +1,427 @@
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+use crate::{
+    color::color_rgb, config::Float, hit::triangle::TriangleMesh, import::{ImportError, parse_value}, material::{MaterialHandle, MaterialRepository, principled::{Parameter, Principled}}, texture::{TextureHandle, TextureRepository}, vec3::{Point, Vec3, vec3}
+};
+
+// Wavefront OBJ importer. Every group (g/o) and every material switch (usemtl) starts a new TriangleMesh,
+// polygons are fan-triangulated. Statements we don't render (smoothing groups, lines, free-form geometry, ...)
+// are skipped, anything we do use but can't parse is reported with its file and line.
+// The materials of the MTL files get registered in material_repository.
+pub fn load_obj(path: impl AsRef<Path>, textures: &mut TextureRepository, material_repository: &mut MaterialRepository) -> Result<Vec<TriangleMesh>, ImportError> {
+    let path = path.as_ref();
+    let source = read_to_string(path).map_err(|e| ImportError::new(path, None, format!("cannot read file: {e}")))?;
+    parse_obj(&source, path, textures, material_repository)
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Point>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    indices: Vec<[usize; 3]>,
+    vertex_indices: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    missing_normals: bool,
+    missing_uvs: bool,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, key: (usize, Option<usize>, Option<usize>), positions: &[Point], uvs: &[(Float, Float)], normals: &[Vec3]) -> usize {
+        if let Some(&index) = self.vertex_indices.get(&key) {
+            return index;
+        }
+
+        let (p, uv, n) = key;
+        let index = self.positions.len();
+        self.positions.push(positions[p]);
+        self.uvs.push(uv.map_or((0., 0.), |i| uvs[i]));
+        self.normals.push(n.map_or(Vec3::default(), |i| normals[i]));
+        self.missing_uvs |= uv.is_none();
+        self.missing_normals |= n.is_none();
+        self.vertex_indices.insert(key, index);
+        index
+    }
+
+    fn build(self, material: MaterialHandle) -> TriangleMesh {
+        let mut mesh = TriangleMesh::new(self.positions, self.indices, material);
+        if !self.missing_normals { mesh.normals = Some(self.normals); }
+        if !self.missing_uvs { mesh.uvs = Some(self.uvs); }
+        mesh
+    }
+}
+
+struct ObjParser<'a> {
+    path: &'a Path,
+    positions: Vec<Point>,
+    uvs: Vec<(Float, Float)>,
+    normals: Vec<Vec3>,
+    materials: HashMap<String, MaterialHandle>,
+    material: MaterialHandle,
+    builder: MeshBuilder,
+    meshes: Vec<TriangleMesh>,
+}
+
+impl ObjParser<'_> {
+    fn finish_mesh(&mut self) {
+        let builder = std::mem::take(&mut self.builder);
+        if !builder.indices.is_empty() {
+            self.meshes.push(builder.build(self.material));
+        }
+    }
+
+    fn resolve_index(&self, token: &str, count: usize, what: &str, line: usize) -> Result<usize, ImportError> {
+        let i: isize = parse_value(Some(token), &format!("{what} index"), self.path, line)?;
+        let resolved = if i > 0 { i - 1 } else { count as isize + i };
+        if i == 0 || resolved < 0 || resolved >= count as isize {
+            return Err(ImportError::new(self.path, Some(line), format!("{what} index {i} out of range ({count} defined)")));
+        }
+        Ok(resolved as usize)
+    }
+
+    fn face_vertex(&mut self, token: &str, line: usize) -> Result<usize, ImportError> {
+        let mut parts = token.split('/');
+        let p = self.resolve_index(parts.next().unwrap_or(""), self.positions.len(), "vertex", line)?;
+        let uv = match parts.next() {
+            Some(t) if !t.is_empty() => Some(self.resolve_index(t, self.uvs.len(), "texture coordinate", line)?),
+            _ => None,
+        };
+        let n = match parts.next() {
+            Some(t) if !t.is_empty() => Some(self.resolve_index(t, self.normals.len(), "normal", line)?),
+            _ => None,
+        };
+        if parts.next().is_some() {
+            return Err(ImportError::new(self.path, Some(line), format!("invalid face vertex '{token}'")));
+        }
+        Ok(self.builder.vertex((p, uv, n), &self.positions, &self.uvs, &self.normals))
+    }
+}
+
+fn parse_vec3<'a>(tokens: &mut impl Iterator<Item = &'a str>, what: &str, path: &Path, line: usize) -> Result<Vec3, ImportError> {
+    Ok(vec3(
+        parse_value(tokens.next(), &format!("{what} x"), path, line)?,
+        parse_value(tokens.next(), &format!("{what} y"), path, line)?,
+        parse_value(tokens.next(), &format!("{what} z"), path, line)?,
+    ))
+}
+
+fn parse_obj(source: &str, path: &Path, textures: &mut TextureRepository, material_repository: &mut MaterialRepository) -> Result<Vec<TriangleMesh>, ImportError> {
+    let mut parser = ObjParser {
+        path,
+        positions: Vec::new(),
+        uvs: Vec::new(),
+        normals: Vec::new(),
+        materials: HashMap::new(),
+        material: material_repository.add(Principled::default()),
+        builder: MeshBuilder::default(),
+        meshes: Vec::new(),
+    };
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_nr = line_idx + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => parser.positions.push(parse_vec3(&mut tokens, "vertex", path, line_nr)?),
+            Some("vn") => parser.normals.push(parse_vec3(&mut tokens, "normal", path, line_nr)?.normalize()),
+            Some("vt") => {
+                let u = parse_value(tokens.next(), "texture coordinate u", path, line_nr)?;
+                let v = if let Some(t) = tokens.next() { parse_value(Some(t), "texture coordinate v", path, line_nr)? } else { 0. };
+                parser.uvs.push((u, v));
+            },
+            Some("f") => {
+                let vertices = tokens.map(|t| parser.face_vertex(t, line_nr)).collect::<Result<Vec<_>, _>>()?;
+                if vertices.len() < 3 {
+                    return Err(ImportError::new(path, Some(line_nr), format!("face needs at least 3 vertices, found {}", vertices.len())));
+                }
+                for i in 1..vertices.len() - 1 {
+                    parser.builder.indices.push([vertices[0], vertices[i], vertices[i + 1]]);
+                }
+            },
+            Some("g") | Some("o") => parser.finish_mesh(),
+            Some("usemtl") => {
+                let name = tokens.next().ok_or_else(|| ImportError::new(path, Some(line_nr), "missing material name"))?;
+                let material = *parser.materials.get(name)
+                    .ok_or_else(|| ImportError::new(path, Some(line_nr), format!("undefined material '{name}'")))?;
+                parser.finish_mesh();
+                parser.material = material;
+            },
+            Some("mtllib") => {
+                let file = tokens.collect::<Vec<_>>().join(" ");
+                if file.is_empty() {
+                    return Err(ImportError::new(path, Some(line_nr), "missing material library name"));
+                }
+                let library = load_mtl(path.parent().unwrap_or(Path::new("")).join(file), textures)?;
+                parser.materials.extend(library.into_iter().map(|(name, material)| (name, material_repository.add(material))));
+            },
+            _ => {},
+        }
+    }
+
+    parser.finish_mesh();
+    Ok(parser.meshes)
+}
+
+pub fn load_mtl(path: impl AsRef<Path>, textures: &mut TextureRepository) -> Result<HashMap<String, Principled>, ImportError> {
+    let path = path.as_ref();
+    let source = read_to_string(path).map_err(|e| ImportError::new(path, None, format!("cannot read material library: {e}")))?;
+    parse_mtl(&source, path, textures)
+}
+
+struct MtlMaterial {
+    kd: Vec3,
+    ks: Vec3,
+    ke: Vec3,
+    tf: Vec3,
+    ns: Float,
+    ni: Float,
+    dissolve: Float,
+    illum: usize,
+    // the PBR extension
+    pr: Option<Float>,
+    pm: Option<Float>,
+    ps: Float,
+    pc: Float,
+    map_kd: Option<TextureHandle>,
+    map_ke: Option<TextureHandle>,
+    map_pr: Option<TextureHandle>,
+    map_pm: Option<TextureHandle>,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            kd: vec3(0.8, 0.8, 0.8), ks: Vec3::default(), ke: Vec3::default(), tf: vec3(1., 1., 1.), ns: 0., ni: 1.5, dissolve: 1., illum: 2,
+            pr: None, pm: None, ps: 0., pc: 0., map_kd: None, map_ke: None, map_pr: None, map_pm: None,
+        }
+    }
+}
+
+impl MtlMaterial {
+    // MTL describes Phong-style materials unless it uses the PBR extension (Pr, Pm, ...), which maps onto Principled
+    // directly. Otherwise reflective materials (illum 3 or no diffuse part) become metals in their specular color,
+    // transparent ones glass tinted by their transmission filter, and the Blinn-Phong exponent gives the roughness.
+    fn to_material(&self) -> Principled {
+        let max = |v: Vec3| v.x.max(v.y).max(v.z);
+        let color = |v: Vec3| Parameter::Value(color_rgb(v.x, v.y, v.z));
+        let mirror = self.pm.is_none() && max(self.ks) > 0. && (self.illum == 3 || max(self.kd) == 0.);
+        let transmission = if matches!(self.illum, 4 | 6 | 7) { 1. } else { 1. - self.dissolve.clamp(0., 1.) };
+
+        let base_color = match self.map_kd {
+            Some(texture) => Parameter::Texture(texture),
+            None if mirror => color(self.ks),
+            None if transmission > 0. => color(self.tf),
+            None => color(self.kd),
+        };
+        let scalar = |texture: Option<TextureHandle>, value: Float| texture.map_or(Parameter::Value(value), Parameter::Texture);
+        // Blinn-Phong exponent to GGX alpha, which is roughness squared
+        let roughness = self.pr.unwrap_or_else(|| (2. / (self.ns + 2.)).sqrt().sqrt().min(1.));
+        // the reflectance head on that goes with the index of refraction, on Principled's specular scale
+        let specular = ((self.ni - 1.) / (self.ni + 1.)).powi(2) / 0.08;
+
+        Principled {
+            base_color,
+            metallic: scalar(self.map_pm, self.pm.unwrap_or(if mirror { 1. } else { 0. })),
+            roughness: scalar(self.map_pr, roughness),
+            specular: Parameter::Value(specular.min(1.)),
+            sheen: Parameter::Value(self.ps),
+            clearcoat: Parameter::Value(self.pc),
+            transmission: Parameter::Value(transmission),
+            ior: self.ni,
+            // meshes aren't sampled as lights, so Ke only shows up where scattered rays happen to hit it
+            emission: self.map_ke.map_or(color(self.ke), Parameter::Texture),
+            emission_intensity: 1.,
+        }
+    }
+}
+
+fn parse_mtl(source: &str, path: &Path, textures: &mut TextureRepository) -> Result<HashMap<String, Principled>, ImportError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_nr = line_idx + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            let name = tokens.next().ok_or_else(|| ImportError::new(path, Some(line_nr), "missing material name"))?;
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl.to_material());
+            }
+            current = Some((name.to_string(), MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, mtl)) = current.as_mut() else {
+            return Err(ImportError::new(path, Some(line_nr), format!("'{keyword}' before any newmtl")));
+        };
+
+        match keyword {
+            "Kd" => mtl.kd = parse_vec3(&mut tokens, "Kd", path, line_nr)?,
+            "Ks" => mtl.ks = parse_vec3(&mut tokens, "Ks", path, line_nr)?,
+            "Ke" => mtl.ke = parse_vec3(&mut tokens, "Ke", path, line_nr)?,
+            "Ns" => mtl.ns = parse_value(tokens.next(), "Ns", path, line_nr)?,
+            "Ni" => {
+                mtl.ni = parse_value(tokens.next(), "Ni", path, line_nr)?;
+                // Principled refracts by the ratio of indices, which is meaningless for anything but a positive one
+                if mtl.ni <= 0. || mtl.ni.is_nan() {
+                    return Err(ImportError::new(path, Some(line_nr), format!("expected a positive Ni, found {}", mtl.ni)));
+                }
+            },
+            "d" => mtl.dissolve = parse_value(tokens.next(), "d", path, line_nr)?,
+            "Tr" => mtl.dissolve = 1. - parse_value::<Float>(tokens.next(), "Tr", path, line_nr)?,
+            "Tf" => mtl.tf = parse_vec3(&mut tokens, "Tf", path, line_nr)?,
+            "illum" => mtl.illum = parse_value(tokens.next(), "illum", path, line_nr)?,
+            "Pr" => mtl.pr = Some(parse_value(tokens.next(), "Pr", path, line_nr)?),
+            "Pm" => mtl.pm = Some(parse_value(tokens.next(), "Pm", path, line_nr)?),
+            "Ps" => mtl.ps = parse_value(tokens.next(), "Ps", path, line_nr)?,
+            "Pc" => mtl.pc = parse_value(tokens.next(), "Pc", path, line_nr)?,
+            "map_Kd" | "map_Ke" | "map_Pr" | "map_Pm" => {
+                // options like -s or -o come before the file name, we don't support them so just take the last token
+                let file = tokens.last().ok_or_else(|| ImportError::new(path, Some(line_nr), "missing texture file"))?;
+                let texture_path = path.parent().unwrap_or(Path::new("")).join(file);
+                let texture = textures.try_load_texture(&texture_path.to_string_lossy())
+                    .map_err(|e| ImportError::new(path, Some(line_nr), format!("cannot load texture '{}': {e}", texture_path.display())))?;
+                match keyword {
+                    "map_Kd" => mtl.map_kd = Some(texture),
+                    "map_Ke" => mtl.map_ke = Some(texture),
+                    "map_Pr" => mtl.map_pr = Some(texture),
+                    _ => mtl.map_pm = Some(texture),
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if let Some((name, mtl)) = current {
+        materials.insert(name, mtl.to_material());
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use approx::assert_ulps_eq;
+
+    use crate::{color::color_rgb, config::Float, hit::Hit, material::{MaterialRepository, principled::{Parameter, Principled}}, texture::TextureRepository};
+    use super::{parse_mtl, parse_obj};
+
+    #[test]
+    fn test_obj_groups() {
+        let source = "
+            # a unit square and a triangle
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 2
+            g square
+            f 1/1/1 2/2/1 3/3/1 4/4/1
+            g triangle
+            f -4 -3 -1
+        ";
+        let mut materials = MaterialRepository::new();
+        let meshes = parse_obj(source, Path::new("test.obj"), &mut TextureRepository::new(), &mut materials).unwrap();
+        assert_eq!(meshes.len(), 2);
+
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(meshes[0].positions.len(), 4);
+        assert_eq!(meshes[0].normals.as_ref().unwrap()[0], vec3!(0, 0, 1));
+        assert_eq!(meshes[0].uvs.as_ref().unwrap()[2], (1., 1.));
+        assert_eq!(materials.get(meshes[0].material), Some(&Principled::default()));
+
+        assert_eq!(meshes[1].indices, vec![[0, 1, 2]]);
+        assert_eq!(meshes[1].positions[2], vec3!(0, 1, 0));
+        assert!(meshes[1].normals.is_none());
+        assert!(meshes[1].uvs.is_none());
+
+        let tris = meshes.into_iter().next().unwrap().triangles();
+        let hit = tris.hit(ray!((0.75, 0.75, 1) -> (0, 0, -1)), 0., Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.uv.coords().0, 0.75);
+        assert_ulps_eq!(hit.uv.coords().1, 0.75);
+    }
+
+    #[test]
+    fn test_obj_errors() {
+        let parse = |source: &str| parse_obj(source, Path::new("test.obj"), &mut TextureRepository::new(), &mut MaterialRepository::new()).map(|_| ()).unwrap_err().to_string();
+
+        assert_eq!(parse("v 0 0 0\nv 1 0 x"), "test.obj:2: invalid vertex z 'x'");
+        assert_eq!(parse("v 0 0 0\nv 1 0"), "test.obj:2: missing vertex z");
+        assert_eq!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4"), "test.obj:4: vertex index 4 out of range (3 defined)");
+        assert_eq!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 0"), "test.obj:4: vertex index 0 out of range (3 defined)");
+        assert_eq!(parse("v 0 0 0\nv 1 0 0\nf 1 2"), "test.obj:3: face needs at least 3 vertices, found 2");
+        assert_eq!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/1 3/1"), "test.obj:4: texture coordinate index 1 out of range (0 defined)");
+        assert_eq!(parse("usemtl missing"), "test.obj:1: undefined material 'missing'");
+    }
+
+    #[test]
+    fn test_mtl() {
+        let source = "
+            newmtl red
+            Kd 0.8 0.1 0.1
+            Ks 0.5 0.5 0.5
+            Ns 10
+            newmtl mirror
+            Kd 0 0 0
+            Ks 0.9 0.9 0.9
+            Ns 1000
+            newmtl glass
+            Ni 1.45
+            d 0.1
+            newmtl lamp
+            Kd 0.8 0.8 0.8
+            Ke 10 10 8
+            newmtl brushed
+            Kd 0.5 0.5 0.6
+            Pm 1
+            Pr 0.3
+            Pc 0.5
+        ";
+        let materials = parse_mtl(source, Path::new("test.mtl"), &mut TextureRepository::new()).unwrap();
+        let red = materials["red"];
+        assert_eq!((red.base_color, red.metallic, red.transmission), (Parameter::Value(color_rgb(0.8, 0.1, 0.1)), Parameter::Value(0.), Parameter::Value(0.)));
+        assert_eq!(red.roughness, Parameter::Value((2. / 12. as Float).sqrt().sqrt()));
+        assert!(matches!(red.specular, Parameter::Value(s) if (s - 0.5).abs() < 1e-9));
+
+        let mirror = materials["mirror"];
+        assert_eq!((mirror.base_color, mirror.metallic), (Parameter::Value(color_rgb(0.9, 0.9, 0.9)), Parameter::Value(1.)));
+        assert_eq!(mirror.roughness, Parameter::Value((2. / 1002. as Float).sqrt().sqrt()));
+
+        let glass = materials["glass"];
+        assert_eq!((glass.base_color, glass.ior), (Parameter::Value(color_rgb(1., 1., 1.)), 1.45));
+        assert!(matches!(glass.transmission, Parameter::Value(t) if (t - 0.9).abs() < 1e-9));
+
+        assert_eq!(materials["lamp"].emission, Parameter::Value(color_rgb(10., 10., 8.)));
+        assert_eq!(materials["lamp"].base_color, Parameter::Value(color_rgb(0.8, 0.8, 0.8)));
+
+        let brushed = materials["brushed"];
+        assert_eq!((brushed.metallic, brushed.roughness, brushed.clearcoat), (Parameter::Value(1.), Parameter::Value(0.3), Parameter::Value(0.5)));
+        assert_eq!(brushed.base_color, Parameter::Value(color_rgb(0.5, 0.5, 0.6)));
+
+        let err = parse_mtl("Kd 1 1 1", Path::new("test.mtl"), &mut TextureRepository::new()).unwrap_err();
+        assert_eq!(err.to_string(), "test.mtl:1: 'Kd' before any newmtl");
+        let err = parse_mtl("newmtl a\nmap_Kd missing.png", Path::new("test.mtl"), &mut TextureRepository::new()).unwrap_err();
+        assert!(err.to_string().starts_with("test.mtl:2: cannot load texture"));
+        let err = parse_mtl("newmtl a\nd 0.5\nNi 0", Path::new("test.mtl"), &mut TextureRepository::new()).unwrap_err();
+        assert_eq!(err.to_string(), "test.mtl:3: expected a positive Ni, found 0");
+    }
+
+    #[test]
+    fn test_mtl_texture() {
+        let mut textures = TextureRepository::new();
+        let materials = parse_mtl("newmtl earth\nmap_Kd -s 1 1 1 earthmap.jpg\nmap_Pr earthmap.jpg", Path::new("res/test.mtl"), &mut textures).unwrap();
+        let texture = textures.load_texture("res/earthmap.jpg");
+        assert_eq!((materials["earth"].base_color, materials["earth"].roughness), (Parameter::Texture(texture), Parameter::Texture(texture)));
+    }
+}
@@ -0,0 +1,425 @@
+use std::{fs::read, path::Path};
+
+use crate::{
+    color::color_rgb, config::{Color, Float}, hit::triangle::TriangleMesh, import::ImportError, material::MaterialHandle, vec3::{Point, Vec3, vec3}
+};
+
+// Stanford PLY importer for ascii and binary (little and big endian) files. Only the vertex and face elements
+// are used: positions, optional normals, texture coordinates and colors, and polygon faces which get
+// fan-triangulated. Other elements and properties are read past and ignored.
+pub fn load_ply(path: impl AsRef<Path>, material: MaterialHandle) -> Result<TriangleMesh, ImportError> {
+    let path = path.as_ref();
+    let data = read(path).map_err(|e| ImportError::new(path, None, format!("cannot read file: {e}")))?;
+    parse_ply(&data, path, material)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ScalarType { I8, U8, I16, U16, I32, U32, F32, F64 }
+
+impl ScalarType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(ScalarType::I8),
+            "uchar" | "uint8" => Some(ScalarType::U8),
+            "short" | "int16" => Some(ScalarType::I16),
+            "ushort" | "uint16" => Some(ScalarType::U16),
+            "int" | "int32" => Some(ScalarType::I32),
+            "uint" | "uint32" => Some(ScalarType::U32),
+            "float" | "float32" => Some(ScalarType::F32),
+            "double" | "float64" => Some(ScalarType::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    ty: ScalarType,
+    // count type for list properties
+    list: Option<ScalarType>,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn property(&self, name: &str) -> Option<usize> {
+        self.properties.iter().position(|p| p.name == name)
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    // byte offset and line number where the body starts
+    body_offset: usize,
+    body_line: usize,
+}
+
+fn parse_header(data: &[u8], path: &Path) -> Result<Header, ImportError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut offset = 0;
+    let mut line_nr = 0;
+
+    loop {
+        line_nr += 1;
+        let end = data[offset..].iter().position(|&b| b == b'\n')
+            .ok_or_else(|| ImportError::new(path, Some(line_nr), "unexpected end of file in header"))?;
+        let line = std::str::from_utf8(&data[offset..offset + end])
+            .map_err(|_| ImportError::new(path, Some(line_nr), "header is not valid text"))?
+            .trim_end_matches('\r');
+        offset += end + 1;
+
+        let err = |message: String| ImportError::new(path, Some(line_nr), message);
+        let mut tokens = line.split_whitespace();
+
+        if line_nr == 1 {
+            if line != "ply" {
+                return Err(err("not a PLY file".to_string()));
+            }
+            continue;
+        }
+
+        match tokens.next() {
+            Some("format") => {
+                format = Some(match tokens.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::BinaryLittleEndian,
+                    Some("binary_big_endian") => Format::BinaryBigEndian,
+                    other => return Err(err(format!("unknown format '{}'", other.unwrap_or("")))),
+                });
+            },
+            Some("element") => {
+                let name = tokens.next().ok_or_else(|| err("missing element name".to_string()))?;
+                let count = tokens.next().and_then(|t| t.parse().ok()).ok_or_else(|| err(format!("invalid count for element '{name}'")))?;
+                elements.push(Element { name: name.to_string(), count, properties: Vec::new() });
+            },
+            Some("property") => {
+                let element = elements.last_mut().ok_or_else(|| err("property before any element".to_string()))?;
+                let ty_name = tokens.next().unwrap_or("");
+                let property = if ty_name == "list" {
+                    let count_ty = tokens.next().unwrap_or("");
+                    let item_ty = tokens.next().unwrap_or("");
+                    Property {
+                        ty: ScalarType::parse(item_ty).ok_or_else(|| err(format!("unknown property type '{item_ty}'")))?,
+                        list: Some(ScalarType::parse(count_ty).ok_or_else(|| err(format!("unknown property type '{count_ty}'")))?),
+                        name: tokens.next().ok_or_else(|| err("missing property name".to_string()))?.to_string(),
+                    }
+                } else {
+                    Property {
+                        ty: ScalarType::parse(ty_name).ok_or_else(|| err(format!("unknown property type '{ty_name}'")))?,
+                        list: None,
+                        name: tokens.next().ok_or_else(|| err("missing property name".to_string()))?.to_string(),
+                    }
+                };
+                element.properties.push(property);
+            },
+            Some("end_header") => break,
+            Some("comment") | Some("obj_info") | None => {},
+            Some(other) => return Err(err(format!("unknown header keyword '{other}'"))),
+        }
+    }
+
+    let format = format.ok_or_else(|| ImportError::new(path, None, "missing format in header"))?;
+    Ok(Header { format, elements, body_offset: offset, body_line: line_nr + 1 })
+}
+
+// List lengths and vertex indices are read like any other value, they have to be whole and not negative
+fn as_index(value: Float) -> Option<usize> {
+    (value >= 0. && value.fract() == 0.).then_some(value as usize)
+}
+
+// Reads the values of one element instance at a time. Scalars end up in `scalars`, lists in `lists`,
+// both indexed by property, so the buffers can be reused across instances.
+struct BodyReader<'a> {
+    path: &'a Path,
+    format: Format,
+    data: &'a [u8],
+    offset: usize,
+    line_nr: usize,
+    scalars: Vec<Float>,
+    lists: Vec<Vec<Float>>,
+}
+
+impl BodyReader<'_> {
+    fn read_binary(&mut self, ty: ScalarType) -> Option<Float> {
+        let bytes = self.data.get(self.offset..self.offset + ty.size())?;
+        self.offset += ty.size();
+
+        macro_rules! convert {
+            ($t:ty) => {{
+                let b = bytes.try_into().unwrap();
+                (if self.format == Format::BinaryLittleEndian { <$t>::from_le_bytes(b) } else { <$t>::from_be_bytes(b) }) as Float
+            }};
+        }
+
+        Some(match ty {
+            ScalarType::I8 => convert!(i8),
+            ScalarType::U8 => convert!(u8),
+            ScalarType::I16 => convert!(i16),
+            ScalarType::U16 => convert!(u16),
+            ScalarType::I32 => convert!(i32),
+            ScalarType::U32 => convert!(u32),
+            ScalarType::F32 => convert!(f32),
+            ScalarType::F64 => convert!(f64),
+        })
+    }
+
+    fn read_instance(&mut self, element: &Element, index: usize) -> Result<(), ImportError> {
+        self.scalars.resize(element.properties.len(), 0.);
+        self.lists.resize(element.properties.len(), Vec::new());
+
+        if self.format == Format::Ascii {
+            self.read_ascii_instance(element)
+        } else {
+            let eof = || ImportError::new(self.path, None, format!("unexpected end of file in element '{}' {index}", element.name));
+            for (i, property) in element.properties.iter().enumerate() {
+                if let Some(count_ty) = property.list {
+                    let value = self.read_binary(count_ty).ok_or_else(eof)?;
+                    let count = as_index(value)
+                        .ok_or_else(|| ImportError::new(self.path, None, format!("invalid list length {value} in element '{}' {index}", element.name)))?;
+                    self.lists[i].clear();
+                    for _ in 0..count {
+                        let value = self.read_binary(property.ty).ok_or_else(eof)?;
+                        self.lists[i].push(value);
+                    }
+                } else {
+                    self.scalars[i] = self.read_binary(property.ty).ok_or_else(eof)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn read_ascii_instance(&mut self, element: &Element) -> Result<(), ImportError> {
+        // every element instance is on its own line, skip empty ones
+        let line = loop {
+            if self.offset >= self.data.len() {
+                return Err(ImportError::new(self.path, Some(self.line_nr), format!("unexpected end of file in element '{}'", element.name)));
+            }
+            let end = self.data[self.offset..].iter().position(|&b| b == b'\n').map_or(self.data.len(), |e| self.offset + e);
+            let line = std::str::from_utf8(&self.data[self.offset..end])
+                .map_err(|_| ImportError::new(self.path, Some(self.line_nr), "line is not valid text"))?;
+            self.offset = end + 1;
+            self.line_nr += 1;
+            if !line.trim().is_empty() {
+                break line;
+            }
+        };
+
+        let path = self.path;
+        let line_nr = self.line_nr - 1;
+        let mut tokens = line.split_whitespace();
+        let mut next = |name: &str| -> Result<Float, ImportError> {
+            let token = tokens.next().ok_or_else(|| ImportError::new(path, Some(line_nr), format!("missing value for property '{name}'")))?;
+            token.parse().map_err(|_| ImportError::new(path, Some(line_nr), format!("invalid value '{token}' for property '{name}'")))
+        };
+
+        for (i, property) in element.properties.iter().enumerate() {
+            if property.list.is_some() {
+                let value = next(&property.name)?;
+                let count = as_index(value)
+                    .ok_or_else(|| ImportError::new(path, Some(line_nr), format!("invalid list length {value} for property '{}'", property.name)))?;
+                self.lists[i].clear();
+                for _ in 0..count {
+                    let value = next(&property.name)?;
+                    self.lists[i].push(value);
+                }
+            } else {
+                self.scalars[i] = next(&property.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_ply(data: &[u8], path: &Path, material: MaterialHandle) -> Result<TriangleMesh, ImportError> {
+    let header = parse_header(data, path)?;
+    let mut reader = BodyReader {
+        path,
+        format: header.format,
+        data,
+        offset: header.body_offset,
+        line_nr: header.body_line,
+        scalars: Vec::new(),
+        lists: Vec::new(),
+    };
+
+    let mut positions: Vec<Point> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<(Float, Float)> = Vec::new();
+    let mut colors: Vec<Color> = Vec::new();
+    let mut indices: Vec<[usize; 3]> = Vec::new();
+
+    for element in &header.elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let find = |names: &[&str]| names.iter().map(|n| element.property(n)).collect::<Option<Vec<usize>>>();
+                let position = find(&["x", "y", "z"])
+                    .ok_or_else(|| ImportError::new(path, None, "vertex element needs x, y and z properties"))?;
+                let normal = find(&["nx", "ny", "nz"]);
+                let uv = [["u", "v"], ["s", "t"], ["texture_u", "texture_v"], ["texture_s", "texture_t"]].iter().find_map(|names| find(names));
+                let color = find(&["red", "green", "blue"]);
+                // 8-bit colors are assumed to be gamma encoded, float colors linear
+                let color_scale = color.as_ref().map(|c| element.properties[c[0]].ty == ScalarType::U8);
+
+                for i in 0..element.count {
+                    reader.read_instance(element, i)?;
+                    let s = &reader.scalars;
+                    positions.push(vec3(s[position[0]], s[position[1]], s[position[2]]));
+                    if let Some(n) = &normal {
+                        normals.push(vec3(s[n[0]], s[n[1]], s[n[2]]).normalize());
+                    }
+                    if let Some(uv) = &uv {
+                        uvs.push((s[uv[0]], s[uv[1]]));
+                    }
+                    if let Some(c) = &color {
+                        let decode = |v: Float| if color_scale == Some(true) { (v / 255.).powf(2.2) } else { v };
+                        colors.push(color_rgb(decode(s[c[0]]), decode(s[c[1]]), decode(s[c[2]])));
+                    }
+                }
+            },
+            "face" => {
+                let list = element.property("vertex_indices").or_else(|| element.property("vertex_index"))
+                    .filter(|&i| element.properties[i].list.is_some())
+                    .ok_or_else(|| ImportError::new(path, None, "face element needs a vertex_indices list property"))?;
+
+                for i in 0..element.count {
+                    reader.read_instance(element, i)?;
+                    let face = &reader.lists[list];
+                    let line = if header.format == Format::Ascii { Some(reader.line_nr - 1) } else { None };
+                    if face.len() < 3 {
+                        return Err(ImportError::new(path, line, format!("face {i} needs at least 3 vertices, found {}", face.len())));
+                    }
+                    let index = |v: Float| as_index(v).ok_or_else(|| ImportError::new(path, line, format!("invalid vertex index {v} in face {i}")));
+                    for j in 1..face.len() - 1 {
+                        indices.push([index(face[0])?, index(face[j])?, index(face[j + 1])?]);
+                    }
+                }
+            },
+            _ => {
+                for i in 0..element.count {
+                    reader.read_instance(element, i)?;
+                }
+            },
+        }
+    }
+
+    if let Some(i) = indices.iter().flatten().find(|&&i| i >= positions.len()) {
+        return Err(ImportError::new(path, None, format!("vertex index {i} out of range ({} vertices)", positions.len())));
+    }
+
+    let mut mesh = TriangleMesh::new(positions, indices, material);
+    if !normals.is_empty() { mesh.normals = Some(normals); }
+    if !uvs.is_empty() { mesh.uvs = Some(uvs); }
+    if !colors.is_empty() { mesh.colors = Some(colors); }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{color::color_rgb, material::NO_MATERIAL};
+    use super::parse_ply;
+
+    const HEADER: &str = "ply
+format FORMAT 1.0
+comment a unit square
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    const VERTICES: [[f32; 6]; 4] = [[0., 0., 0., 0., 0., 1.], [1., 0., 0., 0., 0., 1.], [1., 1., 0., 0., 0., 1.], [0., 1., 0., 0., 0., 1.]];
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = HEADER.replace("FORMAT", format).into_bytes();
+        for v in VERTICES {
+            for f in v {
+                data.extend(if big_endian { f.to_be_bytes() } else { f.to_le_bytes() });
+            }
+            data.extend([255, 0, 0]);
+        }
+        data.push(4);
+        for i in [0i32, 1, 2, 3] {
+            data.extend(if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        data
+    }
+
+    #[test]
+    fn test_ply_formats() {
+        let ascii = HEADER.replace("FORMAT", "ascii") + "0 0 0 0 0 1 255 0 0\n1 0 0 0 0 1 255 0 0\n1 1 0 0 0 1 255 0 0\n0 1 0 0 0 1 255 0 0\n4 0 1 2 3\n";
+
+        for data in [ascii.into_bytes(), binary(false), binary(true)] {
+            let mesh = parse_ply(&data, Path::new("test.ply"), NO_MATERIAL).unwrap();
+            assert_eq!(mesh.positions, vec![vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(1, 1, 0), vec3!(0, 1, 0)]);
+            assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+            assert_eq!(mesh.normals.unwrap()[2], vec3!(0, 0, 1));
+            assert_eq!(mesh.colors.unwrap()[1], color_rgb(1., 0., 0.));
+            assert!(mesh.uvs.is_none());
+        }
+    }
+
+    #[test]
+    fn test_ply_errors() {
+        let parse = |data: &[u8]| parse_ply(data, Path::new("test.ply"), NO_MATERIAL).map(|_| ()).unwrap_err().to_string();
+        let minimal = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n";
+
+        assert_eq!(parse(b"obj\n"), "test.ply:1: not a PLY file");
+        assert_eq!(parse(b"ply\nformat ascii 1.0\nproperty float x\nend_header\n"), "test.ply:3: property before any element");
+        assert_eq!(parse(b"ply\nformat ascii 1.0\nelement vertex 1\nproperty quad x\nend_header\n"), "test.ply:4: unknown property type 'quad'");
+        assert_eq!(parse(format!("{minimal}0 0 0\n1 0 0\n0 1 x\n3 0 1 2\n").as_bytes()), "test.ply:12: invalid value 'x' for property 'z'");
+        assert_eq!(parse(format!("{minimal}0 0 0\n1 0 0\n0 1 0\n2 0 1\n").as_bytes()), "test.ply:13: face 0 needs at least 3 vertices, found 2");
+        assert_eq!(parse(format!("{minimal}0 0 0\n1 0 0\n0 1 0\n3 0 1 3\n").as_bytes()), "test.ply: vertex index 3 out of range (3 vertices)");
+        assert_eq!(parse(format!("{minimal}0 0 0\n1 0 0\n0 1 0\n3 0 -1 2\n").as_bytes()), "test.ply:13: invalid vertex index -1 in face 0");
+        assert_eq!(parse(format!("{minimal}0 0 0\n1 0 0\n0 1 0\n-3 0 1 2\n").as_bytes()), "test.ply:13: invalid list length -3 for property 'vertex_indices'");
+
+        let truncated = binary(false);
+        assert_eq!(parse(&truncated[..truncated.len() - 2]), "test.ply: unexpected end of file in element 'face' 0");
+
+        let mut negative = binary(false);
+        let len = negative.len();
+        negative[len - 4..].copy_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(parse(&negative), "test.ply: invalid vertex index -1 in face 0");
+
+        // counts are only checked against the data actually there
+        let huge = minimal.replace("element vertex 3", "element vertex 99999999999");
+        assert_eq!(parse(format!("{huge}0 0 0\n").as_bytes()), "test.ply:11: unexpected end of file in element 'vertex'");
+        let mut huge = b"ply\nformat binary_little_endian 1.0\nelement vertex 99999999999\nproperty float x\nproperty float y\nproperty float z\nend_header\n".to_vec();
+        huge.extend_from_slice(&[0; 12]);
+        assert_eq!(parse(&huge), "test.ply: unexpected end of file in element 'vertex' 1");
+    }
+}
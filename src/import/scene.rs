@@ -0,0 +1,1023 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::{Display, Formatter}, fs::read_to_string, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Deserializer, de::{Error, MapAccess, Visitor, value::MapAccessDeserializer}};
+use toml::Spanned;
+
+use crate::{
+    animation::{Keyframes, Timeline, TransformKey}, camera::{Camera, Rigged, aperture::{Aperture, ApertureMask}, equirectangular::EquirectangularCamera, fisheye::FisheyeCamera, orthographic::OrthographicCamera, realistic::{LensElement, RealisticCamera}, thin_lens::{CameraKey, ThinLensCamera, f_stop_defocus_angle}}, color::color_rgb, config::{Color, Film, Float}, exposure::{Exposure, Metering}, hit::{bvh::{AxisAlignedBound, BvhBuilder}, instance::{Blas, Instance, Keyframed}, quad::{quad, quad_box}, sphere::sphere, triangle::TriangleMesh},
+    import::{ImportError, lens::load_lens, obj::load_obj, ply::load_ply}, integrator::{EvaluatorKind, IntegratorSettings}, material::{MaterialHandle, MaterialRepository, principled::{Parameter, Principled}, simple::{emissive, emissive_texture, lambertian, lambertian_texture, metal, rough_dielectric}},
+    light::Light, ray::Ray, sampler::SamplerKind, scene::{Scene, overcast_sky_background}, texture::{TextureHandle, TextureRepository, try_load_image_linear}, transform::{Quaternion, Transform}, vec3::Vec3
+};
+
+// Declarative TOML scene description, see scenes/ for examples. Unknown keys and invalid values are rejected
+// with the line they're on, references to materials and textures are checked when building the scene.
+// Relative paths are resolved against the directory of the scene file.
+pub struct SceneFile {
+    pub path: PathBuf,
+    source: String,
+    pub description: SceneDescription,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDescription {
+    #[serde(default)]
+    pub render: RenderSettings,
+    pub animation: Option<AnimationDescription>,
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub background: BackgroundDescription,
+    // sorted by name, so materials are registered and textures loaded in the same order on every run
+    #[serde(default)]
+    pub textures: BTreeMap<String, String>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, Spanned<MaterialDescription>>,
+    // meshes that can be placed any number of times by instance objects, while being loaded only once
+    #[serde(default)]
+    pub meshes: BTreeMap<String, MeshFileDescription>,
+    #[serde(default)]
+    pub objects: Vec<ObjectDescription>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct RenderSettings {
+    #[serde(deserialize_with = "positive")]
+    pub width: usize,
+    #[serde(deserialize_with = "positive")]
+    pub height: usize,
+    #[serde(deserialize_with = "positive")]
+    pub variance_target: Float,
+    #[serde(deserialize_with = "positive")]
+    pub min_samples: usize,
+    #[serde(deserialize_with = "positive")]
+    pub max_samples: usize,
+    #[serde(deserialize_with = "positive")]
+    pub max_bounces: usize,
+    pub sampler: SamplerKind,
+    pub evaluator: EvaluatorKind,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        let IntegratorSettings { min_samples, max_samples, variance_target, max_bounces, .. } = IntegratorSettings::default();
+        RenderSettings { width: 800, height: 450, variance_target, min_samples, max_samples, max_bounces, sampler: SamplerKind::default(), evaluator: EvaluatorKind::default() }
+    }
+}
+
+// Renders a sequence of frames instead of a still, see Timeline. The camera shutter is replaced by each frame's.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnimationDescription {
+    #[serde(deserialize_with = "positive")]
+    pub frames: usize,
+    #[serde(default)]
+    pub start: Float,
+    #[serde(default = "default_end")]
+    pub end: Float,
+    #[serde(default = "default_shutter", deserialize_with = "fraction")]
+    pub shutter: Float,
+}
+
+fn default_end() -> Float { Timeline::default().end }
+fn default_shutter() -> Float { Timeline::default().shutter }
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDescription {
+    pub from: [Float; 3],
+    pub to: Spanned<[Float; 3]>,
+    #[serde(default = "default_up")]
+    pub up: [Float; 3],
+    #[serde(default)]
+    pub projection: Projection,
+    // across the film for perspective cameras and across the image circle for fisheyes
+    pub vfov: Option<Spanned<Float>>,
+    // how much of the scene an orthographic camera takes in vertically
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub view_height: Option<Float>,
+    // defaults to the distance between from and to
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub focus_distance: Option<Float>,
+    #[serde(default)]
+    pub defocus_angle: Option<Float>,
+    // a real lens, which sets defocus_angle for a scene in meters and the exposure along with the next two
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub f_stop: Option<Float>,
+    // the shape of the lens opening, a disk without this
+    pub aperture: Option<Spanned<ApertureDescription>>,
+    // the lens prescription the lens projection traces rays through
+    pub lens: Option<LensDescription>,
+    // in seconds, not to be confused with shutter, which is when in scene time rays are taken
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub shutter_speed: Option<Float>,
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub iso: Option<Float>,
+    #[serde(default)]
+    pub auto_exposure: bool,
+    // in stops, brightens auto exposed images
+    #[serde(default)]
+    pub exposure_compensation: Float,
+    pub shutter: Option<Spanned<[Float; 2]>>,
+    // moves the camera as a whole, on top of from, to and up. Either this or path, not both
+    #[serde(default)]
+    pub keys: Vec<KeyDescription>,
+    #[serde(default)]
+    pub path: Vec<CameraKeyDescription>,
+}
+
+// One point along an animated camera path, anything left out is taken from the camera table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraKeyDescription {
+    pub time: Spanned<Float>,
+    pub from: Option<[Float; 3]>,
+    pub to: Option<[Float; 3]>,
+    pub up: Option<[Float; 3]>,
+    #[serde(default, deserialize_with = "optional_field_of_view")]
+    pub vfov: Option<Float>,
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub focus_distance: Option<Float>,
+    pub defocus_angle: Option<Float>,
+}
+
+fn default_up() -> [Float; 3] { [0., 1., 0.] }
+
+// blades for a polygon, or an image whose bright parts are the opening
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApertureDescription {
+    pub blades: Option<Spanned<u32>>,
+    // in degrees, turns the polygon
+    #[serde(default)]
+    pub rotation: Float,
+    pub mask: Option<Spanned<String>>,
+}
+
+// A lens file in pbrt's format, see res/lenses. Sizes are in millimeters like in the file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LensDescription {
+    pub file: Spanned<String>,
+    #[serde(default = "default_sensor_height", deserialize_with = "positive")]
+    pub sensor_height: Float,
+    // stops the lens down from its widest opening
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub stop_diameter: Option<Float>,
+}
+
+fn default_sensor_height() -> Float { 24. }
+
+impl CameraDescription {
+    fn defocus_angle(&self, vfov: Float, focus_distance: Float) -> Float {
+        match self.f_stop {
+            Some(f_stop) => f_stop_defocus_angle(f_stop, vfov, focus_distance),
+            None => self.defocus_angle.unwrap_or(0.),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+    Lens,
+}
+
+// without a color we get the overcast sky
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BackgroundDescription {
+    pub color: Option<[Float; 3]>,
+}
+
+pub enum MaterialDescription {
+    Lambertian(LambertianDescription),
+    Metal(MetalDescription),
+    Dielectric(DielectricDescription),
+    Emissive(EmissiveDescription),
+    Principled(Box<PrincipledDescription>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LambertianDescription { pub color: Option<[Float; 3]>, pub texture: Option<Spanned<String>> }
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetalDescription { pub color: [Float; 3], #[serde(default, deserialize_with = "fraction")] pub roughness: Float }
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DielectricDescription { #[serde(deserialize_with = "positive")] pub ir: Float, #[serde(default, deserialize_with = "fraction")] pub roughness: Float }
+
+// the color is the emitted radiance, so it can (and for small lights should) be well above 1
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmissiveDescription { pub color: Option<[Float; 3]>, pub texture: Option<Spanned<String>>, #[serde(default = "default_intensity")] pub intensity: Float }
+
+fn default_intensity() -> Float { 1. }
+
+// every parameter can come from a texture instead, named by the same key with _texture appended. Left out ones
+// get Principled's defaults
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrincipledDescription {
+    pub base_color: Option<[Float; 3]>,
+    pub base_color_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub metallic: Option<Float>,
+    pub metallic_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub roughness: Option<Float>,
+    pub roughness_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub specular: Option<Float>,
+    pub specular_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub sheen: Option<Float>,
+    pub sheen_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub clearcoat: Option<Float>,
+    pub clearcoat_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_fraction")]
+    pub transmission: Option<Float>,
+    pub transmission_texture: Option<Spanned<String>>,
+    #[serde(default, deserialize_with = "optional_positive")]
+    pub ior: Option<Float>,
+    pub emission: Option<[Float; 3]>,
+    pub emission_texture: Option<Spanned<String>>,
+    #[serde(default = "default_intensity")]
+    pub emission_intensity: Float,
+}
+
+pub enum ObjectDescription {
+    Sphere(SphereDescription),
+    Quad(QuadDescription),
+    Box(BoxDescription),
+    Obj(MeshDescription),
+    Ply(MeshDescription),
+    Instance(InstanceDescription),
+}
+
+pub enum MeshFileDescription {
+    Obj(MeshDescription),
+    Ply(MeshDescription),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SphereDescription { pub center: [Float; 3], #[serde(deserialize_with = "positive")] pub radius: Float, pub material: Spanned<String> }
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuadDescription { pub origin: [Float; 3], pub u: [Float; 3], pub v: [Float; 3], pub material: Spanned<String> }
+
+// six quads spanned by two opposite corners
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoxDescription { pub min: [Float; 3], pub max: [Float; 3], pub material: Spanned<String> }
+
+// for OBJ files the material overrides the materials from the MTL files
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDescription { pub path: Spanned<String>, pub material: Option<Spanned<String>> }
+
+// The mesh is scaled, then rotated around the x, y and z axes in that order (in degrees), then offset.
+// Keys animate the instance on top of that, at the ray times given by the camera shutter.
+// The material replaces every material of the mesh, for this instance only.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceDescription {
+    pub mesh: Spanned<String>,
+    #[serde(default)]
+    pub offset: [Float; 3],
+    #[serde(default)]
+    pub rotate: [Float; 3],
+    pub scale: Option<Spanned<[Float; 3]>>,
+    #[serde(default)]
+    pub keys: Vec<KeyDescription>,
+    pub material: Option<Spanned<String>>,
+}
+
+// rotations are interpolated along the shortest arc, so turns of 180 degrees or more need keys in between
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyDescription {
+    pub time: Float,
+    #[serde(default)]
+    pub offset: [Float; 3],
+    #[serde(default)]
+    pub rotate: [Float; 3],
+    #[serde(default = "default_scale", deserialize_with = "positive_triple")]
+    pub scale: [Float; 3],
+}
+
+fn default_scale() -> [Float; 3] { [1., 1., 1.] }
+
+// serde's internally tagged enums buffer their contents, which loses the spans toml uses to point at
+// the offending line. Requiring the type to come first lets us stream the rest of the table straight
+// into the description of that type instead.
+macro_rules! tagged_description {
+    ($name:ident { $($tag:literal => $variant:ident($description:ty)),* $(,)? }) => {
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                struct TaggedVisitor;
+                impl<'de> Visitor<'de> for TaggedVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                        write!(f, "a table starting with a `type` key")
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<$name, A::Error> {
+                        if map.next_key::<String>()?.as_deref() != Some("type") {
+                            return Err(A::Error::custom("`type` must be the first key"));
+                        }
+                        let tag: String = map.next_value()?;
+                        match tag.as_str() {
+                            $($tag => <$description>::deserialize(MapAccessDeserializer::new(map)).map($name::$variant),)*
+                            other => Err(A::Error::unknown_variant(other, &[$($tag),*])),
+                        }
+                    }
+                }
+                d.deserialize_map(TaggedVisitor)
+            }
+        }
+    };
+}
+
+tagged_description!(MaterialDescription {
+    "lambertian" => Lambertian(LambertianDescription),
+    "metal" => Metal(MetalDescription),
+    "dielectric" => Dielectric(DielectricDescription),
+    "emissive" => Emissive(EmissiveDescription),
+    "principled" => Principled(Box<PrincipledDescription>),
+});
+
+tagged_description!(ObjectDescription {
+    "sphere" => Sphere(SphereDescription),
+    "quad" => Quad(QuadDescription),
+    "box" => Box(BoxDescription),
+    "obj" => Obj(MeshDescription),
+    "ply" => Ply(MeshDescription),
+    "instance" => Instance(InstanceDescription),
+});
+
+tagged_description!(MeshFileDescription {
+    "obj" => Obj(MeshDescription),
+    "ply" => Ply(MeshDescription),
+});
+
+fn positive<'de, D: Deserializer<'de>, T: Deserialize<'de> + PartialOrd + Default + Display>(d: D) -> Result<T, D::Error> {
+    let value = T::deserialize(d)?;
+    if value > T::default() { Ok(value) } else { Err(D::Error::custom(format!("expected a positive number, found {value}"))) }
+}
+
+fn optional_positive<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Float>, D::Error> {
+    positive(d).map(Some)
+}
+
+fn optional_fraction<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Float>, D::Error> {
+    fraction(d).map(Some)
+}
+
+fn positive_triple<'de, D: Deserializer<'de>>(d: D) -> Result<[Float; 3], D::Error> {
+    let value = <[Float; 3]>::deserialize(d)?;
+    if value.iter().all(|&v| v > 0.) { Ok(value) } else { Err(D::Error::custom(format!("expected positive numbers, found {value:?}"))) }
+}
+
+fn fraction<'de, D: Deserializer<'de>>(d: D) -> Result<Float, D::Error> {
+    let value = Float::deserialize(d)?;
+    if (0. ..=1.).contains(&value) { Ok(value) } else { Err(D::Error::custom(format!("expected a number between 0 and 1, found {value}"))) }
+}
+
+fn field_of_view<'de, D: Deserializer<'de>>(d: D) -> Result<Float, D::Error> {
+    let value = Float::deserialize(d)?;
+    if value > 0. && value < 180. { Ok(value) } else { Err(D::Error::custom(format!("expected a field of view between 0 and 180 degrees, found {value}"))) }
+}
+
+fn optional_field_of_view<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Float>, D::Error> {
+    field_of_view(d).map(Some)
+}
+
+fn triple(v: [Float; 3]) -> (Float, Float, Float) {
+    (v[0], v[1], v[2])
+}
+
+impl SceneFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<SceneFile, ImportError> {
+        let path = path.as_ref();
+        let source = read_to_string(path).map_err(|e| ImportError::new(path, None, format!("cannot read file: {e}")))?;
+        SceneFile::parse(source, path)
+    }
+
+    pub fn parse(source: String, path: &Path) -> Result<SceneFile, ImportError> {
+        let description = toml::from_str(&source).map_err(|e: toml::de::Error| {
+            let line = e.span().map(|span| line_of(&source, span.start));
+            ImportError::new(path, line, e.message().trim_end())
+        })?;
+        Ok(SceneFile { path: path.to_path_buf(), source, description })
+    }
+
+    pub fn render_settings(&self) -> RenderSettings {
+        self.description.render
+    }
+
+    pub fn timeline(&self) -> Result<Option<Timeline>, ImportError> {
+        let Some(AnimationDescription { frames, start, end, shutter }) = self.description.animation else { return Ok(None) };
+        if end < start {
+            return Err(ImportError::new(&self.path, None, "animation must not end before it starts"));
+        }
+        Ok(Some(Timeline { frames, start, end, shutter }))
+    }
+
+    // Anything a key leaves out comes from the camera table
+    fn camera_path(&self, camera: &CameraDescription, vfov: Float) -> Result<Keyframes<CameraKey>, ImportError> {
+        let (from, to, up): (Vec3, Vec3, Vec3) = (triple(camera.from).into(), triple(*camera.to.get_ref()).into(), triple(camera.up).into());
+        let keys = camera.path.iter().map(|key| {
+            let (from, to): (Vec3, Vec3) = (key.from.map_or(from, |v| triple(v).into()), key.to.map_or(to, |v| triple(v).into()));
+            if (from - to).near_zero() {
+                return Err(self.error_at(&key.time, format!("camera 'from' and 'to' must differ at time {}", key.time.get_ref())));
+            }
+            let (vfov, focus_distance) = (key.vfov.unwrap_or(vfov), key.focus_distance.or(camera.focus_distance).unwrap_or((from - to).length()));
+            Ok((*key.time.get_ref(), CameraKey {
+                from,
+                to,
+                up: key.up.map_or(up, |v| triple(v).into()),
+                vfov,
+                focus_distance,
+                defocus_angle: key.defocus_angle.unwrap_or(camera.defocus_angle(vfov, focus_distance)),
+            }))
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(Keyframes::new(keys))
+    }
+
+    fn aperture(&self, spanned: &Spanned<ApertureDescription>) -> Result<Aperture, ImportError> {
+        let aperture = spanned.get_ref();
+        match (&aperture.blades, &aperture.mask) {
+            (Some(blades), None) if *blades.get_ref() < 3 => Err(self.error_at(blades, format!("expected at least 3 blades, found {}", blades.get_ref()))),
+            (Some(blades), None) => Ok(Aperture::Polygon { blades: *blades.get_ref(), rotation: aperture.rotation }),
+            (None, Some(mask)) => {
+                let path = self.resolve(mask.get_ref());
+                let image = try_load_image_linear(&path.to_string_lossy())
+                    .map_err(|e| self.error_at(mask, format!("cannot load aperture mask '{}': {e}", path.display())))?;
+                let mask = ApertureMask::new(&image).ok_or_else(|| self.error_at(mask, format!("aperture mask '{}' is black all over", path.display())))?;
+                Ok(Aperture::Mask(mask))
+            },
+            _ => Err(self.error_at(spanned, "aperture needs exactly one of blades and mask".to_string())),
+        }
+    }
+
+    fn lens(&self, lens: &LensDescription) -> Result<Vec<LensElement>, ImportError> {
+        let path = self.resolve(lens.file.get_ref());
+        let mut elements = load_lens(&path).map_err(|e| self.error_at(&lens.file, format!("cannot load lens: {e}")))?;
+        if let Some(diameter) = lens.stop_diameter {
+            let stop = elements.iter_mut().find(|e| e.radius == 0.)
+                .ok_or_else(|| self.error_at(&lens.file, format!("lens '{}' has no aperture stop", path.display())))?;
+            if diameter > stop.aperture {
+                return Err(self.error_at(&lens.file, format!("expected a stop_diameter of at most {} for this lens, found {diameter}", stop.aperture)));
+            }
+            stop.aperture = diameter;
+        }
+        Ok(elements)
+    }
+
+    fn error_at<T>(&self, item: &Spanned<T>, message: String) -> ImportError {
+        ImportError::new(&self.path, Some(line_of(&self.source, item.span().start)), message)
+    }
+
+    // for scenes that still render, just not the way the user might expect
+    fn warn<T>(&self, item: &Spanned<T>, message: &str) {
+        eprintln!("warning: {}", self.error_at(item, message.to_string()));
+    }
+
+    fn resolve(&self, file: &str) -> PathBuf {
+        self.path.parent().unwrap_or(Path::new("")).join(file)
+    }
+
+    fn material(&self, name: &Spanned<String>, materials: &HashMap<String, MaterialHandle>) -> Result<MaterialHandle, ImportError> {
+        materials.get(name.get_ref()).copied().ok_or_else(|| self.error_at(name, format!("undefined material '{}'", name.get_ref())))
+    }
+
+    fn texture(&self, texture: &Spanned<String>, texture_repository: &mut TextureRepository) -> Result<TextureHandle, ImportError> {
+        let file = self.description.textures.get(texture.get_ref())
+            .ok_or_else(|| self.error_at(texture, format!("undefined texture '{}'", texture.get_ref())))?;
+        let texture_path = self.resolve(file);
+        texture_repository.try_load_texture(&texture_path.to_string_lossy())
+            .map_err(|e| self.error_at(texture, format!("cannot load texture '{}': {e}", texture_path.display())))
+    }
+
+    fn parameter<T>(&self, name: &str, key: &str, value: Option<T>, texture: &Option<Spanned<String>>, default: Parameter<T>, texture_repository: &mut TextureRepository) -> Result<Parameter<T>, ImportError> {
+        match (value, texture) {
+            (Some(_), Some(texture)) => Err(self.error_at(texture, format!("material '{name}' needs at most one of {key} and {key}_texture"))),
+            (Some(value), None) => Ok(Parameter::Value(value)),
+            (None, Some(texture)) => Ok(Parameter::Texture(self.texture(texture, texture_repository)?)),
+            (None, None) => Ok(default),
+        }
+    }
+
+    fn principled(&self, name: &str, p: &PrincipledDescription, texture_repository: &mut TextureRepository) -> Result<Principled, ImportError> {
+        let default = Principled::default();
+        let color = |c: Option<[Float; 3]>| c.map(|c| Color::from(triple(c)));
+        Ok(Principled {
+            base_color: self.parameter(name, "base_color", color(p.base_color), &p.base_color_texture, default.base_color, texture_repository)?,
+            metallic: self.parameter(name, "metallic", p.metallic, &p.metallic_texture, default.metallic, texture_repository)?,
+            roughness: self.parameter(name, "roughness", p.roughness, &p.roughness_texture, default.roughness, texture_repository)?,
+            specular: self.parameter(name, "specular", p.specular, &p.specular_texture, default.specular, texture_repository)?,
+            sheen: self.parameter(name, "sheen", p.sheen, &p.sheen_texture, default.sheen, texture_repository)?,
+            clearcoat: self.parameter(name, "clearcoat", p.clearcoat, &p.clearcoat_texture, default.clearcoat, texture_repository)?,
+            transmission: self.parameter(name, "transmission", p.transmission, &p.transmission_texture, default.transmission, texture_repository)?,
+            ior: p.ior.unwrap_or(default.ior),
+            emission: self.parameter(name, "emission", color(p.emission), &p.emission_texture, default.emission, texture_repository)?,
+            emission_intensity: p.emission_intensity,
+        })
+    }
+
+    fn build_material(&self, name: &str, material: &Spanned<MaterialDescription>, texture_repository: &mut TextureRepository, material_repository: &mut MaterialRepository) -> Result<MaterialHandle, ImportError> {
+        let color_or_texture = || self.error_at(material, format!("material '{name}' needs exactly one of color and texture"));
+        let material = match material.get_ref() {
+            MaterialDescription::Lambertian(LambertianDescription { color: Some(color), texture: None }) => lambertian(triple(*color)),
+            MaterialDescription::Lambertian(LambertianDescription { color: None, texture: Some(texture) }) => lambertian_texture(self.texture(texture, texture_repository)?),
+            MaterialDescription::Lambertian(_) => return Err(color_or_texture()),
+            MaterialDescription::Metal(MetalDescription { color, roughness }) => metal(triple(*color), *roughness),
+            MaterialDescription::Dielectric(DielectricDescription { ir, roughness }) => rough_dielectric(*ir, *roughness),
+            MaterialDescription::Emissive(EmissiveDescription { color: Some(color), texture: None, intensity }) => emissive(triple(color.map(|c| c * intensity))),
+            MaterialDescription::Emissive(EmissiveDescription { color: None, texture: Some(texture), intensity }) => emissive_texture(self.texture(texture, texture_repository)?, *intensity),
+            MaterialDescription::Emissive(_) => return Err(color_or_texture()),
+            MaterialDescription::Principled(p) => return Ok(material_repository.add(self.principled(name, p, texture_repository)?)),
+        };
+        Ok(material_repository.add(material))
+    }
+
+    fn obj_objects(&self, mesh: &MeshDescription, materials: &HashMap<String, MaterialHandle>, texture_repository: &mut TextureRepository, material_repository: &mut MaterialRepository) -> Result<Vec<Box<dyn AxisAlignedBound + Send + Sync>>, ImportError> {
+        let material = mesh.material.as_ref().map(|m| self.material(m, materials)).transpose()?;
+        let mut meshes = load_obj(self.resolve(mesh.path.get_ref()), texture_repository, material_repository)?;
+        if let Some(material) = material {
+            meshes.iter_mut().for_each(|m| m.material = material);
+        }
+        Ok(self.mesh_objects(&mesh.path, meshes, material_repository))
+    }
+
+    fn ply_objects(&self, mesh: &MeshDescription, materials: &HashMap<String, MaterialHandle>, material_repository: &mut MaterialRepository) -> Result<Vec<Box<dyn AxisAlignedBound + Send + Sync>>, ImportError> {
+        let material = match &mesh.material {
+            Some(m) => self.material(m, materials)?,
+            None => material_repository.add(Principled::default()),
+        };
+        Ok(self.mesh_objects(&mesh.path, vec![load_ply(self.resolve(mesh.path.get_ref()), material)?], material_repository))
+    }
+
+    fn mesh_objects(&self, path: &Spanned<String>, meshes: Vec<TriangleMesh>, material_repository: &MaterialRepository) -> Vec<Box<dyn AxisAlignedBound + Send + Sync>> {
+        if meshes.iter().any(|m| material_repository.material(m.material).is_emissive()) {
+            self.warn(path, "emissive meshes aren't sampled as lights, only scattered rays find them");
+        }
+        meshes.into_iter().flat_map(TriangleMesh::into_objects).collect()
+    }
+
+    pub fn build(&self, film: &Film, bvh: &mut BvhBuilder) -> Result<Scene, ImportError> {
+        let description = &self.description;
+        let mut texture_repository = TextureRepository::new();
+        let mut material_repository = MaterialRepository::new();
+
+        let mut materials = HashMap::new();
+        for (name, material) in &description.materials {
+            materials.insert(name.clone(), self.build_material(name, material, &mut texture_repository, &mut material_repository)?);
+        }
+
+        // every mesh gets its own BVH, shared by all of its instances
+        let mut meshes = HashMap::new();
+        for (name, mesh) in &description.meshes {
+            let objects = match mesh {
+                MeshFileDescription::Obj(mesh) => self.obj_objects(mesh, &materials, &mut texture_repository, &mut material_repository)?,
+                MeshFileDescription::Ply(mesh) => self.ply_objects(mesh, &materials, &mut material_repository)?,
+            };
+            if objects.is_empty() {
+                return Err(ImportError::new(&self.path, None, format!("mesh '{name}' has no triangles")));
+            }
+            meshes.insert(name.clone(), Blas::new(bvh, objects));
+        }
+
+        // spheres, quads and the sides of boxes with emissive materials can be sampled directly. Meshes and instances
+        // can't, mesh_objects and the instances below warn about them
+        let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = Vec::new();
+        let mut lights = Vec::new();
+        for object in &description.objects {
+            match object {
+                ObjectDescription::Sphere(SphereDescription { center, radius, material }) => {
+                    let sphere = sphere(triple(*center), *radius, self.material(material, &materials)?);
+                    if material_repository.material(sphere.material).is_emissive() { lights.push(Light::Sphere(sphere)); }
+                    objects.push(Box::new(sphere));
+                },
+                ObjectDescription::Quad(QuadDescription { origin, u, v, material }) => {
+                    let quad = quad(triple(*origin), triple(*u), triple(*v), self.material(material, &materials)?);
+                    if material_repository.material(quad.material).is_emissive() { lights.push(Light::Quad(quad)); }
+                    objects.push(Box::new(quad));
+                },
+                ObjectDescription::Box(BoxDescription { min, max, material }) => {
+                    let sides = quad_box(triple(*min), triple(*max), self.material(material, &materials)?);
+                    if material_repository.material(sides[0].material).is_emissive() { lights.extend(sides.iter().copied().map(Light::Quad)); }
+                    objects.extend(sides.into_iter().map(|q| Box::new(q) as Box<dyn AxisAlignedBound + Send + Sync>));
+                },
+                ObjectDescription::Obj(mesh) => objects.extend(self.obj_objects(mesh, &materials, &mut texture_repository, &mut material_repository)?),
+                ObjectDescription::Ply(mesh) => objects.extend(self.ply_objects(mesh, &materials, &mut material_repository)?),
+                ObjectDescription::Instance(InstanceDescription { mesh, offset, rotate, scale, keys, material }) => {
+                    let blas = meshes.get(mesh.get_ref()).ok_or_else(|| self.error_at(mesh, format!("undefined mesh '{}'", mesh.get_ref())))?;
+                    let material = match material {
+                        Some(m) => {
+                            let handle = self.material(m, &materials)?;
+                            if material_repository.material(handle).is_emissive() {
+                                self.warn(m, "emissive instances aren't sampled as lights, only scattered rays find them");
+                            }
+                            Some(handle)
+                        },
+                        None => None,
+                    };
+                    let scale = match scale {
+                        Some(scale) => Transform::scale(triple(*scale.get_ref()).into()).ok_or_else(|| self.error_at(scale, "scale factors must not be zero".to_string()))?,
+                        None => Transform::IDENTITY,
+                    };
+                    let transform = Transform::translate(triple(*offset).into()) * Transform::rotate(vec3!(0, 0, 1), rotate[2])
+                        * Transform::rotate(vec3!(0, 1, 0), rotate[1]) * Transform::rotate(vec3!(1, 0, 0), rotate[0]) * scale;
+                    let instance = Instance { blas: blas.clone(), transform, material };
+                    if keys.is_empty() {
+                        objects.push(Box::new(instance));
+                    } else {
+                        objects.push(Box::new(Keyframed::new(transform_keys(keys), Box::new(instance))));
+                    }
+                },
+            }
+        }
+
+        let camera = &description.camera;
+        let (from, to): (Vec3, Vec3) = (triple(camera.from).into(), triple(*camera.to.get_ref()).into());
+        if (from - to).near_zero() {
+            return Err(self.error_at(&camera.to, "camera 'from' and 'to' must differ".to_string()));
+        }
+        let shutter = match &camera.shutter {
+            Some(shutter) if shutter.get_ref()[0] > shutter.get_ref()[1] => return Err(self.error_at(shutter, "camera shutter must not close before it opens".to_string())),
+            Some(shutter) => *shutter.get_ref(),
+            None => [0., 0.],
+        };
+        if camera.f_stop.is_some() && camera.defocus_angle.is_some() {
+            return Err(ImportError::new(&self.path, None, "camera takes either an f_stop or a defocus_angle"));
+        }
+        let exposure = match (camera.auto_exposure, camera.shutter_speed) {
+            (true, Some(_)) => return Err(ImportError::new(&self.path, None, "camera takes either auto_exposure or a shutter_speed")),
+            (true, None) => Metering::Auto { compensation: camera.exposure_compensation },
+            (false, Some(shutter_speed)) => {
+                let f_stop = camera.f_stop.ok_or_else(|| ImportError::new(&self.path, None, "camera shutter_speed needs an f_stop"))?;
+                Metering::Manual(Exposure { f_stop, shutter_speed, iso: camera.iso.unwrap_or(100.) })
+            },
+            (false, None) if camera.iso.is_some() => return Err(ImportError::new(&self.path, None, "camera iso needs a shutter_speed")),
+            (false, None) => Metering::None,
+        };
+        let (up, shutter) = (triple(camera.up).into(), (shutter[0], shutter[1]).into());
+        let vfov = |max: Float| match &camera.vfov {
+            Some(vfov) if *vfov.get_ref() > 0. && *vfov.get_ref() < max => Ok(*vfov.get_ref()),
+            Some(vfov) => Err(self.error_at(vfov, format!("expected a field of view between 0 and {max} degrees, found {}", vfov.get_ref()))),
+            None => Err(ImportError::new(&self.path, None, "camera needs a vfov")),
+        };
+        if let (Some(_), Some(key)) = (camera.keys.first(), camera.path.first()) {
+            return Err(self.error_at(&key.time, "camera takes either keys or a path".to_string()));
+        }
+        let mut cam: Box<dyn Camera + Send + Sync> = match camera.projection {
+            _ if camera.projection != Projection::Perspective && !camera.path.is_empty() => {
+                return Err(self.error_at(&camera.path[0].time, "camera path needs the perspective projection".to_string()));
+            },
+            _ if camera.projection != Projection::Perspective && camera.aperture.is_some() => {
+                return Err(self.error_at(camera.aperture.as_ref().unwrap(), "camera aperture needs the perspective projection".to_string()));
+            },
+            _ if camera.projection != Projection::Lens && camera.lens.is_some() => {
+                return Err(ImportError::new(&self.path, None, "camera lens needs the lens projection"));
+            },
+            Projection::Perspective => {
+                let cam = if camera.path.is_empty() {
+                    let focus_distance = camera.focus_distance.unwrap_or((from - to).length());
+                    let vfov = vfov(180.)?;
+                    ThinLensCamera::new(film, from, to, up, vfov, focus_distance, camera.defocus_angle(vfov, focus_distance), shutter)
+                } else {
+                    ThinLensCamera::animated(film, self.camera_path(camera, vfov(180.)?)?, shutter)
+                };
+                match &camera.aperture {
+                    Some(aperture) => Box::new(cam.with_aperture(self.aperture(aperture)?)),
+                    None => Box::new(cam),
+                }
+            },
+            Projection::Orthographic => {
+                let view_height = camera.view_height.ok_or_else(|| ImportError::new(&self.path, None, "orthographic camera needs a view_height"))?;
+                Box::new(OrthographicCamera::new(film, from, to, up, view_height, shutter))
+            },
+            Projection::Fisheye => Box::new(FisheyeCamera::new(film, from, to, up, vfov(360.)?, shutter)),
+            Projection::Equirectangular => Box::new(EquirectangularCamera::new(film, from, to, up, shutter)),
+            Projection::Lens => {
+                let lens = camera.lens.as_ref().ok_or_else(|| ImportError::new(&self.path, None, "lens projection needs a lens"))?;
+                let focus_distance = camera.focus_distance.unwrap_or((from - to).length());
+                let cam = RealisticCamera::new(film, from, to, up, self.lens(lens)?, lens.sensor_height, focus_distance, shutter)
+                    .ok_or_else(|| self.error_at(&lens.file, format!("lens can't focus {focus_distance} away")))?;
+                Box::new(cam)
+            },
+        };
+        if !camera.keys.is_empty() {
+            cam = Box::new(Rigged { rig: transform_keys(&camera.keys), camera: cam });
+        }
+
+        let background_color: Box<dyn Fn(Ray) -> Color + Send + Sync> = match description.background.color {
+            None => Box::new(overcast_sky_background),
+            Some(color) => {
+                let color = color_rgb(color[0], color[1], color[2]);
+                Box::new(move |_| color)
+            },
+        };
+
+        Ok(Scene { objects: bvh.build_objects(objects), background_color, cam, texture_repository, material_repository, lights, exposure })
+    }
+}
+
+fn transform_keys(keys: &[KeyDescription]) -> Keyframes<TransformKey> {
+    Keyframes::new(keys.iter().map(|k| (k.time, TransformKey {
+        translation: triple(k.offset).into(), rotation: Quaternion::from_euler(triple(k.rotate).into()), scale: triple(k.scale).into()
+    })).collect())
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use approx::assert_ulps_eq;
+
+    use crate::{animation::Timeline, config::{Film, Float}, exposure::{Exposure, Metering}, hit::bvh::BvhBuilder, import::scene::{RenderSettings, SceneFile}, light::Light, material::{principled::{Parameter, Principled}, simple::{emissive, lambertian, rough_dielectric}}, ray::ray, vec3::dot};
+
+    fn error(source: &str) -> String {
+        match SceneFile::parse(source.to_string(), Path::new("test.toml")).and_then(|f| f.build(&Film::new((4, 3)), &mut BvhBuilder::default())) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const CAMERA: &str = "[camera]\nfrom = [0, 0, 0]\nto = [0, 0, -1]\nvfov = 90\n";
+
+    #[test]
+    fn test_scene_file() {
+        let file = SceneFile::load("scenes/simple.toml").unwrap();
+        assert_eq!(file.render_settings(), RenderSettings { width: 400, height: 225, ..RenderSettings::default() });
+
+        let scene = file.build(&Film::new((400, 225)), &mut BvhBuilder::default()).unwrap();
+        let hit = scene.objects.hit(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 0.5);
+
+        let file = SceneFile::parse(format!("{CAMERA}[background]\ncolor = [0.1, 0.2, 0.3]\n"), Path::new("test.toml")).unwrap();
+        assert_eq!(file.render_settings(), RenderSettings::default());
+        let scene = file.build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+        assert_eq!((scene.background_color)(ray!((0, 0, 0) -> (0, 1, 0))), (0.1, 0.2, 0.3).into());
+        assert!(scene.objects.hit(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).is_none());
+
+        let frosted = format!("{CAMERA}[materials]\nfrosted = {{ type = \"dielectric\", ir = 1.5, roughness = 0.3 }}\n\n[[objects]]\ntype = \"sphere\"\ncenter = [0, 0, -2]\nradius = 1\nmaterial = \"frosted\"\n");
+        let scene = SceneFile::parse(frosted.clone(), Path::new("test.toml")).unwrap().build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+        let hit = scene.objects.hit(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        assert_eq!(scene.material_repository.get(hit.material), Some(&rough_dielectric(1.5, 0.3)));
+        assert_eq!(error(&frosted.replace("0.3", "2")), "test.toml:6: expected a number between 0 and 1, found 2");
+
+        // materials get their handles in name order, whatever order the file lists them in
+        let named = frosted.replace("frosted = {", "matte = { type = \"lambertian\", color = [0.5, 0.5, 0.5] }\nfrosted = {");
+        let scene = SceneFile::parse(named, Path::new("test.toml")).unwrap().build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+        assert_eq!(scene.objects.hit(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap().material, 1);
+        assert_eq!(scene.material_repository.get(2), Some(&lambertian((0.5, 0.5, 0.5))));
+    }
+
+    #[test]
+    fn test_scene_file_lights() {
+        let file = SceneFile::load("scenes/cornell.toml").unwrap();
+        assert_eq!(file.render_settings().max_samples, 256);
+
+        let scene = file.build(&Film::new((4, 4)), &mut BvhBuilder::default()).unwrap();
+        assert_eq!(scene.lights.len(), 1);
+        let hit = scene.objects.hit(ray!((278, 300, 278) -> (0, 1, 0)), 0.001, Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 254.);
+        assert_eq!(scene.material_repository.get(hit.material), Some(&emissive((15., 15., 15.))));
+
+        // inside the short box
+        let hit = scene.objects.hit(ray!((200, 100, 150) -> (0, 1, 0)), 0.001, Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 65.);
+
+        // every side of an emissive box is a light
+        let source = format!("{CAMERA}[materials]\nlight = {{ type = \"emissive\", color = [4, 4, 4] }}\n\n\
+            [[objects]]\ntype = \"box\"\nmin = [-1, -1, -3]\nmax = [1, 1, -2]\nmaterial = \"light\"\n");
+        let scene = SceneFile::parse(source, Path::new("test.toml")).unwrap().build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+        assert_eq!(scene.lights.len(), 6);
+        assert!(scene.lights.iter().all(|l| matches!(l, Light::Quad(_))));
+
+        assert_eq!(error(&format!("{CAMERA}[materials]\nlight = {{ type = \"emissive\", intensity = 4 }}\n")), "test.toml:6: material 'light' needs exactly one of color and texture");
+    }
+
+    #[test]
+    fn test_scene_file_principled() {
+        let source = format!("{CAMERA}[textures]\nearth = \"../res/earthmap.jpg\"\n\n[materials]\n\
+            paint = {{ type = \"principled\", base_color = [0.8, 0.1, 0.1], roughness = 0.3, clearcoat = 1 }}\n\
+            globe = {{ type = \"principled\", base_color_texture = \"earth\", metallic = 1, roughness_texture = \"earth\" }}\n\n\
+            [[objects]]\ntype = \"sphere\"\ncenter = [0, 0, -2]\nradius = 1\nmaterial = \"paint\"\n\n\
+            [[objects]]\ntype = \"sphere\"\ncenter = [3, 0, -2]\nradius = 1\nmaterial = \"globe\"\n");
+        let file = SceneFile::parse(source.clone(), Path::new("scenes/test.toml")).unwrap();
+        let scene = file.build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+
+        let hit = scene.objects.hit(ray!((0, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        let paint = Principled {
+            base_color: Parameter::Value((0.8, 0.1, 0.1).into()), roughness: Parameter::Value(0.3), clearcoat: Parameter::Value(1.), ..Principled::default()
+        };
+        assert_eq!(scene.material_repository.get(hit.material), Some(&paint));
+        let hit = scene.objects.hit(ray!((3, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        let globe = scene.material_repository.get::<Principled>(hit.material).unwrap();
+        assert!(matches!((globe.base_color, globe.metallic, globe.roughness), (Parameter::Texture(a), Parameter::Value(1.), Parameter::Texture(b)) if a == b));
+
+        let error = |source: String| match SceneFile::parse(source, Path::new("scenes/test.toml")).and_then(|f| f.build(&Film::new((4, 3)), &mut BvhBuilder::default())) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(error(source.replace("metallic = 1", "metallic = 1, metallic_texture = \"earth\"")), "scenes/test.toml:10: material 'globe' needs at most one of metallic and metallic_texture");
+        assert_eq!(error(source.replace("clearcoat = 1", "clearcoat = 2")), "scenes/test.toml:9: expected a number between 0 and 1, found 2");
+        assert_eq!(error(source.replace("roughness_texture = \"earth\"", "roughness_texture = \"moon\"")), "scenes/test.toml:10: undefined texture 'moon'");
+    }
+
+    #[test]
+    fn test_scene_file_instances() {
+        let dir = std::env::temp_dir().join(format!("rust-tracer-instances-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("square.obj"), "v -1 -1 0\nv 1 -1 0\nv 1 1 0\nv -1 1 0\nf 1 2 3 4\n").unwrap();
+        let source = format!("{CAMERA}[materials]\nred = {{ type = \"lambertian\", color = [1, 0, 0] }}\n\n\
+            [meshes]\nsquare = {{ type = \"obj\", path = \"square.obj\" }}\n\n\
+            [[objects]]\ntype = \"instance\"\nmesh = \"square\"\noffset = [0, 0, -2]\n\n\
+            [[objects]]\ntype = \"instance\"\nmesh = \"square\"\noffset = [5, 0, -2]\nmaterial = \"red\"\n\n\
+            [[objects]]\ntype = \"instance\"\nmesh = \"square\"\noffset = [0, 5, -2]\nrotate = [0, 90, 0]\nscale = [2, 1, 1]\n\n\
+            [[objects]]\ntype = \"instance\"\nmesh = \"square\"\nkeys = [{{ time = 0, offset = [0, -5, -2] }}, {{ time = 1, offset = [2, -5, -2], scale = [1, 1, 1] }}]\n");
+        let file = SceneFile::parse(source.clone(), &dir.join("test.toml")).unwrap();
+        let scene = file.build(&Film::new((4, 3)), &mut BvhBuilder::default()).unwrap();
+
+        let hit = scene.objects.hit(ray!((0.5, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 2.);
+        assert_eq!(scene.material_repository.get(hit.material), Some(&Principled::default()));
+        let hit = scene.objects.hit(ray!((5.5, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).unwrap();
+        assert_eq!(scene.material_repository.get(hit.material), Some(&lambertian((1., 0., 0.))));
+        assert!(scene.objects.hit(ray!((2.5, 0, 0) -> (0, 0, -1)), 0.001, Float::INFINITY).is_none());
+        // turned to face along x and stretched to four units deep
+        let hit = scene.objects.hit(ray!((-5, 5, -3.5) -> (1, 0, 0)), 0.001, Float::INFINITY).unwrap();
+        assert_ulps_eq!(hit.t, 5.);
+        assert_ulps_eq!(hit.normal.x.abs(), 1.);
+        // moving right over the shutter
+        assert!(scene.objects.hit(ray(vec3!(1.5, -5, 0), vec3!(0, 0, -1), 0.), 0.001, Float::INFINITY).is_none());
+        assert!(scene.objects.hit(ray(vec3!(1.5, -5, 0), vec3!(0, 0, -1), 0.5), 0.001, Float::INFINITY).is_some());
+
+        let error = SceneFile::parse(source.replace("mesh = \"square\"\noffset = [5", "mesh = \"circle\"\noffset = [5"), &dir.join("test.toml"))
+            .and_then(|f| f.build(&Film::new((4, 3)), &mut BvhBuilder::default())).map(|_| ()).unwrap_err();
+        assert!(error.to_string().ends_with(":18: undefined mesh 'circle'"), "{error}");
+        let error = SceneFile::parse(source.replace("scale = [2, 1, 1]", "scale = [2, 0, 1]"), &dir.join("test.toml"))
+            .and_then(|f| f.build(&Film::new((4, 3)), &mut BvhBuilder::default())).map(|_| ()).unwrap_err();
+        assert!(error.to_string().ends_with(":27: scale factors must not be zero"), "{error}");
+        let error = SceneFile::parse(source.replace("scale = [1, 1, 1]", "scale = [1, 0, 1]"), &dir.join("test.toml")).map(|_| ()).unwrap_err();
+        assert!(error.to_string().ends_with(":32: expected positive numbers, found [1.0, 0.0, 1.0]"), "{error}");
+
+        std::fs::write(dir.join("empty.obj"), "v -1 -1 0\nv 1 -1 0\n").unwrap();
+        let error = SceneFile::parse(source.replace("path = \"square.obj\"", "path = \"empty.obj\""), &dir.join("test.toml"))
+            .and_then(|f| f.build(&Film::new((4, 3)), &mut BvhBuilder::default())).map(|_| ()).unwrap_err();
+        assert!(error.to_string().ends_with(": mesh 'square' has no triangles"), "{error}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scene_file_animation() {
+        let file = SceneFile::load("scenes/orbit.toml").unwrap();
+        assert_eq!(file.timeline().unwrap(), Some(Timeline { frames: 24, start: 0., end: 1., shutter: 0.5 }));
+        assert_eq!(SceneFile::load("scenes/simple.toml").unwrap().timeline().unwrap(), None);
+
+        // a quarter turn about the middle sphere puts the camera on its left
+        let mut scene = file.build(&Film::new((4, 4)), &mut BvhBuilder::default()).unwrap();
+        scene.cam.set_shutter((1., 1.).into());
+        let r = scene.cam.ray((2., 2.)).unwrap();
+        assert!((r.origin - vec3!(4, 0.5, -1)).length() < 1e-9, "{:?}", r.origin);
+        assert!((r.direction.normalize() - vec3!(-4, -0.5, 0).normalize()).length() < 1e-9, "{:?}", r.direction);
+
+        // the path leaves out from at the first key and to everywhere, which come from the camera table.
+        // Halfway along, rays through the middle of the image cross in the focal point whatever their origin on the lens
+        let mut scene = SceneFile::load("scenes/rack_focus.toml").unwrap().build(&Film::new((4, 4)), &mut BvhBuilder::default()).unwrap();
+        let from = vec3!(1, 0.5, 3.25);
+        let focus = from + 6.75 * (vec3!(0, 0, -4) - from).normalize();
+        scene.cam.set_shutter((0.5, 0.5).into());
+        for _ in 0..10 {
+            let r = scene.cam.ray((2., 2.)).unwrap();
+            assert!((r.at(dot(focus - r.origin, r.direction) / r.direction.length_squared()) - focus).length() < 1e-9, "{r:?}");
+        }
+
+        assert_eq!(error(&format!("{CAMERA}keys = [{{ time = 0 }}]\npath = [{{ time = 1 }}]\n")), "test.toml:6: camera takes either keys or a path");
+        assert_eq!(error(&format!("{CAMERA}[animation]\nframes = 0\n")), "test.toml:6: expected a positive number, found 0");
+        assert_eq!(error(&format!("{CAMERA}[animation]\nframes = 2\nshutter = 1.5\n")), "test.toml:7: expected a number between 0 and 1, found 1.5");
+        let file = SceneFile::parse(format!("{CAMERA}[animation]\nframes = 2\nstart = 2\nend = 1\n"), Path::new("test.toml")).unwrap();
+        assert_eq!(file.timeline().unwrap_err().to_string(), "test.toml: animation must not end before it starts");
+    }
+
+    #[test]
+    fn test_scene_file_projections() {
+        let camera = |projection: &str| format!("[camera]\nfrom = [0, 0, 0]\nto = [0, 0, -1]\nprojection = \"{projection}\"\n");
+        let build = |source: String| SceneFile::parse(source, Path::new("test.toml")).unwrap().build(&Film::new((4, 2)), &mut BvhBuilder::default()).unwrap();
+
+        let scene = build(camera("orthographic") + "view_height = 2\n");
+        assert_eq!(scene.cam.ray((0., 0.)).unwrap().origin, vec3!(-2, 1, 0));
+        let scene = build(camera("fisheye") + "vfov = 180\n");
+        assert!(scene.cam.ray((0., 0.)).is_none());
+        assert_ulps_eq!(scene.cam.ray((2., 0.)).unwrap().direction.y, 1.);
+        let scene = build(camera("equirectangular"));
+        assert_ulps_eq!(scene.cam.ray((0., 1.)).unwrap().direction.z, 1.);
+        let lens = camera("lens") + "lens = { file = \"res/lenses/dgauss50.dat\" }\n";
+        let r = build(lens.clone()).cam.ray((2., 1.)).unwrap();
+        assert!(r.direction.z < -0.99 && r.origin.z < -0.05, "{r:?}");
+
+        assert_eq!(error(&camera("orthographic")), "test.toml: orthographic camera needs a view_height");
+        assert_eq!(error(&camera("perspective")), "test.toml: camera needs a vfov");
+        assert_eq!(error(&(camera("fisheye") + "vfov = 400\n")), "test.toml:5: expected a field of view between 0 and 360 degrees, found 400");
+        assert_eq!(error(&(camera("equirectangular") + "path = [{ time = 1 }]\n")), "test.toml:5: camera path needs the perspective projection");
+        assert_eq!(error(&camera("lens")), "test.toml: lens projection needs a lens");
+        assert_eq!(error(&(camera("fisheye") + "vfov = 180\nlens = { file = \"lens.dat\" }\n")), "test.toml: camera lens needs the lens projection");
+        assert!(error(&(camera("lens") + "lens = { file = \"missing.dat\" }\n")).starts_with("test.toml:5: cannot load lens: missing.dat: cannot read file"));
+        assert_eq!(error(&lens.replace(" }", ", stop_diameter = 20 }")), "test.toml:5: expected a stop_diameter of at most 17.1 for this lens, found 20");
+        assert_eq!(error(&(lens + "focus_distance = 0.01\n")), "test.toml:5: lens can't focus 0.01 away");
+        assert_eq!(error(&camera("spherical")), "test.toml:4: unknown variant `spherical`, expected one of `perspective`, `orthographic`, `fisheye`, `equirectangular`, `lens`");
+    }
+
+    #[test]
+    fn test_scene_file_exposure() {
+        let build = |source: String| SceneFile::parse(source, Path::new("test.toml")).unwrap().build(&Film::new((4, 2)), &mut BvhBuilder::default()).unwrap();
+
+        let scene = build(format!("{CAMERA}f_stop = 2\nshutter_speed = 0.01\niso = 400\n"));
+        assert_eq!(scene.exposure, Metering::Manual(Exposure { f_stop: 2., shutter_speed: 0.01, iso: 400. }));
+        // a 12mm lens at f/2 focused 1m away
+        let r = scene.cam.ray((2., 1.)).unwrap();
+        assert!(r.origin.length() > 0. && r.origin.length() <= 0.003 + 1e-9, "{r:?}");
+        assert_eq!(build(format!("{CAMERA}f_stop = 2\nshutter_speed = 0.01\n")).exposure, Metering::Manual(Exposure { f_stop: 2., shutter_speed: 0.01, iso: 100. }));
+        assert_eq!(build(format!("{CAMERA}auto_exposure = true\nexposure_compensation = -1\n")).exposure, Metering::Auto { compensation: -1. });
+        assert_eq!(build(CAMERA.to_string()).exposure, Metering::None);
+
+        assert_eq!(error(&format!("{CAMERA}f_stop = 2\ndefocus_angle = 1\n")), "test.toml: camera takes either an f_stop or a defocus_angle");
+        assert_eq!(error(&format!("{CAMERA}shutter_speed = 0.01\n")), "test.toml: camera shutter_speed needs an f_stop");
+        assert_eq!(error(&format!("{CAMERA}f_stop = 2\nshutter_speed = 0.01\nauto_exposure = true\n")), "test.toml: camera takes either auto_exposure or a shutter_speed");
+        assert_eq!(error(&format!("{CAMERA}iso = 100\n")), "test.toml: camera iso needs a shutter_speed");
+        assert_eq!(error(&format!("{CAMERA}f_stop = 0\n")), "test.toml:5: expected a positive number, found 0");
+    }
+
+    #[test]
+    fn test_scene_file_aperture() {
+        let dir = std::env::temp_dir().join(format!("rust-tracer-aperture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut mask = image::RgbImage::new(2, 2);
+        mask.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        mask.save(dir.join("mask.png")).unwrap();
+        let build = |aperture: &str| SceneFile::parse(format!("{CAMERA}defocus_angle = 90\naperture = {aperture}\n"), &dir.join("test.toml"))
+            .and_then(|f| f.build(&Film::new((4, 2)), &mut BvhBuilder::default()));
+
+        // a lens as wide as it is far from the focal plane, cut down to a square
+        let scene = build("{ blades = 4, rotation = 45 }").unwrap();
+        for _ in 0..100 {
+            let r = scene.cam.ray((2., 1.)).unwrap();
+            assert!(r.origin.x.abs() <= (0.5 as Float).sqrt() + 1e-9 && r.origin.y.abs() <= (0.5 as Float).sqrt() + 1e-9, "{r:?}");
+        }
+        let scene = build("{ mask = \"mask.png\" }").unwrap();
+        for _ in 0..100 {
+            let r = scene.cam.ray((2., 1.)).unwrap();
+            assert!(r.origin.x <= 0. && r.origin.y >= 0., "{r:?}");
+        }
+
+        let error = |aperture: &str| build(aperture).map(|_| ()).unwrap_err().to_string();
+        assert!(error("{ blades = 2 }").ends_with("test.toml:6: expected at least 3 blades, found 2"));
+        assert!(error("{ blades = 5, mask = \"mask.png\" }").ends_with("test.toml:6: aperture needs exactly one of blades and mask"));
+        assert!(error("{ mask = \"missing.png\" }").contains("test.toml:6: cannot load aperture mask"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scene_file_errors() {
+        let sphere = |center: &str, radius: &str| format!("{CAMERA}[materials]\nred = {{ type = \"lambertian\", color = [1, 0, 0] }}\n\n[[objects]]\ntype = \"sphere\"\ncenter = {center}\nradius = {radius}\nmaterial = \"red\"\n");
+
+        assert_eq!(error("[camera]\nfrom = [0, 0, 0]\nto = [0, 0, -1]\nfov = 90\n"), "test.toml:4: unknown field `fov`, expected one of `from`, `to`, `up`, `projection`, `vfov`, `view_height`, `focus_distance`, `defocus_angle`, `f_stop`, `aperture`, `lens`, `shutter_speed`, `iso`, `auto_exposure`, `exposure_compensation`, `shutter`, `keys`, `path`");
+        assert_eq!(error(&format!("{CAMERA}path = [{{ time = 1, to = [0, 0, 0] }}]\n")), "test.toml:5: camera 'from' and 'to' must differ at time 1");
+        assert_eq!(error("[camera]\nfrom = [0, 0, 0]\nto = [0, 0, 0]\nvfov = 90\n"), "test.toml:3: camera 'from' and 'to' must differ");
+        assert_eq!(error(&format!("{CAMERA}shutter = [1, 0]\n")), "test.toml:5: camera shutter must not close before it opens");
+        assert_eq!(error(&format!("{CAMERA}projection = \"fisheye\"\naperture = {{ blades = 5 }}\n")), "test.toml:6: camera aperture needs the perspective projection");
+        assert_eq!(error("[camera]\nfrom = [0, 0, 0]\nto = [0, 0, -1]\nvfov = 190\n"), "test.toml:4: expected a field of view between 0 and 180 degrees, found 190");
+        assert_eq!(error(&format!("{CAMERA}[render]\nwidth = 0\n")), "test.toml:6: expected a positive number, found 0");
+        assert_eq!(error(&format!("{CAMERA}[render]\nmin_samples = 0\n")), "test.toml:6: expected a positive number, found 0");
+        assert_eq!(error(&format!("{CAMERA}[render]\nsampler = \"stratified\"\n")), "test.toml:6: unknown variant `stratified`, expected `center` or `square`");
+        assert_eq!(error(&sphere("[0, 0]", "1")), "test.toml:10: invalid length 2, expected an array of length 3");
+        assert_eq!(error(&sphere("[0, 0, 0]", "-1")), "test.toml:11: expected a positive number, found -1");
+        assert_eq!(error(&sphere("[0, 0, 0]", "1").replace("radius", "raduis")), "test.toml:11: unknown field `raduis`, expected one of `center`, `radius`, `material`");
+        assert_eq!(error(&sphere("[0, 0, 0]", "1").replace("\"red\"\n", "\"blue\"\n")), "test.toml:12: undefined material 'blue'");
+        assert_eq!(error(&format!("{CAMERA}[materials]\nred = {{ type = \"plastic\" }}\n")), "test.toml:6: unknown variant `plastic`, expected one of `lambertian`, `metal`, `dielectric`, `emissive`, `principled`");
+        assert_eq!(error(&format!("{CAMERA}[materials]\nred = {{ color = [1, 0, 0], type = \"lambertian\" }}\n")), "test.toml:6: `type` must be the first key");
+        assert_eq!(error(&format!("{CAMERA}[materials]\nsteel = {{ type = \"metal\", color = [1, 1, 1], roughness = 2 }}\n")), "test.toml:6: expected a number between 0 and 1, found 2");
+        assert_eq!(error(&format!("{CAMERA}[materials]\nearth = {{ type = \"lambertian\", texture = \"earth\" }}\n")), "test.toml:6: undefined texture 'earth'");
+        assert_eq!(error(&format!("{CAMERA}[[objects]]\ntype = \"ply\"\npath = \"missing.ply\"\n")), "missing.ply: cannot read file: No such file or directory (os error 2)");
+    }
+}
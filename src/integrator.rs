@@ -5,12 +5,16 @@
 use std::sync::{Arc, Mutex};
 use std::thread::Scope;
 
-use crate::random::random_float;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::random::{random_float, random_floats, reseed_thread};
 use crate::{
-    color::color_rgb, config::{Color, Film, Float}, film::SampleCollector, material::Scatter, png::Png, ray::Ray, sampler::PixelSample, scene::Scene, util::is_power_of_2, window::MinifbWindow,
+    color::color_rgb, config::{Color, Film, Float}, film::SampleCollector, hit::HitRecord, png::Png, ray::{Ray, ray}, sampler::PixelSample, scene::Scene,
+    util::is_power_of_2, window::MinifbWindow,
 };
 
-pub trait RayEvaluator: Default {
+pub trait RayEvaluator {
     fn li(&self, scene: &Scene, r: Ray, max_bounces: usize) -> Color;
 }
 
@@ -18,30 +22,137 @@ pub trait RayEvaluator: Default {
 pub struct SimpleRayEvaluator;
 impl RayEvaluator for SimpleRayEvaluator {
     fn li(&self, scene: &Scene, r: Ray, max_bounces: usize) -> Color {
+        let mut radiance = color_rgb(0., 0., 0.);
         let mut attenuation = color_rgb(1., 1., 1.);
         let mut r = r;
         for bounce in 0..max_bounces {
             match scene.objects.hit(r, 0.001, Float::INFINITY) {
-                Some(hit_record) => match hit_record.material.scatter(scene, r, hit_record.pos, hit_record.normal, hit_record.uv) {
-                    Some(scatter_record) => {
-                        attenuation *= scatter_record.attenuation;
-
-                        if bounce >= 3 {
-                            let survival = attenuation.r.max(attenuation.g).max(attenuation.b).min(0.95);
-                            if random_float() > survival {
-                                return color_rgb(0., 0., 0.);
+                Some(hit_record) => {
+                    let material = scene.material_repository.material(hit_record.material);
+                    radiance += attenuation * material.emitted(scene, hit_record.pos, hit_record.uv.coords());
+
+                    match material.sample(scene, &hit_record, -r.direction, random_floats()) {
+                        Some(sample) => {
+                            attenuation *= sample.weight;
+
+                            if bounce >= 3 {
+                                let survival = attenuation.r.max(attenuation.g).max(attenuation.b).min(0.95);
+                                if random_float() > survival {
+                                    return radiance;
+                                }
+                                attenuation /= survival;
                             }
-                            attenuation = attenuation / survival;
-                        }
 
-                        r = scatter_record.out;
-                    },
-                    None => return color_rgb(0., 0., 0.)
+                            r = ray(hit_record.pos, sample.wi, r.time);
+                        },
+                        None => return radiance
+                    }
                 },
-                None => return attenuation * (scene.background_color)(r),
+                None => return radiance + attenuation * (scene.background_color)(r),
             }
         }
-        color_rgb(0., 0., 0.)
+        radiance
+    }
+}
+
+// Path tracer that also samples a random light from Scene::lights at every diffuse bounce (next-event estimation).
+// Emission found by shadow rays and by scattered rays is combined using multiple importance sampling with the
+// power heuristic, so small lights converge quickly without making large ones noisier. Delta lobes can't be
+// evaluated (see Scatter::eval) and don't get shadow rays, emission found after them is counted in full.
+#[derive(Clone, Copy, Default)]
+pub struct NeeRayEvaluator;
+
+fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+}
+
+impl NeeRayEvaluator {
+    // pdf of light sampling finding the emitter r hit at distance t: choosing that light uniformly and sampling r's
+    // direction on it. Other lights along r are hidden behind it, so sampling them can't produce this path
+    fn light_pdf(scene: &Scene, r: Ray, t: Float) -> Float {
+        let light = scene.lights.iter().find(|l| l.hit_distance(r).is_some_and(|light_t| (light_t - t).abs() <= 1e-9 * t.max(1.)));
+        light.map_or(0., |l| l.pdf(r.origin, r.direction)) / scene.lights.len() as Float
+    }
+
+    fn sample_light(scene: &Scene, hit_record: &HitRecord, r: Ray) -> Color {
+        let black = color_rgb(0., 0., 0.);
+        let material = scene.material_repository.material(hit_record.material);
+        if material.is_delta() { return black; }
+        let light = &scene.lights[((random_float() * scene.lights.len() as Float) as usize).min(scene.lights.len() - 1)];
+        let Some(sample) = light.sample(scene, hit_record.pos) else { return black };
+        let bsdf_pdf = material.pdf(scene, hit_record, -r.direction, sample.wi);
+        if bsdf_pdf <= 0. { return black; }
+        let f_cos = material.eval(scene, hit_record, -r.direction, sample.wi);
+
+        let shadow_ray = ray(hit_record.pos, sample.wi, r.time);
+        if scene.objects.occluded(shadow_ray, 0.001, sample.dist - 0.001) { return black; }
+
+        let light_pdf = sample.pdf / scene.lights.len() as Float;
+        power_heuristic(light_pdf, bsdf_pdf) / light_pdf * f_cos * sample.radiance
+    }
+}
+
+impl RayEvaluator for NeeRayEvaluator {
+    fn li(&self, scene: &Scene, r: Ray, max_bounces: usize) -> Color {
+        let mut radiance = color_rgb(0., 0., 0.);
+        let mut attenuation = color_rgb(1., 1., 1.);
+        let mut r = r;
+        // pdf of the last scattered direction, None for camera rays and specular bounces
+        let mut bsdf_pdf: Option<Float> = None;
+
+        for bounce in 0..max_bounces {
+            let Some(hit_record) = scene.objects.hit(r, 0.001, Float::INFINITY) else {
+                return radiance + attenuation * (scene.background_color)(r);
+            };
+
+            let material = scene.material_repository.material(hit_record.material);
+            if material.is_emissive() {
+                let weight = match bsdf_pdf {
+                    Some(pdf) if !scene.lights.is_empty() => power_heuristic(pdf, NeeRayEvaluator::light_pdf(scene, r, hit_record.t)),
+                    _ => 1.,
+                };
+                radiance += weight * attenuation * material.emitted(scene, hit_record.pos, hit_record.uv.coords());
+            }
+
+            if !scene.lights.is_empty() {
+                radiance += attenuation * NeeRayEvaluator::sample_light(scene, &hit_record, r);
+            }
+
+            let Some(sample) = material.sample(scene, &hit_record, -r.direction, random_floats()) else {
+                return radiance;
+            };
+            attenuation *= sample.weight;
+            bsdf_pdf = (!sample.delta).then_some(sample.pdf);
+
+            if bounce >= 3 {
+                let survival = attenuation.r.max(attenuation.g).max(attenuation.b).min(0.95);
+                if random_float() > survival {
+                    return radiance;
+                }
+                attenuation /= survival;
+            }
+
+            r = ray(hit_record.pos, sample.wi, r.time);
+        }
+        radiance
+    }
+}
+
+// Evaluator picked at runtime, e.g. from the command line or a scene file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EvaluatorKind {
+    #[default]
+    Simple,
+    Nee,
+}
+
+impl RayEvaluator for EvaluatorKind {
+    fn li(&self, scene: &Scene, r: Ray, max_bounces: usize) -> Color {
+        match self {
+            EvaluatorKind::Simple => SimpleRayEvaluator.li(scene, r, max_bounces),
+            EvaluatorKind::Nee => NeeRayEvaluator.li(scene, r, max_bounces),
+        }
     }
 }
 
@@ -59,45 +170,91 @@ fn print_progress(prog: Float) {
     stdout().flush().unwrap();
 }
 
+
 // Integrator takes a scene and renders it onto a film, following sample size and variance targets
 // We iterate over pixels, generating subpixel samples using a Sampler. Scene contains a Camera that maps these
 // film-space samples to world-space rays. A RayEvaluator then computes the radiance contribution for each ray.
 
-pub trait Integrate<const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> {
-    fn integrate(scene: &Scene, film: &mut Film, variance_target: Float);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntegratorSettings {
+    pub tile_size: (usize, usize),
+    pub workers: usize,
+    pub min_samples: usize,
+    pub max_samples: usize,
+    pub variance_target: Float,
+    pub max_bounces: usize,
+    // show the mean and variance of the film in windows while rendering
+    pub preview: bool,
+}
+
+impl Default for IntegratorSettings {
+    fn default() -> Self {
+        IntegratorSettings { tile_size: (50, 50), workers: 8, min_samples: 32, max_samples: 64, variance_target: 0.004, max_bounces: 64, preview: true }
+    }
 }
 
-pub struct SimpleIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> {
-    _sampler: std::marker::PhantomData<Sampler>,
-    _evaluator: std::marker::PhantomData<Evaluator>,
+pub trait Integrate {
+    fn integrate(&self, scene: &Scene, film: &mut Film);
 }
 
-impl<Sampler: PixelSample, Evaluator: RayEvaluator, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> Integrate<MIN_SAMPLES, MAX_SAMPLES> for SimpleIntegrator<Sampler, Evaluator, MIN_SAMPLES, MAX_SAMPLES> {
-    // TODO might be neater to factor out the window updates and progress printing
-    fn integrate(scene: &Scene, film: &mut Film, variance_target: Float) {
-        let mut win = MinifbWindow::new(film.width, film.height);
-        let mut win2 = MinifbWindow::new(film.width, film.height);
-        let mut win3 = MinifbWindow::new(film.width, film.height);
+struct PreviewWindows {
+    mean: MinifbWindow,
+    variance: MinifbWindow,
+    avg_variance: MinifbWindow,
+}
+
+impl PreviewWindows {
+    fn new(width: usize, height: usize) -> Self {
+        PreviewWindows { mean: MinifbWindow::new(width, height), variance: MinifbWindow::new(width, height), avg_variance: MinifbWindow::new(width, height) }
+    }
+
+    fn positioned(width: usize, height: usize) -> Self {
+        PreviewWindows {
+            mean: MinifbWindow::positioned(width, height, 0, 0),
+            variance: MinifbWindow::positioned(width, height, width as isize, 0),
+            avg_variance: MinifbWindow::positioned(width, height, width as isize, height as isize),
+        }
+    }
+
+    fn update(&mut self, film: &Film) {
+        self.mean.update(film, SampleCollector::gamma_corrected_mean);
+        self.variance.update(film, SampleCollector::variance);
+        self.avg_variance.update(film, SampleCollector::avg_variance);
+    }
+}
+
+pub struct SimpleIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator> {
+    settings: IntegratorSettings,
+    sampler: Sampler,
+    evaluator: Evaluator,
+}
+
+impl<Sampler: PixelSample, Evaluator: RayEvaluator> SimpleIntegrator<Sampler, Evaluator> {
+    pub fn new(settings: IntegratorSettings, sampler: Sampler, evaluator: Evaluator) -> Self {
+        SimpleIntegrator { settings, sampler, evaluator }
+    }
+}
+
+impl<Sampler: PixelSample, Evaluator: RayEvaluator> Integrate for SimpleIntegrator<Sampler, Evaluator> {
+    fn integrate(&self, scene: &Scene, film: &mut Film) {
+        let IntegratorSettings { min_samples, max_samples, variance_target, max_bounces, .. } = self.settings;
+        let mut preview = self.settings.preview.then(|| PreviewWindows::new(film.width, film.height));
 
         let mut sample_count = 0;
-        let sampler = Sampler::default();
-        let evaluator = Evaluator::default();
 
-        for n in 0..MAX_SAMPLES {
-            win.update(film, SampleCollector::gamma_corrected_mean);
-            win2.update(film, SampleCollector::variance);
-            win3.update(film, SampleCollector::avg_variance);
+        for n in 0..max_samples {
+            if let Some(preview) = preview.as_mut() { preview.update(film); }
 
             for x in 0..film.width {
                 for y in 0..film.height {
-                    if n < MIN_SAMPLES || film.sample_collector((x, y)).max_variance() > variance_target {
+                    if n < min_samples || film.sample_collector((x, y)).max_variance() > variance_target {
                         sample_count += 1;
-                        let (s, t) = sampler.pixel_sample((x, y));
-                        let r = scene.cam.ray((s, t));
-                        film.add_sample((x, y), evaluator.li(scene, r, 64));
+                        let (s, t) = self.sampler.pixel_sample((x, y));
+                        let color = scene.cam.weighted_ray((s, t)).map_or(color_rgb(0., 0., 0.), |(r, weight)| weight * self.evaluator.li(scene, r, max_bounces));
+                        film.add_sample((x, y), color);
                     }
                 }
-                print_progress((n * film.width * film.height + x * film.height) as Float / (film.width * film.height * MAX_SAMPLES) as Float);
+                print_progress((n * film.width * film.height + x * film.height) as Float / (film.width * film.height * max_samples) as Float);
             }
 
             if (n == 0) || is_power_of_2(n) {
@@ -108,62 +265,64 @@ fn integrate(scene: &Scene, film: &mut Film, variance_target: Float) {
         }
 
         println!();
-        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (film.width * film.height * MAX_SAMPLES) as Float);
+        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (film.width * film.height * max_samples) as Float);
     }
 }
 
-pub struct SingleCoreTiledIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> {
-    _sampler: std::marker::PhantomData<Sampler>,
-    _evaluator: std::marker::PhantomData<Evaluator>,
+pub struct SingleCoreTiledIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator> {
+    settings: IntegratorSettings,
+    sampler: Sampler,
+    evaluator: Evaluator,
 }
 
-impl<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> SingleCoreTiledIntegrator<Sampler, Evaluator, TILE_WIDTH, TILE_HEIGHT, MIN_SAMPLES, MAX_SAMPLES> {
-    fn integrate_tile(scene: &Scene, film: &mut Film, tile_x: usize, tile_y: usize, variance_target: Float) -> usize {
+impl<Sampler: PixelSample, Evaluator: RayEvaluator> SingleCoreTiledIntegrator<Sampler, Evaluator> {
+    pub fn new(settings: IntegratorSettings, sampler: Sampler, evaluator: Evaluator) -> Self {
+        SingleCoreTiledIntegrator { settings, sampler, evaluator }
+    }
+
+    fn integrate_tile(&self, scene: &Scene, film: &mut Film, tile_x: usize, tile_y: usize) -> usize {
+        let IntegratorSettings { min_samples, max_samples, variance_target, max_bounces, .. } = self.settings;
         let mut sample_count = 0;
-        let sampler = Sampler::default();
-        let evaluator = Evaluator::default();
         for y in 0..film.height {
             for x in 0..film.width {
-                for n in 0..MAX_SAMPLES {
-                    if n >= MIN_SAMPLES && film.sample_collector((x, y)).max_variance() <= variance_target { break; }
+                for n in 0..max_samples {
+                    if n >= min_samples && film.sample_collector((x, y)).max_variance() <= variance_target { break; }
 
                     sample_count += 1;
-                    let (s, t) = sampler.pixel_sample((x + tile_x * film.width, y + tile_y * film.height));
-                    let r = scene.cam.ray((s, t));
-                    film.add_sample((x, y), evaluator.li(scene, r, 64));
+                    let (s, t) = self.sampler.pixel_sample((x + tile_x * film.width, y + tile_y * film.height));
+                    let color = scene.cam.weighted_ray((s, t)).map_or(color_rgb(0., 0., 0.), |(r, weight)| weight * self.evaluator.li(scene, r, max_bounces));
+                    film.add_sample((x, y), color);
                 }
             }
         }
         sample_count
     }
 }
-impl<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> Integrate<MIN_SAMPLES, MAX_SAMPLES> for SingleCoreTiledIntegrator<Sampler, Evaluator, TILE_WIDTH, TILE_HEIGHT, MIN_SAMPLES, MAX_SAMPLES> {
-    fn integrate(scene: &Scene, film: &mut Film, variance_target: Float) {
-        let mut win = MinifbWindow::new(film.width, film.height);
-        let mut win2 = MinifbWindow::new(film.width, film.height);
-        let mut win3 = MinifbWindow::new(film.width, film.height);
 
-        let tiles_hor = film.width / TILE_WIDTH + 1.min(film.width % TILE_WIDTH);
-        let tiles_ver = film.height / TILE_HEIGHT + 1.min(film.height % TILE_HEIGHT);
+impl<Sampler: PixelSample, Evaluator: RayEvaluator> Integrate for SingleCoreTiledIntegrator<Sampler, Evaluator> {
+    fn integrate(&self, scene: &Scene, film: &mut Film) {
+        let (tile_width, tile_height) = self.settings.tile_size;
+        let mut preview = self.settings.preview.then(|| PreviewWindows::new(film.width, film.height));
+
+        let tiles_hor = film.width / tile_width + 1.min(film.width % tile_width);
+        let tiles_ver = film.height / tile_height + 1.min(film.height % tile_height);
 
         let mut sample_count = 0;
 
         for x in 0..tiles_hor {
             for y in 0..tiles_ver {
-                let mut tile_film = Film::new((TILE_WIDTH, TILE_HEIGHT));
-                sample_count += Self::integrate_tile(scene, &mut tile_film, x, y, variance_target);
-                film.overwrite_with((x * TILE_WIDTH, y * TILE_HEIGHT), &tile_film);
+                let mut tile_film = Film::new((tile_width, tile_height));
+                sample_count += self.integrate_tile(scene, &mut tile_film, x, y);
+                film.overwrite_with((x * tile_width, y * tile_height), &tile_film);
 
-                win.update(film, SampleCollector::gamma_corrected_mean);
-                win2.update(film, SampleCollector::variance);
-                win3.update(film, SampleCollector::avg_variance);
+                if let Some(preview) = preview.as_mut() { preview.update(film); }
 
                 print_progress((y + x * tiles_ver) as Float / (tiles_hor * tiles_ver) as Float);
             }
         }
 
         println!();
-        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (film.width * film.height * MAX_SAMPLES) as Float);
+        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (film.width * film.height * self.settings.max_samples) as Float);
     }
 }
 
@@ -221,57 +380,65 @@ fn next(&mut self) -> Option<Self::Item> {
     }
 }
 
-pub struct MultiCoreTiledIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const WORKER_COUNT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> {
-    _sampler: std::marker::PhantomData<Sampler>,
-    _evaluator: std::marker::PhantomData<Evaluator>,
+pub struct MultiCoreTiledIntegrator<Sampler: PixelSample, Evaluator: RayEvaluator> {
+    settings: IntegratorSettings,
+    sampler: Sampler,
+    evaluator: Evaluator,
 }
 
-impl<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const WORKER_COUNT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> MultiCoreTiledIntegrator<Sampler, Evaluator, TILE_WIDTH, TILE_HEIGHT, WORKER_COUNT, MIN_SAMPLES, MAX_SAMPLES> {
-    pub fn integrate_inner(scene: &Scene, film: &Mutex<Film>, variance_target: Float) {
+impl<Sampler: PixelSample + Sync, Evaluator: RayEvaluator + Sync> MultiCoreTiledIntegrator<Sampler, Evaluator> {
+    pub fn new(settings: IntegratorSettings, sampler: Sampler, evaluator: Evaluator) -> Self {
+        MultiCoreTiledIntegrator { settings, sampler, evaluator }
+    }
+
+    pub fn integrate_inner(&self, scene: &Scene, film: &Mutex<Film>) {
         let width = film.lock().unwrap().width;
         let height = film.lock().unwrap().height;
 
-        let queue = Self::queue_jobs(scene, film, (width, height), variance_target);
+        let queue = self.queue_jobs(scene, film, (width, height));
 
         let sample_count = Mutex::new(0);
         std::thread::scope(|scope| {
-            Self::spawn_workers(scope, &queue, &sample_count);
+            self.spawn_workers(scope, &queue, &sample_count);
 
             #[cfg(not(feature = "bench"))]
-            Self::spawn_progress_thread(scope, &queue, film, &sample_count, (width, height));
+            self.spawn_progress_thread(scope, &queue, film, &sample_count, (width, height));
         });
 
         let sample_count = *sample_count.lock().unwrap();
 
         println!();
-        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (width * height * MAX_SAMPLES) as Float);
+        println!("{} samples collected, {:.2}%", sample_count, sample_count as Float * 100. / (width * height * self.settings.max_samples) as Float);
     }
 
-    fn queue_jobs<'scene>(scene: &'scene Scene, film: &'scene Mutex<Film>, (width, height): (usize, usize), variance_target: Float) -> JobQueue<'scene, impl FnOnce(&mut dyn Write) -> usize + Send + 'scene> {
-        let tiles_hor = width / TILE_WIDTH + 1.min(width % TILE_WIDTH);
-        let tiles_ver = height / TILE_HEIGHT + 1.min(height % TILE_HEIGHT);
+    fn queue_jobs<'scene>(&'scene self, scene: &'scene Scene, film: &'scene Mutex<Film>, (width, height): (usize, usize)) -> JobQueue<'scene, impl FnOnce(&mut dyn Write) -> usize + Send + 'scene> {
+        let (tile_width, tile_height) = self.settings.tile_size;
+        let tiles_hor = width / tile_width + 1.min(width % tile_width);
+        let tiles_ver = height / tile_height + 1.min(height % tile_height);
 
         JobQueue::new(CoordinateRange(0..tiles_hor, 0..tiles_ver).iter().map(|(x, y)| {
-            move |out: &mut dyn Write|
-                Self::render_tile(scene, film, (x * TILE_WIDTH, y * TILE_HEIGHT), (TILE_WIDTH, TILE_HEIGHT), variance_target, out)
+            move |out: &mut dyn Write| {
+                // every tile draws from its own random stream, so the image doesn't depend on which worker renders it
+                reseed_thread((y * tiles_hor + x) as u64);
+                self.render_tile(scene, film, (x * tile_width, y * tile_height), (tile_width, tile_height), out)
+            }
         }).collect())
     }
 
-    fn render_tile(scene: &Scene, film: &Mutex<Film>, (topleft_x, topleft_y): (usize, usize), (tile_width, tile_height): (usize, usize), variance_target: Float, _out: &mut dyn Write) -> usize {
+    fn render_tile(&self, scene: &Scene, film: &Mutex<Film>, (topleft_x, topleft_y): (usize, usize), (tile_width, tile_height): (usize, usize), _out: &mut dyn Write) -> usize {
+        let IntegratorSettings { min_samples, max_samples, variance_target, max_bounces, .. } = self.settings;
         let mut sample_count = 0;
         let mut local_film = Film::new((tile_width, tile_height));
-        let sampler = Sampler::default();
-        let evaluator = Evaluator::default();
 
         for y in 0..tile_height {
             for x in 0..tile_width {
-                for n in 0..MAX_SAMPLES {
-                    if n >= MIN_SAMPLES && local_film.sample_collector((x, y)).max_variance() <= variance_target { break; }
+                for n in 0..max_samples {
+                    if n >= min_samples && local_film.sample_collector((x, y)).max_variance() <= variance_target { break; }
 
                     sample_count += 1;
-                    let (s, t) = sampler.pixel_sample((topleft_x + x, topleft_y + y));
-                    let r = scene.cam.ray((s, t));
-                    local_film.add_sample((x, y), evaluator.li(scene, r, 64));
+                    let (s, t) = self.sampler.pixel_sample((topleft_x + x, topleft_y + y));
+                    let color = scene.cam.weighted_ray((s, t)).map_or(color_rgb(0., 0., 0.), |(r, weight)| weight * self.evaluator.li(scene, r, max_bounces));
+                    local_film.add_sample((x, y), color);
                 }
             }
         }
@@ -283,8 +450,8 @@ fn render_tile(scene: &Scene, film: &Mutex<Film>, (topleft_x, topleft_y): (usize
         sample_count
     }
 
-    fn spawn_workers<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, queue: &'scope JobQueue<impl FnOnce(&mut dyn Write) -> usize + Send + 'scope>, sample_count: &'scope Mutex<usize>) {
-        for i in 0..WORKER_COUNT {
+    fn spawn_workers<'scope, 'env>(&self, scope: &'scope Scope<'scope, 'env>, queue: &'scope JobQueue<impl FnOnce(&mut dyn Write) -> usize + Send + 'scope>, sample_count: &'scope Mutex<usize>) {
+        for i in 0..self.settings.workers {
             scope.spawn(move || {
                 #[cfg(not(feature = "bench"))]
                 let mut out = File::create(format!("out/worker-{i}.log")).unwrap();
@@ -303,14 +470,14 @@ fn spawn_workers<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, queue: &'scop
         };
     }
 
-    fn spawn_progress_thread<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, queue: &'scope JobQueue<impl FnOnce(&mut dyn Write) -> usize + Send + 'scope>, film: &'scope Mutex<Film>, sample_count: &'scope Mutex<usize>, (width, height): (usize, usize)) {
+    fn spawn_progress_thread<'scope, 'env>(&self, scope: &'scope Scope<'scope, 'env>, queue: &'scope JobQueue<impl FnOnce(&mut dyn Write) -> usize + Send + 'scope>, film: &'scope Mutex<Film>, sample_count: &'scope Mutex<usize>, (width, height): (usize, usize)) {
+        let total_samples = width * height * self.settings.max_samples;
+        let show_preview = self.settings.preview;
+
         scope.spawn(move || {
             // TODO it might be neater to move the window creation so that the time it takes is counted towards initialization instead of rendering
             // But since this doesn't run until after the workers are spawned, does it really affect the measured render time?
-            let mut win = MinifbWindow::positioned(width, height, 0, 0);
-            let mut win2 = MinifbWindow::positioned(width, height, width as isize, 0);
-            let mut win3 = MinifbWindow::positioned(width, height, width as isize, height as isize);
-            let total_samples = width * height * MAX_SAMPLES;
+            let mut preview = show_preview.then(|| PreviewWindows::positioned(width, height));
 
             while !queue.is_empty() {
                 {
@@ -318,11 +485,9 @@ fn spawn_progress_thread<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, queue
                     print_progress(progress);
                 }
 
-                {
+                if let Some(preview) = preview.as_mut() {
                     let film = film.lock().unwrap();
-                    win.update(&film, SampleCollector::gamma_corrected_mean);
-                    win2.update(&film, SampleCollector::variance);
-                    win3.update(&film, SampleCollector::avg_variance);
+                    preview.update(&film);
                 }
 
                 for _ in 0..10 {
@@ -334,13 +499,13 @@ fn spawn_progress_thread<'scope, 'env>(scope: &'scope Scope<'scope, 'env>, queue
     }
 }
 
-impl<Sampler: PixelSample, Evaluator: RayEvaluator, const TILE_WIDTH: usize, const TILE_HEIGHT: usize, const WORKER_COUNT: usize, const MIN_SAMPLES: usize, const MAX_SAMPLES: usize> Integrate<MIN_SAMPLES, MAX_SAMPLES> for MultiCoreTiledIntegrator<Sampler, Evaluator, TILE_WIDTH, TILE_HEIGHT, WORKER_COUNT, MIN_SAMPLES, MAX_SAMPLES> {
-    fn integrate(scene: &Scene, film: &mut Film, variance_target: Float) {
+impl<Sampler: PixelSample + Sync, Evaluator: RayEvaluator + Sync> Integrate for MultiCoreTiledIntegrator<Sampler, Evaluator> {
+    fn integrate(&self, scene: &Scene, film: &mut Film) {
         // the replace / into_inner is still a bit ugly, but it avoids cloning the film
 
         let film_mutex = Mutex::new(replace(film, Film::new((1, 1))));
 
-        Self::integrate_inner(scene, &film_mutex, variance_target);
+        self.integrate_inner(scene, &film_mutex);
 
         *film = film_mutex.into_inner().unwrap();
     }
@@ -357,4 +522,110 @@ fn test_coordinate_range() {
     assert_eq!(cr.next(), Some((1, 1)));
     assert_eq!(cr.next(), Some((1, 2)));
     assert_eq!(cr.next(), None);
-}
\ No newline at end of file
+}
+#[test]
+fn test_simple_evaluator_emission() {
+    use crate::{hit::sphere::sphere, material::{MaterialRepository, simple::emissive}};
+
+    let mut material_repository = MaterialRepository::new();
+    let scene = Scene {
+        objects: Box::new(vec![sphere((0., 0., -2.), 1., material_repository.add(emissive((4., 2., 1.))))]),
+        background_color: Box::new(|_| color_rgb(0.5, 0.5, 0.5)),
+        material_repository,
+        ..Scene::default()
+    };
+    assert_eq!(SimpleRayEvaluator.li(&scene, ray!((0, 0, 0) -> (0, 0, -1)), 8), color_rgb(4., 2., 1.));
+    assert_eq!(SimpleRayEvaluator.li(&scene, ray!((0, 0, 0) -> (0, 0, 1)), 8), color_rgb(0.5, 0.5, 0.5));
+    assert_eq!(SimpleRayEvaluator.li(&scene, ray!((0, 0, 0) -> (0, 0, -1)), 0), color_rgb(0., 0., 0.));
+}
+
+#[test]
+fn test_nee_evaluator_matches_simple() {
+    use crate::{hit::{bvh::{AxisAlignedBound, Bvh}, quad::quad, sphere::sphere}, light::Light, material::{MaterialRepository, simple::{emissive, lambertian}}};
+
+    let mut material_repository = MaterialRepository::new();
+    let light = quad((-1., 1., -1.), (2., 0., 0.), (0., 0., 2.), material_repository.add(emissive((2., 2., 2.))));
+    let small_light = sphere((0.5, 0.6, 0.5), 0.1, material_repository.add(emissive((8., 4., 2.))));
+    let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = vec![
+        Box::new(quad((-5., 0., -5.), (10., 0., 0.), (0., 0., 10.), material_repository.add(lambertian((0.5, 0.5, 0.5))))),
+        Box::new(light),
+        Box::new(small_light),
+    ];
+    let scene = Scene {
+        objects: Box::new(Bvh::from_slice(&mut objects)),
+        background_color: Box::new(|_| color_rgb(0., 0., 0.)),
+        lights: vec![Light::Quad(light), Light::Sphere(small_light)],
+        material_repository,
+        ..Scene::default()
+    };
+
+    let r = ray!((0, 0.5, 0) -> (0.1, -1, 0.2));
+    let estimate = |evaluator: &dyn RayEvaluator| {
+        let n = 100000;
+        (0..n).fold(color_rgb(0., 0., 0.), |sum, _| sum + evaluator.li(&scene, r, 3)) / n as Float
+    };
+    let simple = estimate(&SimpleRayEvaluator);
+    let nee = estimate(&NeeRayEvaluator);
+
+    approx::assert_relative_eq!(simple.r, nee.r, max_relative = 0.05);
+    approx::assert_relative_eq!(simple.b, nee.b, max_relative = 0.05);
+    assert!(nee.r > nee.b);
+}
+
+#[test]
+fn test_nee_evaluator_overlapping_lights() {
+    use crate::{hit::{bvh::{AxisAlignedBound, Bvh}, quad::quad}, light::Light, material::{MaterialRepository, simple::{emissive, lambertian}}};
+
+    // a small light right below a large one, hiding part of it from the floor
+    let mut material_repository = MaterialRepository::new();
+    let near = quad((-0.5, 1., -0.5), (1., 0., 0.), (0., 0., 1.), material_repository.add(emissive((4., 4., 4.))));
+    let far = quad((-1., 1.5, -1.), (2., 0., 0.), (0., 0., 2.), material_repository.add(emissive((1., 1., 1.))));
+    let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = vec![
+        Box::new(quad((-5., 0., -5.), (10., 0., 0.), (0., 0., 10.), material_repository.add(lambertian((0.5, 0.5, 0.5))))),
+        Box::new(near),
+        Box::new(far),
+    ];
+    let scene = Scene {
+        objects: Box::new(Bvh::from_slice(&mut objects)),
+        background_color: Box::new(|_| color_rgb(0., 0., 0.)),
+        lights: vec![Light::Quad(near), Light::Quad(far)],
+        material_repository,
+        ..Scene::default()
+    };
+
+    let r = ray!((0, 0.5, 0) -> (0.1, -1, 0.2));
+    let estimate = |evaluator: &dyn RayEvaluator| {
+        let n = 100000;
+        (0..n).fold(color_rgb(0., 0., 0.), |sum, _| sum + evaluator.li(&scene, r, 2)) / n as Float
+    };
+    approx::assert_relative_eq!(estimate(&NeeRayEvaluator).g, estimate(&SimpleRayEvaluator).g, max_relative = 0.03);
+}
+
+#[test]
+fn test_multi_core_reproducible() {
+    use crate::{camera::thin_lens::ThinLensCamera, hit::sphere::sphere, material::{MaterialRepository, simple::lambertian}, sampler::SamplerKind};
+
+    let size = (16, 8);
+    let mut material_repository = MaterialRepository::new();
+    let matte = material_repository.add(lambertian((0.5, 0.5, 0.5)));
+    let scene = Scene {
+        objects: Box::new(vec![sphere((0., 0., -2.), 1., matte), sphere((0., -101., -2.), 100., matte)]),
+        cam: Box::new(ThinLensCamera::new(&Film::new(size), vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 90., 1., 0., (0., 0.).into())),
+        material_repository,
+        ..Scene::default()
+    };
+
+    std::fs::create_dir_all("out/jobs").unwrap();
+    let render = |workers: usize| {
+        let settings = IntegratorSettings { tile_size: (4, 4), workers, min_samples: 4, max_samples: 4, preview: false, ..IntegratorSettings::default() };
+        let mut film = Film::new(size);
+        MultiCoreTiledIntegrator::new(settings, SamplerKind::Square, EvaluatorKind::Simple).integrate(&scene, &mut film);
+        film.pix.iter().map(SampleCollector::mean).collect::<Vec<Color>>()
+    };
+
+    // the same seed gives the same image, however the tiles end up spread over the workers
+    let image = render(4);
+    assert_eq!(render(4), image);
+    assert_eq!(render(1), image);
+    assert_ne!(image[0], image[1]);
+}
@@ -0,0 +1,170 @@
+use crate::{
+    config::{Color, Float, PI}, hit::{Hit, quad::Quad, sphere::Sphere}, material::MaterialHandle, random::random_float, ray::{Ray, ray}, scene::Scene,
+    vec3::{Point, Vec3, cross, dot, orthonormal_basis}
+};
+
+// Emitters that can be sampled directly, so a RayEvaluator can aim shadow rays at them instead of waiting for
+// scattered rays to hit them by chance. These are copies of the emitting objects in the scene, the objects
+// themselves still have to be added to Scene::objects to be visible.
+#[derive(Clone, Copy)]
+pub enum Light {
+    Quad(Quad),
+    Sphere(Sphere),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LightSample {
+    // unit direction from the shading point towards the light, and the distance along it
+    pub wi: Vec3,
+    pub dist: Float,
+    // solid angle pdf of wi
+    pub pdf: Float,
+    pub radiance: Color,
+}
+
+impl Light {
+    pub fn material(&self) -> MaterialHandle {
+        match self {
+            Light::Quad(q) => q.material,
+            Light::Sphere(s) => s.material,
+        }
+    }
+
+    pub fn sample(&self, scene: &Scene, origin: Point) -> Option<LightSample> {
+        match self {
+            Light::Quad(q) => {
+                let (s, t) = (random_float(), random_float());
+                let pos = q.origin + s * q.u + t * q.v;
+                let n = cross(q.u, q.v);
+                let area = n.length();
+
+                let to_light = pos - origin;
+                let dist = to_light.length();
+                let wi = to_light / dist;
+                let cos_light = dot(n, wi).abs() / area;
+                if cos_light < 1e-6 { return None; }
+
+                let radiance = scene.material_repository.material(q.material).emitted(scene, pos, (s, t));
+                Some(LightSample { wi, dist, pdf: dist * dist / (cos_light * area), radiance })
+            },
+            Light::Sphere(s) => {
+                // uniformly sample the cone of directions the sphere covers as seen from origin
+                let to_center = s.center - origin;
+                let dist_center = to_center.length();
+                if dist_center <= s.radius { return None; }
+
+                let one_minus_cos_max = sphere_one_minus_cos_max(s, dist_center);
+                let cos_theta = 1. - random_float() * one_minus_cos_max;
+                let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+                let phi = 2. * PI * random_float();
+
+                let w = to_center / dist_center;
+                let (u, v) = orthonormal_basis(w);
+                let wi = sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w;
+
+                // grazing directions can numerically miss the sphere
+                let hit = s.hit(ray(origin, wi, 0.), 0., Float::INFINITY)?;
+                let radiance = scene.material_repository.material(s.material).emitted(scene, hit.pos, hit.uv.coords());
+                Some(LightSample { wi, dist: hit.t, pdf: 1. / (2. * PI * one_minus_cos_max), radiance })
+            },
+        }
+    }
+
+    // distance along r to the light, like Hit::hit on the object the light was copied from
+    pub fn hit_distance(&self, r: Ray) -> Option<Float> {
+        match self {
+            Light::Quad(q) => q.hit(r, 0.001, Float::INFINITY),
+            Light::Sphere(s) => s.hit(r, 0.001, Float::INFINITY),
+        }.map(|hit| hit.t)
+    }
+
+    // solid angle pdf of sample picking direction, zero if the direction misses the light
+    pub fn pdf(&self, origin: Point, direction: Vec3) -> Float {
+        let wi = direction.normalize();
+        match self {
+            Light::Quad(q) => match q.hit(ray(origin, wi, 0.), 0.001, Float::INFINITY) {
+                Some(hit) => {
+                    let area = cross(q.u, q.v).length();
+                    hit.t * hit.t / (dot(hit.normal, wi).abs() * area)
+                },
+                None => 0.,
+            },
+            Light::Sphere(s) => {
+                let dist_center = (s.center - origin).length();
+                if dist_center <= s.radius || s.hit(ray(origin, wi, 0.), 0.001, Float::INFINITY).is_none() {
+                    return 0.;
+                }
+                1. / (2. * PI * sphere_one_minus_cos_max(s, dist_center))
+            },
+        }
+    }
+}
+
+fn sphere_one_minus_cos_max(s: &Sphere, dist_center: Float) -> Float {
+    let sin2_max = s.radius * s.radius / (dist_center * dist_center);
+    // 1 - sqrt(1 - x) loses all precision for far away lights, use its Taylor expansion instead
+    if sin2_max < 1e-4 { sin2_max / 2. + sin2_max * sin2_max / 8. } else { 1. - (1. - sin2_max).sqrt() }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use crate::{config::{Float, PI}, hit::{quad::quad, sphere::sphere}, light::Light, material::simple::emissive, random::random_float, scene::Scene, vec3::{Vec3, dot, vec3}};
+
+    // integrating 1 / pdf over the directions towards the light gives its solid angle, compare that against
+    // a uniform sphere estimate of the same solid angle
+    fn check_solid_angle(scene: &Scene, light: Light, origin: Vec3) {
+        let n = 20000;
+
+        let mut from_samples = 0.;
+        for _ in 0..n {
+            // grazing samples can miss the light, these are rare enough to not matter
+            let Some(sample) = light.sample(scene, origin) else { continue };
+            assert_relative_eq!(sample.pdf, light.pdf(origin, sample.wi), max_relative = 1e-6);
+            assert_eq!(sample.radiance, (2., 2., 2.).into());
+            from_samples += 1. / sample.pdf;
+        }
+        from_samples /= n as Float;
+
+        let mut hits = 0;
+        let n_directions = 10 * n;
+        for _ in 0..n_directions {
+            let z = 2. * random_float() - 1.;
+            let phi = 2. * PI * random_float();
+            let r = (1. - z * z).sqrt();
+            if light.pdf(origin, vec3(r * phi.cos(), r * phi.sin(), z)) > 0. { hits += 1; }
+        }
+        let from_directions = 4. * PI * hits as Float / n_directions as Float;
+
+        assert_relative_eq!(from_samples, from_directions, max_relative = 0.05);
+    }
+
+    #[test]
+    fn test_quad_light() {
+        let mut scene = Scene::default();
+        let light = Light::Quad(quad((-1., 2., -1.), (2., 0., 0.), (0., 0., 2.), scene.material_repository.add(emissive((2., 2., 2.)))));
+        check_solid_angle(&scene, light, vec3!(0.3, 0, 0.2));
+
+        let sample = light.sample(&scene, vec3!(0, 0, 0)).unwrap();
+        assert!(dot(sample.wi, vec3!(0, 1, 0)) > 0.);
+        assert!(sample.dist >= 2.);
+        assert_eq!(light.pdf(vec3!(0, 0, 0), vec3!(0, -1, 0)), 0.);
+    }
+
+    #[test]
+    fn test_sphere_light() {
+        let mut scene = Scene::default();
+        let glow = scene.material_repository.add(emissive((2., 2., 2.)));
+        let light = Light::Sphere(sphere((0., 3., 0.), 1., glow));
+        check_solid_angle(&scene, light, vec3!(0, 0, 0));
+
+        // inside the light there is nothing to sample
+        assert!(light.sample(&scene, vec3!(0, 3, 0)).is_none());
+        assert_eq!(light.pdf(vec3!(0, 0, 0), vec3!(1, 0, 0)), 0.);
+
+        // far away lights keep a sensible pdf
+        let light = Light::Sphere(sphere((0., 1e4, 0.), 1., glow));
+        assert_relative_eq!(light.pdf(vec3!(0, 0, 0), vec3!(0, 1, 0)), 1e8 / PI, max_relative = 1e-6);
+    }
+}
@@ -20,12 +20,27 @@
 mod window;
 mod conversion;
 mod texture;
+mod import;
+mod light;
+mod transform;
+mod animation;
+mod exposure;
+mod cli;
+#[cfg(feature = "bench")]
+mod bench;
+
+use std::{fs::create_dir_all, process::exit, time::Instant};
+#[cfg(not(feature = "bench"))]
+use std::{fs::rename, path::Path};
 
-use std::{fs::create_dir_all, time::Instant};
+use clap::Parser;
 
-use crate::{config::{Film, Float}, integrator::{Integrate, MultiCoreTiledIntegrator, SimpleRayEvaluator}, sampler::SquareSampler, scene::random_scene};
+use crate::{
+    animation::Timeline, cli::Args, config::{Film, Float}, exposure::Metering, hit::bvh::{BvhBuilder, BvhOptions}, import::{ImportError, scene::{RenderSettings, SceneFile}}, integrator::{Integrate, MultiCoreTiledIntegrator},
+    random::{mix, set_seed}, scene::{Scene, cornell_box_scene, forest_scene, ply_scene, random_scene, simple_scene}
+};
 #[cfg(not(feature = "bench"))]
-use crate::{film::SampleCollector, png::Png, ppm::Ppm};
+use crate::{cli::OutputFormat, conversion::color_gamma, exposure::metered_ev100, film::SampleCollector, png::Png, ppm::Ppm};
 
 fn variance_stats(film: &Film) {
     let mut vals: Vec<Float> = film.pix.iter().map(|sc| sc.avg_variance().r).collect();
@@ -39,35 +54,173 @@ fn variance_stats(film: &Film) {
     println!("max: {}", vals[vals.len() - 1]);
 }
 
+enum SceneSource {
+    Random,
+    Simple,
+    CornellBox,
+    Forest,
+    Ply(String),
+    File(Box<SceneFile>),
+}
+
+impl SceneSource {
+    fn from_arg(scene: &str) -> Result<Self, ImportError> {
+        Ok(match scene {
+            "random" => SceneSource::Random,
+            "simple" => SceneSource::Simple,
+            "cornell" => SceneSource::CornellBox,
+            "forest" => SceneSource::Forest,
+            path if path.to_lowercase().ends_with(".ply") => SceneSource::Ply(path.to_string()),
+            path => SceneSource::File(Box::new(SceneFile::load(path)?)),
+        })
+    }
+
+    fn render_settings(&self) -> RenderSettings {
+        match self {
+            SceneSource::File(file) => file.render_settings(),
+            _ => RenderSettings::default(),
+        }
+    }
+
+    fn timeline(&self) -> Result<Option<Timeline>, ImportError> {
+        match self {
+            SceneSource::File(file) => file.timeline(),
+            _ => Ok(None),
+        }
+    }
+
+    fn build(&self, film: &Film, bvh: &mut BvhBuilder) -> Result<Scene, ImportError> {
+        match self {
+            SceneSource::Random => Ok(random_scene(film, bvh)),
+            SceneSource::Simple => Ok(simple_scene(film)),
+            SceneSource::CornellBox => Ok(cornell_box_scene(film, bvh)),
+            SceneSource::Forest => Ok(forest_scene(film, bvh)),
+            SceneSource::Ply(path) => ply_scene(film, path, bvh),
+            SceneSource::File(file) => file.build(film, bvh),
+        }
+    }
+}
+
+fn exit_on_error<T>(result: Result<T, ImportError>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1);
+    })
+}
+
+#[cfg(not(feature = "bench"))]
+fn write_mean(film: &Film, exposure: Metering, format: OutputFormat, path: &Path) {
+    let scale = exposure.scale(film);
+    if let Metering::Auto { .. } = exposure {
+        println!("Auto exposure: EV100 {:.2}", metered_ev100(film));
+    }
+    let mean = film.to_rgb8(|sc| color_gamma(sc.mean() * scale));
+    match format {
+        OutputFormat::Png => Png::write(film.width, film.height, mean, &path.to_string_lossy()),
+        OutputFormat::Ppm => Ppm::write(film.width, film.height, mean, &path.to_string_lossy()),
+    }
+}
+
+// Renders every frame with the camera shutter set to the frame's part of the timeline. Frames are written under a
+// temporary name and renamed once complete, so an interrupted render never leaves a partial frame to resume after.
+// Every frame gets its own seed, so a resumed frame comes out the same as in an uninterrupted render.
+fn render_animation(args: &Args, timeline: Timeline, scene: &mut Scene, integrator: impl Integrate, size: (usize, usize)) {
+    let first = match args.resume {
+        true => (0..timeline.frames).rev().find(|&frame| args.frame_output(frame).exists()).map_or(0, |frame| frame + 1),
+        false => 0,
+    };
+    if first > 0 {
+        println!("Resuming after frame {} of {}", first - 1, timeline.frames);
+    }
+
+    for frame in first..timeline.frames {
+        let frame_start = Instant::now();
+        set_seed(mix(args.seed, frame as u64));
+        scene.cam.set_shutter(timeline.frame_shutter(frame));
+        let mut film = Film::new(size);
+        integrator.integrate(scene, &mut film);
+
+        let path = args.frame_output(frame);
+        #[cfg(not(feature = "bench"))]
+        {
+            let partial = path.with_file_name(format!(".partial-{}", path.file_name().unwrap().to_string_lossy()));
+            write_mean(&film, scene.exposure, args.output_format(), &partial);
+            rename(&partial, &path).unwrap();
+        }
+        println!("Frame {}/{} ({}) took {:?}", frame + 1, timeline.frames, path.display(), frame_start.elapsed());
+    }
+}
+
 fn main() {
+    let args = Args::parse();
     let start = Instant::now();
 
-    const WIDTH: usize = 800;
-    const HEIGHT: usize = 450;
-    const MIN_SAMPLES: usize = 32;
-    const MAX_SAMPLES: usize = 64;
-
+    set_seed(args.seed);
     create_dir_all("out/jobs").unwrap();
+    if let Some(parent) = args.output.parent() {
+        create_dir_all(parent).unwrap();
+    }
+
+    let source = exit_on_error(SceneSource::from_arg(&args.scene));
+    let render_settings = source.render_settings();
+    let width = args.width.unwrap_or(render_settings.width);
+    let height = args.height.unwrap_or(render_settings.height);
+
+    let settings = args.integrator_settings(render_settings).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1);
+    });
+    let sampler = args.sampler.unwrap_or(render_settings.sampler);
+    let evaluator = args.evaluator.unwrap_or(render_settings.evaluator);
+
+    let mut film = Film::new((width, height));
+    let bvh_options = BvhOptions { split: args.bvh, layout: args.bvh_layout, bins: args.bvh_bins, max_leaf_size: args.bvh_leaf_size };
+    #[cfg(feature = "bench")]
+    if args.compare_bvh {
+        bench::compare_bvh_layouts(&film, bvh_options, |bvh| exit_on_error(source.build(&film, bvh)));
+        return;
+    }
 
-    let mut film = Film::new((WIDTH, HEIGHT));
-    let scene = random_scene(&film);
+    let mut bvh = BvhBuilder::new(bvh_options);
+    let mut scene = exit_on_error(source.build(&film, &mut bvh));
+    if args.auto_exposure {
+        let compensation = match scene.exposure {
+            Metering::Auto { compensation } => compensation,
+            _ => 0.,
+        };
+        scene.exposure = Metering::Auto { compensation };
+    }
+    if bvh.stats.objects > 0 {
+        println!("BVH: {}", bvh.stats);
+    }
+
+    let timeline = match (args.frames, exit_on_error(source.timeline())) {
+        (Some(frames), timeline) => Some(Timeline { frames: frames as usize, ..timeline.unwrap_or_default() }),
+        (None, timeline) => timeline,
+    };
+    if let Some(timeline) = timeline {
+        render_animation(&args, timeline, &mut scene, MultiCoreTiledIntegrator::new(settings, sampler, evaluator), (width, height));
+        println!("Everything took: {:?}", start.elapsed());
+        return;
+    }
 
     let init_dur = start.elapsed();
     let render_start = Instant::now();
 
-    MultiCoreTiledIntegrator::<SquareSampler, SimpleRayEvaluator, 50, 50, 8, MIN_SAMPLES, MAX_SAMPLES>::integrate(&scene, &mut film, 0.004);
+    MultiCoreTiledIntegrator::new(settings, sampler, evaluator).integrate(&scene, &mut film);
 
     let render_dur = render_start.elapsed();
     let post_start = Instant::now();
 
     #[cfg(not(feature = "bench"))]
     {
-        Ppm::write(WIDTH, HEIGHT, film.to_rgb8(SampleCollector::gamma_corrected_mean), "out/out.ppm");
+        write_mean(&film, scene.exposure, args.output_format(), &args.output);
 
-        let base_path = format!("out/out-{}x{}@{}", WIDTH, HEIGHT, MAX_SAMPLES);
-        Png::write(WIDTH, HEIGHT, film.to_rgb8(SampleCollector::gamma_corrected_mean), &format!("{}-mean.png", base_path));
-        Png::write(WIDTH, HEIGHT, film.to_rgb8(SampleCollector::variance), &format!("{}-variance.png", base_path));
-        Png::write(WIDTH, HEIGHT, film.to_rgb8(SampleCollector::avg_variance), &format!("{}-avg-variance.png", base_path));
+        let stem = args.output.with_extension("");
+        let base_path = stem.to_string_lossy();
+        Png::write(width, height, film.to_rgb8(SampleCollector::variance), &format!("{}-variance.png", base_path));
+        Png::write(width, height, film.to_rgb8(SampleCollector::avg_variance), &format!("{}-avg-variance.png", base_path));
+        println!("Wrote {}", args.output.display());
     }
 
     variance_stats(&film);
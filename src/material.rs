@@ -1,13 +1,109 @@
-use crate::{config::{Color, Float}, ray::Ray, scene::Scene, vec3::{Point, Vec3}};
+use std::any::Any;
 
+use crate::{config::{Color, Float}, hit::HitRecord, material::simple::Material, scene::Scene, vec3::{Point, Vec3}};
+
+pub mod microfacet;
+pub mod principled;
 pub mod simple;
 
+// A direction picked by Scatter::sample
 #[derive(Clone, Copy, PartialEq, Debug)]
-pub struct ScatterRecord {
-    pub attenuation: Color,
-    pub out: Ray
+pub struct BsdfSample {
+    // f * |cos| / pdf, what the path's throughput gets multiplied with
+    pub weight: Color,
+    pub wi: Vec3,
+    // solid angle pdf of wi. Delta lobes have no density, for them this is the probability of picking the lobe
+    pub pdf: Float,
+    // a mirror or smooth glass, which eval and pdf can't see
+    pub delta: bool,
+}
+
+// Directions point away from the surface: wo towards where the light leaves (back along the incoming ray), wi towards
+// where it comes from
+pub trait Scatter: Any {
+    // BSDF times |cos| for light from wi leaving towards wo, zero for delta lobes
+    fn eval(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Color;
+    // solid angle pdf of sample picking wi, zero for delta lobes
+    fn pdf(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Float;
+    // u are uniform random numbers, the first picks a lobe and the other two a direction in it.
+    // None when the material absorbs the light
+    fn sample(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample>;
+    // when everything the material scatters is in delta lobes, light sampling can skip it
+    fn is_delta(&self) -> bool;
+    // emitters can be added to the scene's lights
+    fn is_emissive(&self) -> bool { false }
+    // light given off by the surface itself, emitters look the same from both sides
+    fn emitted(&self, scene: &Scene, pos: Point, uv: (Float, Float)) -> Color;
 }
 
-pub trait Scatter {
-    fn scatter(&self, scene: &Scene,ray_in: Ray, pos: Point, normal: Vec3, uv: (Float, Float)) -> Option<ScatterRecord>;
-}
\ No newline at end of file
+// Primitives and hit records refer to materials by their index in the scene's MaterialRepository, which keeps
+// them Copy and small however big a material gets, and lets materials be any Scatter implementation
+pub type MaterialHandle = usize;
+
+// registered by MaterialRepository::new, for objects that only need to be hit
+pub const NO_MATERIAL: MaterialHandle = 0;
+
+pub struct MaterialRepository {
+    materials: Vec<Box<dyn Scatter + Send + Sync>>,
+}
+
+impl MaterialRepository {
+    pub fn new() -> Self {
+        MaterialRepository { materials: vec![Box::new(Material::None)] }
+    }
+
+    pub fn add(&mut self, material: impl Scatter + Send + Sync) -> MaterialHandle {
+        self.materials.push(Box::new(material));
+        self.materials.len() - 1
+    }
+
+    pub fn material(&self, handle: MaterialHandle) -> &(dyn Scatter + Send + Sync) {
+        self.materials[handle].as_ref()
+    }
+
+    // the material as its concrete type, None if it's a different one
+    pub fn get<T: Scatter>(&self, handle: MaterialHandle) -> Option<&T> {
+        (self.materials[handle].as_ref() as &dyn Any).downcast_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        color::color_rgb, config::{Color, Float}, hit::{HitRecord, sphere::sphere}, integrator::{RayEvaluator, SimpleRayEvaluator},
+        material::{BsdfSample, MaterialRepository, NO_MATERIAL, Scatter, simple::{Material, lambertian}}, scene::Scene, vec3::{Point, Vec3}
+    };
+
+    // glows brighter towards the top, which none of the built-in materials can do
+    struct Gradient { top: Color }
+
+    impl Scatter for Gradient {
+        fn eval(&self, _scene: &Scene, _hit: &HitRecord, _wo: Vec3, _wi: Vec3) -> Color { color_rgb(0., 0., 0.) }
+        fn pdf(&self, _scene: &Scene, _hit: &HitRecord, _wo: Vec3, _wi: Vec3) -> Float { 0. }
+        fn sample(&self, _scene: &Scene, _hit: &HitRecord, _wo: Vec3, _u: [Float; 3]) -> Option<BsdfSample> { None }
+        fn is_delta(&self) -> bool { false }
+        fn is_emissive(&self) -> bool { true }
+        fn emitted(&self, _scene: &Scene, pos: Point, _uv: (Float, Float)) -> Color { pos.y.max(0.) * self.top }
+    }
+
+    #[test]
+    fn test_material_repository() {
+        let mut material_repository = MaterialRepository::new();
+        let gradient = material_repository.add(Gradient { top: color_rgb(2., 1., 0.) });
+        let red = material_repository.add(lambertian((1., 0., 0.)));
+        assert_ne!(gradient, red);
+        assert_eq!(material_repository.get(NO_MATERIAL), Some(&Material::None));
+        assert_eq!(material_repository.get(red), Some(&lambertian((1., 0., 0.))));
+        assert!(material_repository.get::<Material>(gradient).is_none());
+        assert!(material_repository.material(gradient).is_emissive());
+
+        let scene = Scene {
+            objects: Box::new(vec![sphere((0., 0., -2.), 1., gradient)]),
+            background_color: Box::new(|_| color_rgb(0., 0., 0.)),
+            material_repository,
+            ..Scene::default()
+        };
+        assert_eq!(SimpleRayEvaluator.li(&scene, ray!((0, 0, 0) -> (0, 0, -1)), 8), color_rgb(0., 0., 0.));
+        assert_eq!(SimpleRayEvaluator.li(&scene, ray!((0, 0.5, 0) -> (0, 0, -1)), 8), color_rgb(1., 0.5, 0.));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{color::color_rgb, config::{Color, Float, PI}, material::BsdfSample, vec3::{Vec3, cross, dot, orthonormal_basis, vec3}};
+
+// Rough surfaces as a distribution of tiny mirrors, following pbrt-v4. Everything here works in a local shading frame
+// with the surface normal along +z, see ShadingFrame.
+
+// The shading frame of a surface point, to and from world space
+#[derive(Clone, Copy, Debug)]
+pub struct ShadingFrame {
+    s: Vec3,
+    t: Vec3,
+    n: Vec3,
+}
+
+impl ShadingFrame {
+    pub fn new(normal: Vec3) -> ShadingFrame {
+        let n = normal.normalize();
+        let (s, t) = orthonormal_basis(n);
+        ShadingFrame { s, t, n }
+    }
+
+    pub fn to_local(self, v: Vec3) -> Vec3 {
+        vec3(dot(v, self.s), dot(v, self.t), dot(v, self.n))
+    }
+
+    pub fn to_world(self, v: Vec3) -> Vec3 {
+        v.x * self.s + v.y * self.t + v.z * self.n
+    }
+}
+
+fn same_hemisphere(w: Vec3, wp: Vec3) -> bool {
+    w.z * wp.z > 0.
+}
+
+fn reflect(wo: Vec3, n: Vec3) -> Vec3 {
+    -wo + 2. * dot(wo, n) * n
+}
+
+// Refracts wi (pointing away from the surface) through a surface with normal n and relative index of refraction eta,
+// inside over outside. Returns the refracted direction and the index it was relative to, None on total internal reflection
+fn refract(wi: Vec3, n: Vec3, eta: Float) -> Option<(Vec3, Float)> {
+    let (mut n, mut eta, mut cos_i) = (n, eta, dot(n, wi));
+    if cos_i < 0. {
+        (eta, cos_i, n) = (1. / eta, -cos_i, -n);
+    }
+    let sin2_t = (1. - cos_i * cos_i).max(0.) / (eta * eta);
+    if sin2_t >= 1. {
+        return None;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    Some((-wi / eta + (cos_i / eta - cos_t) * n, eta))
+}
+
+// Unpolarized reflectance of a dielectric boundary, cos_i is negative from the inside
+pub fn fresnel_dielectric(cos_i: Float, eta: Float) -> Float {
+    let (mut cos_i, mut eta) = (cos_i.clamp(-1., 1.), eta);
+    if cos_i < 0. {
+        (eta, cos_i) = (1. / eta, -cos_i);
+    }
+    let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1. {
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).max(0.).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.
+}
+
+// Schlick's approximation with the metal's color as its reflectance head on
+pub fn fresnel_schlick(f0: Color, cos_i: Float) -> Color {
+    let m = (1. - cos_i.abs()).clamp(0., 1.).powi(5);
+    f0 + m * (color_rgb(1., 1., 1.) - f0)
+}
+
+// The GGX distribution of microfacet normals, with the visible normal sampling of Heitz 2018
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrowbridgeReitz {
+    pub alpha: Float,
+}
+
+impl TrowbridgeReitz {
+    // roughness is perceptually linear, 0 is a mirror and 1 is very rough
+    pub fn from_roughness(roughness: Float) -> TrowbridgeReitz {
+        TrowbridgeReitz { alpha: roughness * roughness }
+    }
+
+    // below this the lobe is so narrow that sampling it as a perfect mirror looks the same, and doesn't break
+    // down numerically
+    pub fn effectively_smooth(&self) -> bool {
+        self.alpha < 1e-3
+    }
+
+    pub fn d(&self, wm: Vec3) -> Float {
+        let cos2 = wm.z * wm.z;
+        if cos2 <= 0. {
+            return 0.;
+        }
+        let e = (1. - cos2) / cos2 / (self.alpha * self.alpha);
+        1. / (PI * self.alpha * self.alpha * cos2 * cos2 * (1. + e) * (1. + e))
+    }
+
+    fn lambda(&self, w: Vec3) -> Float {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0. {
+            return 0.;
+        }
+        let alpha2_tan2 = self.alpha * self.alpha * (1. - cos2) / cos2;
+        ((1. + alpha2_tan2).sqrt() - 1.) / 2.
+    }
+
+    pub fn g1(&self, w: Vec3) -> Float {
+        1. / (1. + self.lambda(w))
+    }
+
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> Float {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // density of normals visible from w, which is also the pdf of sample_wm. From below the surface that's the
+    // normals visible from the other side, like sample_wm
+    pub fn pdf(&self, w: Vec3, wm: Vec3) -> Float {
+        if w.z == 0. {
+            return 0.;
+        }
+        let w = if w.z < 0. { -w } else { w };
+        self.g1(w) / w.z.abs() * self.d(wm) * dot(w, wm).max(0.)
+    }
+
+    // a microfacet normal visible from w, always on the +z side
+    pub fn sample_wm(&self, w: Vec3, u: (Float, Float)) -> Vec3 {
+        // stretch w to where the distribution is a hemisphere
+        let wh = vec3(self.alpha * w.x, self.alpha * w.y, w.z).normalize();
+        let wh = if wh.z < 0. { -wh } else { wh };
+        let t1 = if wh.z < 0.99999 { cross(vec3(0., 0., 1.), wh).normalize() } else { vec3(1., 0., 0.) };
+        let t2 = cross(wh, t1);
+
+        // uniform point on the disk, squeezed onto the part of the hemisphere facing w
+        let (r, phi) = (u.0.sqrt(), 2. * PI * u.1);
+        let (px, py) = (r * phi.cos(), r * phi.sin());
+        let h = (1. - px * px).sqrt();
+        let s = (1. + wh.z) / 2.;
+        let py = (1. - s) * h + s * py;
+        let pz = (1. - px * px - py * py).max(0.).sqrt();
+
+        let nh = px * t1 + py * t2 + pz * wh;
+        vec3(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+// Metals reflect everything that doesn't get absorbed, from either side
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConductorBxdf {
+    pub color: Color,
+    pub distribution: TrowbridgeReitz,
+}
+
+impl ConductorBxdf {
+    pub fn is_delta(&self) -> bool {
+        self.distribution.effectively_smooth()
+    }
+
+    // the half vector on the +z side, None where no microfacet reflects wo to wi
+    fn half_vector(&self, wo: Vec3, wi: Vec3) -> Option<Vec3> {
+        let wm = wo + wi;
+        if self.is_delta() || !same_hemisphere(wo, wi) || wm.near_zero() {
+            return None;
+        }
+        let wm = wm.normalize();
+        Some(if wm.z < 0. { -wm } else { wm })
+    }
+
+    // f * |cos| for light from wi scattered to wo
+    pub fn f(&self, wo: Vec3, wi: Vec3) -> Color {
+        match self.half_vector(wo, wi) {
+            Some(wm) => self.distribution.d(wm) * self.distribution.g(wo, wi) / (4. * wo.z.abs()) * fresnel_schlick(self.color, dot(wo, wm)),
+            None => color_rgb(0., 0., 0.),
+        }
+    }
+
+    pub fn pdf(&self, wo: Vec3, wi: Vec3) -> Float {
+        self.half_vector(wo, wi).map_or(0., |wm| self.distribution.pdf(wo, wm) / (4. * dot(wo, wm).abs()))
+    }
+
+    pub fn sample(&self, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample> {
+        if self.is_delta() {
+            return Some(BsdfSample { weight: fresnel_schlick(self.color, wo.z), wi: vec3(-wo.x, -wo.y, wo.z), pdf: 1., delta: true });
+        }
+        if wo.z == 0. {
+            return None;
+        }
+        let wo_up = if wo.z < 0. { -wo } else { wo };
+        let wm = self.distribution.sample_wm(wo_up, (u[1], u[2]));
+        let wi = reflect(wo_up, wm);
+        let wi = if wo.z < 0. { -wi } else { wi };
+        let pdf = self.pdf(wo, wi);
+        (pdf > 0.).then(|| BsdfSample { weight: self.f(wo, wi) / pdf, wi, pdf, delta: false })
+    }
+}
+
+// Glass and the like, reflecting and refracting in proportion to the Fresnel term. The normal points outside, eta is
+// the index of refraction inside over outside
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DielectricBxdf {
+    pub eta: Float,
+    pub distribution: TrowbridgeReitz,
+}
+
+impl DielectricBxdf {
+    // glass with the same index as its surroundings lets light straight through
+    pub fn is_delta(&self) -> bool {
+        self.eta == 1. || self.distribution.effectively_smooth()
+    }
+
+    // the half vector for scattering from wo to wi on the +z side, and the relative index of refraction along the way,
+    // None where no microfacet scatters that way
+    fn half_vector(&self, wo: Vec3, wi: Vec3) -> Option<(Vec3, Float)> {
+        let (cos_o, cos_i) = (wo.z, wi.z);
+        if self.is_delta() {
+            return None;
+        }
+        let etap = if cos_o * cos_i > 0. { 1. } else if cos_o > 0. { self.eta } else { 1. / self.eta };
+        let wm = etap * wi + wo;
+        if cos_i == 0. || cos_o == 0. || wm.near_zero() {
+            return None;
+        }
+        let wm = wm.normalize();
+        let wm = if wm.z < 0. { -wm } else { wm };
+        // microfacets seen from behind don't scatter anything
+        if dot(wm, wi) * cos_i < 0. || dot(wm, wo) * cos_o < 0. {
+            return None;
+        }
+        Some((wm, etap))
+    }
+
+    pub fn f(&self, wo: Vec3, wi: Vec3) -> Color {
+        let Some((wm, etap)) = self.half_vector(wo, wi) else { return color_rgb(0., 0., 0.) };
+        let distribution = &self.distribution;
+        let r = fresnel_dielectric(dot(wo, wm), self.eta);
+        let f = if same_hemisphere(wo, wi) {
+            distribution.d(wm) * distribution.g(wo, wi) * r / (4. * wo.z.abs())
+        } else {
+            let denom = (dot(wi, wm) + dot(wo, wm) / etap).powi(2);
+            // radiance gets squeezed into a smaller solid angle going into a denser medium
+            distribution.d(wm) * (1. - r) * distribution.g(wo, wi) * (dot(wi, wm) * dot(wo, wm) / (denom * wo.z)).abs() / (etap * etap)
+        };
+        color_rgb(f, f, f)
+    }
+
+    pub fn pdf(&self, wo: Vec3, wi: Vec3) -> Float {
+        let Some((wm, etap)) = self.half_vector(wo, wi) else { return 0. };
+        let r = fresnel_dielectric(dot(wo, wm), self.eta);
+        if same_hemisphere(wo, wi) {
+            self.distribution.pdf(wo, wm) / (4. * dot(wo, wm).abs()) * r
+        } else {
+            let denom = (dot(wi, wm) + dot(wo, wm) / etap).powi(2);
+            self.distribution.pdf(wo, wm) * dot(wi, wm).abs() / denom * (1. - r)
+        }
+    }
+
+    pub fn sample(&self, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample> {
+        if self.is_delta() {
+            let r = fresnel_dielectric(wo.z, self.eta);
+            if u[0] < r {
+                return Some(BsdfSample { weight: color_rgb(1., 1., 1.), wi: vec3(-wo.x, -wo.y, wo.z), pdf: r, delta: true });
+            }
+            let (wi, etap) = refract(wo, vec3(0., 0., 1.), self.eta)?;
+            let t = 1. / (etap * etap);
+            return Some(BsdfSample { weight: color_rgb(t, t, t), wi, pdf: 1. - r, delta: true });
+        }
+
+        let wm = self.distribution.sample_wm(wo, (u[1], u[2]));
+        let r = fresnel_dielectric(dot(wo, wm), self.eta);
+        let wi = if u[0] < r {
+            reflect(wo, wm)
+        } else {
+            refract(wo, wm, self.eta)?.0
+        };
+        let pdf = self.pdf(wo, wi);
+        (pdf > 0.).then(|| BsdfSample { weight: self.f(wo, wi) / pdf, wi, pdf, delta: false })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{color::color_rgb, config::{Float, PI}, material::microfacet::{ConductorBxdf, DielectricBxdf, TrowbridgeReitz, fresnel_dielectric}, random::{random_float, random_floats}, vec3::{dot, random_unit_vector, vec3}};
+
+    #[test]
+    fn test_fresnel_dielectric() {
+        assert_abs_diff_eq!(fresnel_dielectric(1., 1.5), 0.04, epsilon = 1e-12);
+        assert_abs_diff_eq!(fresnel_dielectric(-1., 1.5), 0.04, epsilon = 1e-12);
+        assert_eq!(fresnel_dielectric(0., 1.5), 1.);
+        // beyond the critical angle from the inside everything is reflected
+        assert_eq!(fresnel_dielectric(-0.5, 1.5), 1.);
+        assert_abs_diff_eq!(fresnel_dielectric(0.3, 1.), 0., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_visible_normals() {
+        let distribution = TrowbridgeReitz::from_roughness(0.6);
+        let wo = vec3(1., 0., 1.).normalize();
+        for _ in 0..1000 {
+            let wm = distribution.sample_wm(wo, (random_float(), random_float()));
+            assert!(wm.z > 0. && dot(wo, wm) >= 0., "{wm:?}");
+        }
+
+        // the visible normal density integrates to one over the hemisphere
+        let n = 200000;
+        let integral = (0..n).map(|_| {
+            let wm = random_unit_vector();
+            if wm.z > 0. { distribution.pdf(wo, wm) * 4. * PI } else { 0. }
+        }).sum::<Float>() / n as Float;
+        assert_abs_diff_eq!(integral, 1., epsilon = 0.03);
+    }
+
+    #[test]
+    fn test_bxdf_pdfs() {
+        // the pdfs integrate to at most one, a little less where sampled directions end up below the surface.
+        // Refraction makes for a narrow lobe, which takes a lot of uniform samples to integrate
+        let n = 1000000;
+        let integral = |pdf: &dyn Fn(_) -> Float| (0..n).map(|_| pdf(random_unit_vector()) * 4. * PI).sum::<Float>() / n as Float;
+        for wo in [vec3(0., 0., 1.), vec3(1., 0., 1.).normalize(), vec3(1., 1., -0.5).normalize()] {
+            let conductor = ConductorBxdf { color: color_rgb(1., 1., 1.), distribution: TrowbridgeReitz::from_roughness(0.7) };
+            let p = integral(&|wi| conductor.pdf(wo, wi));
+            assert!((0.75..1.03).contains(&p), "{wo:?} {p}");
+
+            let dielectric = DielectricBxdf { eta: 1.5, distribution: TrowbridgeReitz::from_roughness(0.7) };
+            let p = integral(&|wi| dielectric.pdf(wo, wi));
+            assert!((0.75..1.05).contains(&p), "{wo:?} {p}");
+        }
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        // a white metal only loses the light that would bounce between microfacets more than once, which there's
+        // more of the rougher it gets
+        let n = 100000;
+        let albedo = |roughness| {
+            let conductor = ConductorBxdf { color: color_rgb(1., 1., 1.), distribution: TrowbridgeReitz::from_roughness(roughness) };
+            (0..n).filter_map(|_| conductor.sample(vec3(0.6, 0., 0.8), random_floats())).map(|s| s.weight.g).sum::<Float>() / n as Float
+        };
+        let (mirror, rough, rougher) = (albedo(0.), albedo(0.3), albedo(0.8));
+        assert_abs_diff_eq!(mirror, 1.);
+        assert!(rough > 0.9 && rough < mirror && rougher > 0.5 && rougher < rough, "{rough} {rougher}");
+
+        // glass of index one lets everything through unchanged
+        let clear = DielectricBxdf { eta: 1., distribution: TrowbridgeReitz::from_roughness(0.5) };
+        let s = clear.sample(vec3(0.6, 0., 0.8), random_floats()).unwrap();
+        assert!((s.wi - vec3(-0.6, 0., -0.8)).length() < 1e-12, "{s:?}");
+    }
+}
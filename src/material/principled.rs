@@ -0,0 +1,337 @@
+use crate::{
+    color::{ColorRgb, color_rgb}, config::{Color, Float, PI}, hit::HitRecord, material::{BsdfSample, Scatter, microfacet::{ConductorBxdf, DielectricBxdf, ShadingFrame, TrowbridgeReitz, fresnel_schlick}},
+    scene::Scene, texture::TextureHandle, vec3::{Point, Vec3, dot, vec3}
+};
+
+// A fixed value or one looked up in a texture. Scalar parameters take the average of the texture's channels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Parameter<T> {
+    Value(T),
+    Texture(TextureHandle),
+}
+
+impl Parameter<Color> {
+    pub fn at(self, scene: &Scene, uv: (Float, Float), pos: Point) -> Color {
+        match self {
+            Parameter::Value(color) => color,
+            Parameter::Texture(texture) => scene.texture_repository.texture_value(texture, uv, pos),
+        }
+    }
+}
+
+impl Parameter<Float> {
+    pub fn at(self, scene: &Scene, uv: (Float, Float), pos: Point) -> Float {
+        match self {
+            Parameter::Value(value) => value,
+            Parameter::Texture(texture) => {
+                let color = scene.texture_repository.texture_value(texture, uv, pos);
+                (color.r + color.g + color.b) / 3.
+            },
+        }
+    }
+}
+
+// Disney-style uber material, most surfaces are some mix of its layers. Parameters other than the index of
+// refraction and emission go from 0 to 1:
+// - metallic blends from a dielectric base, diffuse under a specular reflection, to a metal in the base color
+// - roughness applies to both the specular reflection and transmission
+// - specular is the dielectric's reflectance head on, 0.5 gives the 4% of most common materials (Disney's scale)
+// - sheen adds the soft grazing angle highlight of cloth
+// - clearcoat puts a fairly smooth, colorless varnish on top of everything
+// - transmission turns the dielectric base into glass tinted by the base color
+// Emission is radiance like Material::Emissive's color. Vertex colors, if the mesh has them, replace the base color.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Principled {
+    pub base_color: Parameter<Color>,
+    pub metallic: Parameter<Float>,
+    pub roughness: Parameter<Float>,
+    pub specular: Parameter<Float>,
+    pub sheen: Parameter<Float>,
+    pub clearcoat: Parameter<Float>,
+    pub transmission: Parameter<Float>,
+    pub ior: Float,
+    pub emission: Parameter<Color>,
+    pub emission_intensity: Float,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Principled {
+            base_color: Parameter::Value(color_rgb(0.8, 0.8, 0.8)),
+            metallic: Parameter::Value(0.),
+            roughness: Parameter::Value(0.5),
+            specular: Parameter::Value(0.5),
+            sheen: Parameter::Value(0.),
+            clearcoat: Parameter::Value(0.),
+            transmission: Parameter::Value(0.),
+            ior: 1.5,
+            emission: Parameter::Value(color_rgb(0., 0., 0.)),
+            emission_intensity: 1.,
+        }
+    }
+}
+
+const CLEARCOAT_ROUGHNESS: Float = 0.2;
+const CLEARCOAT_F0: Float = 0.04;
+
+fn schlick(f0: Float, cos: Float) -> Float {
+    fresnel_schlick(ColorRgb::grayscale(f0), cos).r
+}
+
+// The material's layers at one surface point, in the local shading frame
+struct Lobes {
+    base_color: Color,
+    // the fractions of the surface that are opaque dielectric and glass, the rest is metal
+    diffuse: Float,
+    dielectric_f0: Float,
+    // the metal's and the opaque dielectric's reflection share one lobe, see Principled::lobes
+    specular: ConductorBxdf,
+    specular_weight: Float,
+    transmission: DielectricBxdf,
+    transmission_weight: Float,
+    sheen: Float,
+    clearcoat: Float,
+    coat: ConductorBxdf,
+}
+
+impl Principled {
+    fn lobes(&self, scene: &Scene, hit: &HitRecord) -> Lobes {
+        let at = |p: Parameter<Float>| p.at(scene, hit.uv.coords(), hit.pos).clamp(0., 1.);
+        let base_color = hit.vertex_color.unwrap_or_else(|| self.base_color.at(scene, hit.uv.coords(), hit.pos));
+        let (metallic, transmission) = (at(self.metallic), at(self.transmission));
+        let distribution = TrowbridgeReitz::from_roughness(at(self.roughness));
+        let diffuse = (1. - metallic) * (1. - transmission);
+        let dielectric_f0 = 0.08 * at(self.specular);
+
+        // Schlick's Fresnel is linear in f0, so the weighted sum of the two reflections is one reflection with the
+        // weighted average f0
+        let specular_weight = metallic + diffuse;
+        let f0 = if specular_weight > 0. {
+            (metallic * base_color + diffuse * ColorRgb::grayscale(dielectric_f0)) / specular_weight
+        } else {
+            color_rgb(0., 0., 0.)
+        };
+        Lobes {
+            base_color,
+            diffuse,
+            dielectric_f0,
+            specular: ConductorBxdf { color: f0, distribution },
+            specular_weight,
+            transmission: DielectricBxdf { eta: self.ior, distribution },
+            transmission_weight: (1. - metallic) * transmission,
+            sheen: at(self.sheen),
+            clearcoat: at(self.clearcoat),
+            coat: ConductorBxdf { color: ColorRgb::grayscale(CLEARCOAT_F0), distribution: TrowbridgeReitz::from_roughness(CLEARCOAT_ROUGHNESS) },
+        }
+    }
+}
+
+impl Lobes {
+    // the light that gets through the clearcoat, and through the specular reflection to the diffuse base
+    fn below_coat(&self, wo: Vec3) -> Float {
+        1. - self.clearcoat * schlick(CLEARCOAT_F0, wo.z)
+    }
+
+    fn below_specular(&self, wo: Vec3) -> Float {
+        1. - schlick(self.dielectric_f0, wo.z)
+    }
+
+    // light refracted through the glass picks up the base color
+    fn tint(&self, f: Color, wo: Vec3, wi: Vec3) -> Color {
+        if wo.z * wi.z < 0. { f * self.base_color } else { f }
+    }
+
+    // f * |cos| of everything but delta lobes. The diffuse base scatters on wo's side of the surface
+    fn f(&self, wo: Vec3, wi: Vec3) -> Color {
+        let mut f = self.specular_weight * self.specular.f(wo, wi) + self.transmission_weight * self.tint(self.transmission.f(wo, wi), wo, wi);
+        if wo.z * wi.z > 0. {
+            let cos_d = dot(wi, (wo + wi).normalize()).abs();
+            let sheen = self.sheen * (1. - cos_d).powi(5);
+            f += self.diffuse * wi.z.abs() * (self.below_specular(wo) / PI * self.base_color + ColorRgb::grayscale(sheen));
+        }
+        self.below_coat(wo) * f + self.clearcoat * self.coat.f(wo, wi)
+    }
+
+    // how likely sample picks the diffuse, specular, transmission and clearcoat lobe, roughly by how much they reflect
+    fn probabilities(&self, wo: Vec3) -> [Float; 4] {
+        let specular = fresnel_schlick(self.specular.color, wo.z);
+        let below_coat = self.below_coat(wo);
+        let weights = [
+            below_coat * self.diffuse * self.below_specular(wo),
+            below_coat * self.specular_weight * specular.r.max(specular.g).max(specular.b),
+            below_coat * self.transmission_weight,
+            self.clearcoat * schlick(CLEARCOAT_F0, wo.z),
+        ];
+        let total: Float = weights.iter().sum();
+        if total > 0. { weights.map(|w| w / total) } else { [0.; 4] }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> Float {
+        let [diffuse, specular, transmission, clearcoat] = self.probabilities(wo);
+        let diffuse_pdf = if wo.z * wi.z > 0. { wi.z.abs() / PI } else { 0. };
+        diffuse * diffuse_pdf + specular * self.specular.pdf(wo, wi) + transmission * self.transmission.pdf(wo, wi) + clearcoat * self.coat.pdf(wo, wi)
+    }
+
+    fn sample(&self, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample> {
+        // pick a lobe with the first number, and stretch what's left of it back to [0, 1) for the lobe's own use
+        let probabilities = self.probabilities(wo);
+        let below = |i: usize| probabilities[..i].iter().sum::<Float>();
+        // rounding can leave u[0] just past the last lobe
+        let lobe = (0..4).find(|&i| probabilities[i] > 0. && u[0] < below(i + 1)).or_else(|| probabilities.iter().rposition(|&p| p > 0.))?;
+        let p = probabilities[lobe];
+        let u = [((u[0] - below(lobe)) / p).clamp(0., 1.), u[1], u[2]];
+
+        let sample = match lobe {
+            0 => {
+                let (r, phi) = (u[1].sqrt(), 2. * PI * u[2]);
+                let z = (1. - u[1]).sqrt();
+                let wi = vec3(r * phi.cos(), r * phi.sin(), if wo.z < 0. { -z } else { z });
+                BsdfSample { weight: color_rgb(0., 0., 0.), wi, pdf: 0., delta: false }
+            },
+            1 => self.specular.sample(wo, u)?,
+            2 => self.transmission.sample(wo, u)?,
+            _ => self.coat.sample(wo, u)?,
+        };
+
+        if sample.delta {
+            let scale = match lobe {
+                1 => self.below_coat(wo) * self.specular_weight,
+                2 => self.below_coat(wo) * self.transmission_weight,
+                _ => self.clearcoat,
+            };
+            let weight = self.tint(scale * sample.weight, wo, sample.wi) / p;
+            return Some(BsdfSample { weight, pdf: p * sample.pdf, ..sample });
+        }
+        let pdf = self.pdf(wo, sample.wi);
+        (pdf > 0.).then(|| BsdfSample { weight: self.f(wo, sample.wi) / pdf, wi: sample.wi, pdf, delta: false })
+    }
+}
+
+// The lobes work around the outward normal so transmission can tell entering from leaving. Everything else scatters
+// on wo's side, which is the side the normal faces, just like Lambertian
+impl Scatter for Principled {
+    fn eval(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Color {
+        let frame = ShadingFrame::new(hit.outward_normal());
+        self.lobes(scene, hit).f(frame.to_local(wo.normalize()), frame.to_local(wi.normalize()))
+    }
+
+    fn pdf(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Float {
+        let frame = ShadingFrame::new(hit.outward_normal());
+        self.lobes(scene, hit).pdf(frame.to_local(wo.normalize()), frame.to_local(wi.normalize()))
+    }
+
+    fn sample(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample> {
+        let frame = ShadingFrame::new(hit.outward_normal());
+        let sample = self.lobes(scene, hit).sample(frame.to_local(wo.normalize()), u)?;
+        Some(BsdfSample { wi: frame.to_world(sample.wi), ..sample })
+    }
+
+    // textures could always bring back a rough or diffuse lobe
+    fn is_delta(&self) -> bool {
+        let (Parameter::Value(metallic), Parameter::Value(transmission), Parameter::Value(roughness), Parameter::Value(clearcoat)) =
+            (self.metallic, self.transmission, self.roughness, self.clearcoat) else { return false };
+        TrowbridgeReitz::from_roughness(roughness).effectively_smooth() && (1. - metallic) * (1. - transmission) == 0. && clearcoat == 0.
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.emission_intensity > 0. && self.emission != Parameter::Value(color_rgb(0., 0., 0.))
+    }
+
+    fn emitted(&self, scene: &Scene, pos: Point, uv: (Float, Float)) -> Color {
+        self.emission_intensity * self.emission.at(scene, uv, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::{assert_abs_diff_eq, assert_ulps_eq};
+
+    use crate::{
+        color::color_rgb, config::{Color, Float}, hit::{Hit, HitRecord, sphere::sphere}, material::{NO_MATERIAL, Scatter, principled::{Parameter, Principled}},
+        random::random_floats, scene::Scene, texture::TextureRepository, vec3::{Vec3, dot}
+    };
+
+    fn hit_sphere() -> (HitRecord, Vec3) {
+        let r = ray!((-1, 0.2, 0) -> (1, 0, 0));
+        (sphere((0., 0., 0.), 0.5, NO_MATERIAL).hit(r, 0.001, Float::INFINITY).unwrap(), -r.direction.normalize())
+    }
+
+    #[test]
+    fn test_principled_eval() {
+        let scene = Scene::default();
+        let (h, wo) = hit_sphere();
+        let materials = [
+            Principled::default(),
+            Principled { metallic: Parameter::Value(1.), roughness: Parameter::Value(0.3), base_color: Parameter::Value(color_rgb(0.9, 0.6, 0.2)), ..Principled::default() },
+            Principled { transmission: Parameter::Value(1.), roughness: Parameter::Value(0.4), ..Principled::default() },
+            Principled { metallic: Parameter::Value(0.3), transmission: Parameter::Value(0.5), sheen: Parameter::Value(1.), clearcoat: Parameter::Value(1.), ..Principled::default() },
+            // smooth reflection and glass over a diffuse base, eval and pdf only see the diffuse part
+            Principled { roughness: Parameter::Value(0.), transmission: Parameter::Value(0.5), ..Principled::default() },
+        ];
+
+        // a sample's weight is what eval gives for its direction, divided by the pdf
+        for material in materials {
+            for _ in 0..64 {
+                let Some(sample) = material.sample(&scene, &h, wo, random_floats()) else { continue };
+                if sample.delta { continue; }
+                let (f_cos, pdf) = (material.eval(&scene, &h, wo, sample.wi), material.pdf(&scene, &h, wo, sample.wi));
+                assert_ulps_eq!(pdf, sample.pdf, max_ulps = 1000);
+                assert_ulps_eq!(f_cos / pdf, sample.weight, max_ulps = 1000);
+            }
+        }
+        assert!(!materials[4].is_delta());
+    }
+
+    #[test]
+    fn test_principled_limits() {
+        let scene = Scene::default();
+        let (h, wo) = hit_sphere();
+        let gold = color_rgb(0.9, 0.6, 0.2);
+
+        // smooth metal is a mirror in the base color
+        let mirror = Principled { metallic: Parameter::Value(1.), roughness: Parameter::Value(0.), base_color: Parameter::Value(gold), ..Principled::default() };
+        assert!(mirror.is_delta());
+        let sample = mirror.sample(&scene, &h, wo, random_floats()).unwrap();
+        assert!(sample.delta);
+        assert_abs_diff_eq!(dot(sample.wi, h.normal), dot(wo, h.normal), epsilon = 1e-9);
+        assert!(sample.weight.r >= gold.r && sample.weight.b >= gold.b && sample.weight.b < 0.3);
+
+        // smooth glass, whatever gets through is tinted
+        let glass = Principled { transmission: Parameter::Value(1.), roughness: Parameter::Value(0.), base_color: Parameter::Value(gold), ..Principled::default() };
+        assert!(glass.is_delta());
+        let sample = glass.sample(&scene, &h, wo, [0.99, 0.5, 0.5]).unwrap();
+        assert!(dot(sample.wi, h.normal) < 0. && sample.delta);
+        assert_ulps_eq!(sample.weight, gold / (1.5 * 1.5), max_ulps = 1000);
+        let sample = glass.sample(&scene, &h, wo, [0., 0.5, 0.5]).unwrap();
+        assert!(dot(sample.wi, h.normal) > 0.);
+        assert_ulps_eq!(sample.weight, color_rgb(1., 1., 1.), max_ulps = 1000);
+
+        // white diffuse keeps no more than all the light, and not much less
+        let white = Principled { base_color: Parameter::Value(color_rgb(1., 1., 1.)), ..Principled::default() };
+        let n = 100000;
+        let albedo = (0..n).filter_map(|_| white.sample(&scene, &h, wo, random_floats())).fold(color_rgb(0., 0., 0.), |sum, s| sum + s.weight) / n as Float;
+        assert!(albedo.g > 0.9 && albedo.g < 1.01, "{albedo:?}");
+    }
+
+    #[test]
+    fn test_principled_textures() {
+        let mut texture_repository = TextureRepository::new();
+        let texture = texture_repository.load_texture("res/earthmap.jpg");
+        let scene = Scene { texture_repository, ..Scene::default() };
+        let (h, _) = hit_sphere();
+
+        let color = scene.texture_repository.texture_value(texture, h.uv.coords(), h.pos);
+        assert_eq!(Parameter::<Color>::Texture(texture).at(&scene, h.uv.coords(), h.pos), color);
+        assert_ulps_eq!(Parameter::<Float>::Texture(texture).at(&scene, h.uv.coords(), h.pos), (color.r + color.g + color.b) / 3.);
+
+        let glowing = Principled { emission: Parameter::Texture(texture), emission_intensity: 2., ..Principled::default() };
+        assert!(glowing.is_emissive() && !Principled::default().is_emissive());
+        assert_eq!(glowing.emitted(&scene, h.pos, h.uv.coords()), 2. * color);
+
+        // vertex colors win over the base color
+        let textured = Principled { base_color: Parameter::Texture(texture), roughness: Parameter::Value(1.), specular: Parameter::Value(0.), ..Principled::default() };
+        let h = HitRecord { vertex_color: Some(color_rgb(0., 1., 0.)), ..h };
+        let f = textured.eval(&scene, &h, h.normal, h.normal);
+        assert!(f.g > 0. && f.r == 0. && f.b == 0.);
+    }
+}
@@ -1,125 +1,246 @@
-use crate::{config::{Color, Float}, material::{Scatter, ScatterRecord}, random::random_float, ray::{Ray, ray}, scene::Scene, texture::TextureHandle, vec3::{Point, Vec3, dot, random_unit_vector}};
+use crate::{
+    color::color_rgb, config::{Color, Float, PI}, hit::HitRecord, material::{BsdfSample, Scatter, microfacet::{ConductorBxdf, DielectricBxdf, ShadingFrame, TrowbridgeReitz}},
+    scene::Scene, texture::TextureHandle, vec3::{Point, Vec3, vec3}
+};
 
-// I've gone back and forth on keeping Material as an enum or a trait. The Scatter implementation for the enum
-// version is ugly as sin, but it's also efficient. When trying to turn it into a trait object, I ran into the issue
-// that trait objects aren't easily copyable. I considered making Materials shared references, but I couldn't figure
-// out how to do that without using Arcs, and the performance overhead of that was quite noticeable. Hopefully I can
-// keep the Materials small, and figure out a different approach for textures.
+// The basic built-in materials. Objects only hold a MaterialHandle into the scene's MaterialRepository, so other
+// materials (like Principled, which the importers use) can be any Scatter implementation, this stays an enum
+// because matching on it is cheap.
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub enum Material {
     #[default]
     None,
     Lambertian { color: Color },
     LambertianTexture { texture: TextureHandle },
+    // roughness goes from a mirror at 0 to very rough at 1, for both of these
     Metal { color: Color, roughness: Float },
-    Dielectric { ir: Float }
+    Dielectric { ir: Float, roughness: Float },
+    Emissive { color: Color },
+    EmissiveTexture { texture: TextureHandle, intensity: Float },
 }
 
 pub fn lambertian(color: (Float, Float, Float)) -> Material { Material::Lambertian { color: color.into() }}
 pub fn lambertian_texture(texture: TextureHandle) -> Material { Material::LambertianTexture { texture }}
 pub fn metal(color: (Float, Float, Float), roughness: Float) -> Material { Material::Metal { color: color.into(), roughness }}
-pub fn dielectric(ir: Float) -> Material { Material::Dielectric { ir }}
+pub fn dielectric(ir: Float) -> Material { Material::Dielectric { ir, roughness: 0. }}
+pub fn rough_dielectric(ir: Float, roughness: Float) -> Material { Material::Dielectric { ir, roughness }}
+pub fn emissive(color: (Float, Float, Float)) -> Material { Material::Emissive { color: color.into() }}
+pub fn emissive_texture(texture: TextureHandle, intensity: Float) -> Material { Material::EmissiveTexture { texture, intensity }}
 
-fn reflect(v: Vec3, n: Vec3) -> Vec3 {
-    v - 2. * dot(v, n) * n
+impl Material {
+    fn conductor(color: Color, roughness: Float) -> ConductorBxdf {
+        ConductorBxdf { color, distribution: TrowbridgeReitz::from_roughness(roughness) }
+    }
+
+    fn dielectric(ir: Float, roughness: Float) -> DielectricBxdf {
+        DielectricBxdf { eta: ir, distribution: TrowbridgeReitz::from_roughness(roughness) }
+    }
+
+    // dielectrics refract against the outside of the surface, everything else scatters on the side the ray came from
+    fn frame(&self, hit: &HitRecord) -> ShadingFrame {
+        match self {
+            Material::Dielectric { .. } => ShadingFrame::new(hit.outward_normal()),
+            _ => ShadingFrame::new(hit.normal),
+        }
+    }
+
+    // diffuse reflectance, for the materials that have any
+    fn albedo(&self, scene: &Scene, hit: &HitRecord) -> Option<Color> {
+        match self {
+            Material::Lambertian { color } => Some(hit.vertex_color.unwrap_or(*color)),
+            Material::LambertianTexture { texture } => Some(scene.texture_repository.texture_value(*texture, hit.uv.coords(), hit.pos)),
+            _ => None,
+        }
+    }
 }
 
+// Lambertian surfaces scatter cosine distributed around the normal, which faces the incoming ray, so back faces of open
+// meshes scatter back to the side they're seen from. Vertex colors, if the mesh has them, replace their color
 impl Scatter for Material {
-    fn scatter(&self, scene: &Scene, ray_in: Ray, pos: Point, normal: Vec3, uv: (Float, Float)) -> Option<ScatterRecord> {
+    fn eval(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Color {
+        let frame = self.frame(hit);
+        let (wo, wi) = (frame.to_local(wo.normalize()), frame.to_local(wi.normalize()));
         match self {
-            Material::Lambertian{color} => {
-                let out_dir = normal + random_unit_vector();
-                Some(ScatterRecord {
-                    attenuation: *color,
-                    out: ray(pos, if out_dir.near_zero() { normal } else { out_dir }, ray_in.time)
-                })
-            },
-            Material::LambertianTexture{texture} => {
-                let out_dir = normal + random_unit_vector();
-                Some(ScatterRecord {
-                    attenuation: scene.texture_repository.texture_value(*texture, uv, pos),
-                    out: ray(pos, if out_dir.near_zero() { normal } else { out_dir }, ray_in.time)
-                })
-            },
-            Material::Metal{color, roughness} => {
-                let out_dir = reflect(ray_in.direction, normal) + *roughness * random_unit_vector();
-                if dot(out_dir, normal) > 0. {
-                    Some(ScatterRecord { attenuation: *color, out: ray(pos, out_dir, ray_in.time) })
-                } else {
-                    None
-                }
-            },
-            Material::Dielectric{ir} => {
-                let inside = dot(ray_in.direction, normal) > 0.;
-                let normal = if inside { -normal } else { normal };
-
-                let cos_theta = dot(-ray_in.direction.normalize(), normal);
-                let sin_theta = (1. - cos_theta * cos_theta).sqrt();
-                let refraction_ratio = if inside { *ir } else { 1. / *ir };
-
-                let r0 = (1. - ir) / (1. + ir);
-                let r02 = r0 * r0;
-                let reflectance = r02 + (1. - r02) * (1. - cos_theta).powi(5);
-
-                let out_dir = if refraction_ratio * sin_theta > 1. || reflectance > random_float() {
-                    reflect(ray_in.direction, normal)
-                } else {
-                    let out_dir_perpendicular = refraction_ratio * (ray_in.direction.normalize() + cos_theta * normal);
-                    let out_dir_parallel = -((1. - out_dir_perpendicular.length_squared()).abs().sqrt()) * normal;
-                    out_dir_parallel + out_dir_perpendicular
-                };
-                Some(ScatterRecord { attenuation: (1., 1., 1.).into(), out: ray(pos, out_dir, ray_in.time) })
+            Material::Metal { color, roughness } => Material::conductor(*color, *roughness).f(wo, wi),
+            Material::Dielectric { ir, roughness } => Material::dielectric(*ir, *roughness).f(wo, wi),
+            _ => self.albedo(scene, hit).map_or(color_rgb(0., 0., 0.), |albedo| wi.z.max(0.) / PI * albedo),
+        }
+    }
+
+    fn pdf(&self, _scene: &Scene, hit: &HitRecord, wo: Vec3, wi: Vec3) -> Float {
+        let frame = self.frame(hit);
+        let (wo, wi) = (frame.to_local(wo.normalize()), frame.to_local(wi.normalize()));
+        match self {
+            Material::Lambertian { .. } | Material::LambertianTexture { .. } => wi.z.max(0.) / PI,
+            Material::Metal { color, roughness } => Material::conductor(*color, *roughness).pdf(wo, wi),
+            Material::Dielectric { ir, roughness } => Material::dielectric(*ir, *roughness).pdf(wo, wi),
+            _ => 0.,
+        }
+    }
+
+    fn sample(&self, scene: &Scene, hit: &HitRecord, wo: Vec3, u: [Float; 3]) -> Option<BsdfSample> {
+        let frame = self.frame(hit);
+        let wo = frame.to_local(wo.normalize());
+        let sample = match self {
+            Material::Lambertian { .. } | Material::LambertianTexture { .. } => {
+                let (r, phi) = (u[1].sqrt(), 2. * PI * u[2]);
+                let wi = vec3(r * phi.cos(), r * phi.sin(), (1. - u[1]).sqrt());
+                BsdfSample { weight: self.albedo(scene, hit)?, wi, pdf: wi.z / PI, delta: false }
             },
-            Material::None => { None }
+            Material::Metal { color, roughness } => Material::conductor(*color, *roughness).sample(wo, u)?,
+            Material::Dielectric { ir, roughness } => Material::dielectric(*ir, *roughness).sample(wo, u)?,
+            Material::Emissive { .. } | Material::EmissiveTexture { .. } | Material::None => return None,
+        };
+        Some(BsdfSample { wi: frame.to_world(sample.wi), ..sample })
+    }
+
+    fn is_delta(&self) -> bool {
+        match self {
+            Material::Metal { color, roughness } => Material::conductor(*color, *roughness).is_delta(),
+            Material::Dielectric { ir, roughness } => Material::dielectric(*ir, *roughness).is_delta(),
+            _ => false,
+        }
+    }
+
+    fn is_emissive(&self) -> bool {
+        matches!(self, Material::Emissive { .. } | Material::EmissiveTexture { .. })
+    }
+
+    fn emitted(&self, scene: &Scene, pos: Point, uv: (Float, Float)) -> Color {
+        match self {
+            Material::Emissive { color } => *color,
+            Material::EmissiveTexture { texture, intensity } => *intensity * scene.texture_repository.texture_value(*texture, uv, pos),
+            _ => color_rgb(0., 0., 0.),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{color::color_rgb, config::Float, hit::{Hit, sphere::sphere}, material::{Scatter, simple::{Material, dielectric, metal}}, scene::Scene, vec3::dot};
+    use approx::{assert_abs_diff_eq, assert_ulps_eq};
+
+    use crate::{
+        color::color_rgb, config::Float, hit::{Hit, sphere::sphere, triangle::TriangleMesh}, material::{NO_MATERIAL, Scatter, simple::{Material, dielectric, emissive, emissive_texture, lambertian, metal, rough_dielectric}},
+        random::random_floats, scene::Scene, texture::TextureRepository, vec3::dot
+    };
 
     #[test]
     fn test_scatter_anti_normal() {
+        let scene = Scene::default();
         let color = color_rgb(0.8, 0.6, 0.4);
-        let mut s = sphere((0., 0., 0.), 0.5, Material::Lambertian { color });
+        let s = sphere((0., 0., 0.), 0.5, NO_MATERIAL);
         let r = ray!((-1, 0, 0) -> (1, 0, 0));
-        let h = s.hit(r.clone(), 0.001, Float::INFINITY).unwrap();
-
-        let scatter_rec = s.material.scatter(&Scene::default(), r.clone(), h.pos, h.normal, h.uv).unwrap();
-        assert_eq!(scatter_rec.attenuation, (0.8, 0.6, 0.4).into());
-        assert!(dot(h.normal, scatter_rec.out.direction) > 0.);
-        assert_eq!(scatter_rec.out.origin, h.pos);
-
-        s.material = metal((0.8, 0.6, 0.4), 0.);
-        let scatter_rec = s.material.scatter(&Scene::default(), r.clone(), h.pos, h.normal, h.uv).unwrap();
-        assert_eq!(scatter_rec.attenuation, (0.8, 0.6, 0.4).into());
-        assert_eq!(scatter_rec.out.direction, -r.direction);
-        assert_eq!(scatter_rec.out.origin, h.pos);
-
-        s.material = dielectric(1.);
-        let scatter_rec = s.material.scatter(&Scene::default(), r.clone(), h.pos, h.normal, h.uv).unwrap();
-        assert_eq!(scatter_rec.attenuation, (1., 1., 1.).into());
-        assert_eq!(scatter_rec.out.direction, r.direction);
-        assert_eq!(scatter_rec.out.origin, h.pos);
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+
+        let sample = Material::Lambertian { color }.sample(&scene, &h, -r.direction, random_floats()).unwrap();
+        assert_eq!(sample.weight, (0.8, 0.6, 0.4).into());
+        assert!(dot(h.normal, sample.wi) > 0. && !sample.delta);
+
+        let sample = metal((0.8, 0.6, 0.4), 0.).sample(&scene, &h, -r.direction, random_floats()).unwrap();
+        assert_ulps_eq!(sample.weight, (0.8, 0.6, 0.4).into());
+        assert_abs_diff_eq!(sample.wi, -r.direction, epsilon = 1e-9);
+        assert!(sample.delta);
+
+        let sample = dielectric(1.).sample(&scene, &h, -r.direction, random_floats()).unwrap();
+        assert_eq!(sample.weight, (1., 1., 1.).into());
+        assert_abs_diff_eq!(sample.wi, r.direction, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_back_face() {
+        let scene = Scene::default();
+
+        // an open mesh seen from behind scatters back to the viewer and doesn't see light from its other side
+        let tris = TriangleMesh::new(vec![vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(0, 1, 0)], vec![[0, 1, 2]], NO_MATERIAL).triangles();
+        let r = ray!((0.25, 0.25, -1) -> (0, 0, 1));
+        let h = tris[0].hit(r, 0.001, Float::INFINITY).unwrap();
+        let matte = lambertian((0.5, 0.5, 0.5));
+        for _ in 0..16 {
+            assert!(matte.sample(&scene, &h, -r.direction, random_floats()).unwrap().wi.z < 0.);
+        }
+        assert_eq!(matte.eval(&scene, &h, -r.direction, vec3!(0, 0, 1)), color_rgb(0., 0., 0.));
+        assert!(matte.eval(&scene, &h, -r.direction, vec3!(0, 0, -1)).r > 0.);
+
+        // leaving glass at a grazing angle reflects everything back inside
+        let s = sphere((0., 0., 0.), 1., NO_MATERIAL);
+        let r = ray!((0, 0.9, 0) -> (1, 0, 0));
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+        assert!(!h.front_face);
+        let sample = dielectric(1.5).sample(&scene, &h, -r.direction, [0.99, 0.5, 0.5]).unwrap();
+        assert!(dot(sample.wi, h.outward_normal()) < 0.);
+    }
+
+    #[test]
+    fn test_emission() {
+        let scene = Scene::default();
+        let s = sphere((0., 0., 0.), 0.5, NO_MATERIAL);
+        let r = ray!((-1, 0, 0) -> (1, 0, 0));
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+        assert!(emissive((4., 2., 1.)).sample(&scene, &h, -r.direction, random_floats()).is_none());
+        assert_eq!(emissive((4., 2., 1.)).emitted(&scene, h.pos, h.uv.coords()), (4., 2., 1.).into());
+        assert_eq!(lambertian((1., 1., 1.)).emitted(&scene, h.pos, h.uv.coords()), (0., 0., 0.).into());
+
+        let mut texture_repository = TextureRepository::new();
+        let texture = texture_repository.load_texture("res/earthmap.jpg");
+        let scene = Scene { texture_repository, ..Scene::default() };
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+        assert_ne!(h.uv.coords(), (0., 0.));
+        let expected = 3. * scene.texture_repository.texture_value(texture, h.uv.coords(), h.pos);
+        assert_eq!(emissive_texture(texture, 3.).emitted(&scene, h.pos, h.uv.coords()), expected);
+    }
+
+    #[test]
+    fn test_eval() {
+        let scene = Scene::default();
+        let r = ray!((-1, 0, 0) -> (1, 0, 0));
+        let h = sphere((0., 0., 0.), 0.5, NO_MATERIAL).hit(r, 0.001, Float::INFINITY).unwrap();
+
+        // a sample's weight is what eval gives for its direction, divided by the pdf. Rough metal and glass can
+        // be evaluated like diffuse surfaces
+        for material in [lambertian((0.8, 0.6, 0.4)), metal((0.8, 0.6, 0.4), 0.5), rough_dielectric(1.5, 0.3)] {
+            assert!(!material.is_delta());
+            for _ in 0..16 {
+                let Some(sample) = material.sample(&scene, &h, -r.direction, random_floats()) else { continue };
+                let (f_cos, pdf) = (material.eval(&scene, &h, -r.direction, sample.wi), material.pdf(&scene, &h, -r.direction, sample.wi));
+                assert!(!sample.delta);
+                assert_ulps_eq!(pdf, sample.pdf, max_ulps = 1000);
+                assert_ulps_eq!(f_cos / pdf, sample.weight, max_ulps = 1000);
+            }
+        }
+        let material = lambertian((0.8, 0.6, 0.4));
+        assert_eq!(material.eval(&scene, &h, -r.direction, -h.normal), color_rgb(0., 0., 0.));
+        assert_eq!(material.pdf(&scene, &h, -r.direction, -h.normal), 0.);
+
+        // mirrors and smooth glass only scatter in delta lobes, which eval and pdf don't see
+        for material in [metal((0.8, 0.6, 0.4), 0.), dielectric(1.5)] {
+            assert!(material.is_delta());
+            let sample = material.sample(&scene, &h, -r.direction, random_floats()).unwrap();
+            assert!(sample.delta);
+            assert_eq!(material.eval(&scene, &h, -r.direction, sample.wi), color_rgb(0., 0., 0.));
+            assert_eq!(material.pdf(&scene, &h, -r.direction, sample.wi), 0.);
+        }
     }
 
     #[test]
     fn test_refraction() {
-        let s = sphere((0., 0., 0.), 0.5, dielectric(1.3));
+        let scene = Scene::default();
+        let s = sphere((0., 0., 0.), 0.5, NO_MATERIAL);
+        let glass = dielectric(1.3);
 
+        // the first number picks between reflection and refraction
         let r = ray!((-1, 0, 0) -> (1, 0, 0));
-        let h = s.hit(r.clone(), 0.001, Float::INFINITY).unwrap();
-        let scatter_rec = s.material.scatter(&Scene::default(), r.clone(), h.pos, h.normal, h.uv).unwrap();
-        assert_eq!(scatter_rec.out.direction, r.direction);
-        assert_eq!(scatter_rec.out.origin, h.pos);
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+        let sample = glass.sample(&scene, &h, -r.direction, [0.99, 0.5, 0.5]).unwrap();
+        assert_abs_diff_eq!(sample.wi, r.direction, epsilon = 1e-9);
+        let sample = glass.sample(&scene, &h, -r.direction, [0., 0.5, 0.5]).unwrap();
+        assert_abs_diff_eq!(sample.wi, -r.direction, epsilon = 1e-9);
+        assert_ulps_eq!(sample.pdf, (0.3 as Float / 2.3).powi(2));
 
         let r = ray!((-1.5, -1, 0) -> (1, 1, 0));
-        let h = s.hit(r.clone(), 0.001, Float::INFINITY).unwrap();
-        let scatter_rec = s.material.scatter(&Scene::default(), r.clone(), h.pos, h.normal, h.uv).unwrap();
-        assert!(dot(r.direction, scatter_rec.out.direction) > 0.);
-        assert!(dot(h.normal, scatter_rec.out.direction) < 0.);
-        assert!(dot(r.direction.normalize(), -h.normal) < dot(scatter_rec.out.direction, -h.normal));
-        assert_eq!(scatter_rec.out.origin, h.pos);
+        let h = s.hit(r, 0.001, Float::INFINITY).unwrap();
+        let sample = glass.sample(&scene, &h, -r.direction, [0.99, 0.5, 0.5]).unwrap();
+        assert!(dot(r.direction, sample.wi) > 0.);
+        assert!(dot(h.normal, sample.wi) < 0.);
+        assert!(dot(r.direction.normalize(), -h.normal) < dot(sample.wi, -h.normal));
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,39 @@
 use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::Float;
 use rand::{SeedableRng, Rng};
 use rand_xoshiro::Xoshiro256Plus;
 
+static SEED: AtomicU64 = AtomicU64::new(0);
+
 // Use UnsafeCell for fast thread-local mutable RNG
 thread_local! {
-    static RNG: UnsafeCell<Xoshiro256Plus> = UnsafeCell::new(Xoshiro256Plus::seed_from_u64(0));
+    static RNG: UnsafeCell<Xoshiro256Plus> = UnsafeCell::new(Xoshiro256Plus::seed_from_u64(SEED.load(Ordering::Relaxed)));
+}
+
+// Each thread seeds its RNG on first use, so this only affects threads that haven't drawn a random number yet,
+// or that reseed themselves
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+}
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+// Derives an independent seed from seed and a stream number, this is SplitMix64's output function
+pub fn mix(seed: u64, stream: u64) -> u64 {
+    let mut z = seed.wrapping_add(stream.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Restarts the current thread's RNG on the given stream of the global seed. The same stream replays the same
+// random numbers on any thread, different streams are uncorrelated
+pub fn reseed_thread(stream: u64) {
+    RNG.with(|rng| unsafe { *rng.get() = Xoshiro256Plus::seed_from_u64(mix(seed(), stream)) });
 }
 
 pub fn random_in_range(min: Float, max: Float) -> Float {
@@ -15,4 +42,9 @@ pub fn random_in_range(min: Float, max: Float) -> Float {
 
 pub fn random_float() -> Float {
     RNG.with(|rng| unsafe { (*rng.get()).gen() })
-}
\ No newline at end of file
+}
+
+// a handful of uniform numbers at once, for the sample methods that take them
+pub fn random_floats<const N: usize>() -> [Float; N] {
+    std::array::from_fn(|_| random_float())
+}
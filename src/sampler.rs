@@ -1,6 +1,9 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
 use crate::{config::Float, random::random_float};
 
-pub trait PixelSample: Default {
+pub trait PixelSample {
     fn pixel_sample(&self, pixel: (usize, usize)) -> (Float, Float);
 }
 
@@ -18,4 +21,31 @@ impl PixelSample for SquareSampler {
     fn pixel_sample(&self, (x, y): (usize, usize)) -> (Float, Float) {
         (x as Float + random_float(), y as Float + random_float())
     }
-}
\ No newline at end of file
+}
+
+// Sampler picked at runtime, e.g. from the command line or a scene file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    Center,
+    #[default]
+    Square,
+}
+
+impl PixelSample for SamplerKind {
+    fn pixel_sample(&self, pixel: (usize, usize)) -> (Float, Float) {
+        match self {
+            SamplerKind::Center => CenterSampler {}.pixel_sample(pixel),
+            SamplerKind::Square => SquareSampler {}.pixel_sample(pixel),
+        }
+    }
+}
+
+#[test]
+fn test_sampler_kind() {
+    assert_eq!(SamplerKind::Center.pixel_sample((3, 4)), (3.5, 4.5));
+    for _ in 0..16 {
+        let (s, t) = SamplerKind::Square.pixel_sample((3, 4));
+        assert!((3. ..4.).contains(&s) && (4. ..5.).contains(&t));
+    }
+}
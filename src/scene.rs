@@ -1,15 +1,23 @@
-use crate::{camera::Camera, color::color_rgb, config::{Color, Film, Float}, hit::{Hit, bvh::{AxisAlignedBound, Bvh}, instance::Animate, sphere::{Sphere, sphere}}, material::simple::{dielectric, lambertian, lambertian_texture, metal}, random::{random_float, random_in_range}, ray::Ray, texture::TextureRepository, vec3::{Vec3, vec3}};
+use std::path::Path;
+
+use crate::{camera::{Camera, thin_lens::ThinLensCamera}, color::color_rgb, config::{Color, Film, Float, PI}, exposure::Metering, hit::{Bound, Hit, bvh::{AxisAlignedBound, BvhBuilder}, instance::{Animate, Blas, Instance, Transformed}, quad::{quad, quad_box}, sphere::{Sphere, sphere}, triangle::TriangleMesh}, material::{MaterialHandle, MaterialRepository, principled::Principled, simple::{dielectric, emissive, lambertian, lambertian_texture, metal}}, import::{ImportError, ply::load_ply}, light::Light, random::{random_float, random_in_range}, ray::Ray, texture::TextureRepository, transform::Transform, vec3::{Vec3, vec3}};
 
 pub struct Scene {
     pub objects: Box<dyn Hit + Send + Sync>,
-    pub background_color: fn(Ray) -> Color,
-    pub cam: Camera,
+    pub background_color: Box<dyn Fn(Ray) -> Color + Send + Sync>,
+    pub cam: Box<dyn Camera + Send + Sync>,
     pub texture_repository: TextureRepository,
+    // every object's material, by the handle it holds
+    pub material_repository: MaterialRepository,
+    // emitters that evaluators can sample directly, these should also be part of objects
+    pub lights: Vec<Light>,
+    // how bright the image comes out, configured along with the camera
+    pub exposure: Metering,
 }
 
 impl Default for Scene {
     fn default() -> Self {
-        Scene { objects: Box::new(Vec::<Sphere>::new()), background_color: overcast_sky_background, cam: Camera::default(), texture_repository: TextureRepository::new() }
+        Scene { objects: Box::new(Vec::<Sphere>::new()), background_color: Box::new(overcast_sky_background), cam: Box::new(ThinLensCamera::default()), texture_repository: TextureRepository::new(), material_repository: MaterialRepository::new(), lights: Vec::new(), exposure: Metering::None }
     }
 }
 
@@ -23,26 +31,28 @@ pub fn overcast_sky_background(r: Ray) -> Color {
 // should camera own the film?
 
 pub fn simple_scene(film: &Film) -> Scene {
-    let center_sphere = sphere((0., 0., -1.), 0.5, lambertian((0.7, 0.3, 0.3)));
-    let left_sphere = sphere((-1., 0., -1.), 0.5, dielectric(1.5));
-    let right_sphere = sphere((1., 0., -1.), 0.5, metal((0.8, 0.6, 0.2), 0.1));
-    let ground_sphere = sphere((0., -100.5, -1.), 100., lambertian((1., 1., 0.)));
+    let mut material_repository = MaterialRepository::new();
+    let center_sphere = sphere((0., 0., -1.), 0.5, material_repository.add(lambertian((0.7, 0.3, 0.3))));
+    let left_sphere = sphere((-1., 0., -1.), 0.5, material_repository.add(dielectric(1.5)));
+    let right_sphere = sphere((1., 0., -1.), 0.5, material_repository.add(metal((0.8, 0.6, 0.2), 0.1)));
+    let ground_sphere = sphere((0., -100.5, -1.), 100., material_repository.add(lambertian((1., 1., 0.))));
 
-    let cam = Camera::new(film, vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 90., 1., 0.6, (0., 0.).into());
+    let cam = Box::new(ThinLensCamera::new(film, vec3!(0, 0, 0), vec3!(0, 0, -1), vec3!(0, 1, 0), 90., 1., 0.6, (0., 0.).into()));
 
-    Scene { objects: Box::new(vec![center_sphere, left_sphere, right_sphere, ground_sphere]), background_color: overcast_sky_background, cam, texture_repository: TextureRepository::new() }
+    Scene { objects: Box::new(vec![center_sphere, left_sphere, right_sphere, ground_sphere]), background_color: Box::new(overcast_sky_background), cam, texture_repository: TextureRepository::new(), material_repository, lights: Vec::new(), exposure: Metering::None }
 }
 
-pub fn random_scene(film: &Film) -> Scene {
+pub fn random_scene(film: &Film, bvh: &mut BvhBuilder) -> Scene {
     for _i in 0..40315 { random_float(); }
 
     let mut texture_repository = TextureRepository::new();
+    let mut material_repository = MaterialRepository::new();
 
-    let ground_sphere = Box::new(sphere((0., -1000., 0.), 1000., lambertian((1., 1., 0.))));
-    let glass_sphere = Box::new(sphere((0., 1., 0.), 1., dielectric(1.5)));
-    let metal_sphere = Box::new(sphere((4., 1., 0.), 1., metal((0.7, 0.6, 0.5), 0.)));
-    //let lamb_sphere = Box::new(sphere((-4., 1., 0.), 1., lambertian((0.7, 0.3, 0.3))));
-    let lamb_sphere = Box::new(sphere((-4., 1., 0.), 1., lambertian_texture(texture_repository.load_texture("res/earthmap.jpg"))));
+    let ground_sphere = Box::new(sphere((0., -1000., 0.), 1000., material_repository.add(lambertian((1., 1., 0.)))));
+    let glass_sphere = Box::new(sphere((0., 1., 0.), 1., material_repository.add(dielectric(1.5))));
+    let metal_sphere = Box::new(sphere((4., 1., 0.), 1., material_repository.add(metal((0.7, 0.6, 0.5), 0.))));
+    //let lamb_sphere = Box::new(sphere((-4., 1., 0.), 1., material_repository.add(lambertian((0.7, 0.3, 0.3)))));
+    let lamb_sphere = Box::new(sphere((-4., 1., 0.), 1., material_repository.add(lambertian_texture(texture_repository.load_texture("res/earthmap.jpg")))));
     let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = vec![ground_sphere, glass_sphere, metal_sphere, lamb_sphere];
 
     for a in -11..11 {
@@ -57,18 +67,112 @@ pub fn random_scene(film: &Film) -> Scene {
                         offset_start: vec3(0., 0., 0.),
                         offset_end: vec3(0., random_in_range(0., 0.5), 0.),
                         interpolation: Box::new(|t| t),
-                        object: Box::new(sphere(center, 0.2, lambertian(Color::random_in_range(0., 1.).into())))
+                        object: Box::new(sphere(center, 0.2, material_repository.add(lambertian(Color::random_in_range(0., 1.).into()))))
                     }));
                 } else if mat_rng < 0.95 {
-                    objects.push(Box::new(sphere(center, 0.2, metal(Color::random_in_range(0.5, 1.0).into(), random_in_range(0., 0.2)))));
+                    objects.push(Box::new(sphere(center, 0.2, material_repository.add(metal(Color::random_in_range(0.5, 1.0).into(), random_in_range(0., 0.2))))));
                 } else {
-                    objects.push(Box::new(sphere(center, 0.2, dielectric(random_in_range(1.4, 1.6)))));
+                    objects.push(Box::new(sphere(center, 0.2, material_repository.add(dielectric(random_in_range(1.4, 1.6))))));
                 }
             }
         }
     }
 
-    let cam = Camera::new(film, vec3!(13, 2, 3), vec3!(0, 0, 0), vec3!(0, 1, 0), 20., 10., 0.6, (0., 1.).into());
+    let cam = Box::new(ThinLensCamera::new(film, vec3!(13, 2, 3), vec3!(0, 0, 0), vec3!(0, 1, 0), 20., 10., 0.6, (0., 1.).into()));
+
+    Scene { objects: bvh.build_objects(objects), background_color: Box::new(overcast_sky_background), cam, texture_repository, material_repository, lights: Vec::new(), exposure: Metering::None }
+}
+// The classic Cornell box, lit only by the quad light in the ceiling
+pub fn cornell_box_scene(film: &Film, bvh: &mut BvhBuilder) -> Scene {
+    let mut material_repository = MaterialRepository::new();
+    let red = material_repository.add(lambertian((0.65, 0.05, 0.05)));
+    let white = material_repository.add(lambertian((0.73, 0.73, 0.73)));
+    let green = material_repository.add(lambertian((0.12, 0.45, 0.15)));
+    let light = quad((343., 554., 332.), (-130., 0., 0.), (0., 0., -105.), material_repository.add(emissive((15., 15., 15.))));
+
+    let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = vec![
+        Box::new(quad((555., 0., 0.), (0., 555., 0.), (0., 0., 555.), green)),
+        Box::new(quad((0., 0., 0.), (0., 555., 0.), (0., 0., 555.), red)),
+        Box::new(light),
+        Box::new(quad((0., 0., 0.), (555., 0., 0.), (0., 0., 555.), white)),
+        Box::new(quad((555., 555., 555.), (-555., 0., 0.), (0., 0., -555.), white)),
+        Box::new(quad((0., 0., 555.), (555., 0., 0.), (0., 555., 0.), white)),
+    ];
+    for (size, degrees, offset) in [(vec3!(165, 330, 165), 15., vec3!(265, 0, 295)), (vec3!(165, 165, 165), -18., vec3!(130, 0, 65))] {
+        let sides = quad_box((0., 0., 0.), size.into(), white).into_iter().map(|q| Box::new(q) as Box<dyn AxisAlignedBound + Send + Sync>).collect();
+        let transform = Transform::translate(offset) * Transform::rotate(vec3!(0, 1, 0), degrees);
+        objects.push(Box::new(Transformed { transform, object: bvh.build_layout(sides) }));
+    }
+
+    let cam = Box::new(ThinLensCamera::new(film, vec3!(278, 278, -800), vec3!(278, 278, 0), vec3!(0, 1, 0), 40., 800., 0., (0., 0.).into()));
+
+    Scene { objects: bvh.build_objects(objects), background_color: Box::new(|_| color_rgb(0., 0., 0.)), cam, texture_repository: TextureRepository::new(), material_repository, lights: vec![Light::Quad(light)], exposure: Metering::None }
+}
+
+// Cone of foliage on a hexagonal trunk, with its base at the origin and one unit tall
+fn tree_mesh(material: MaterialHandle) -> TriangleMesh {
+    const SEGMENTS: usize = 12;
+    let ring = |radius: Float, y: Float, n: usize| (0..n).map(move |i| {
+        let phi = 2. * PI * i as Float / n as Float;
+        vec3(radius * phi.cos(), y, radius * phi.sin())
+    });
+
+    // trunk: six sides between two rings, then the cone: a ring around its base and the tip
+    let mut positions: Vec<_> = ring(0.05, 0., 6).chain(ring(0.05, 0.3, 6)).chain(ring(0.3, 0.2, SEGMENTS)).collect();
+    positions.extend([vec3!(0, 1, 0), vec3!(0, 0.2, 0)]);
+    let (tip, base) = (positions.len() - 2, positions.len() - 1);
+
+    let mut indices = Vec::new();
+    for i in 0..6 {
+        let j = (i + 1) % 6;
+        indices.extend([[i, j, 6 + j], [i, 6 + j, 6 + i]]);
+    }
+    for i in 0..SEGMENTS {
+        let j = (i + 1) % SEGMENTS;
+        indices.extend([[12 + i, tip, 12 + j], [12 + j, base, 12 + i]]);
+    }
+    TriangleMesh::new(positions, indices, material)
+}
+
+// Hundreds of copies of one tree, all sharing a single Blas, with some of them in autumn colors
+pub fn forest_scene(film: &Film, bvh: &mut BvhBuilder) -> Scene {
+    let mut material_repository = MaterialRepository::new();
+    let tree = Blas::new(bvh, tree_mesh(material_repository.add(lambertian((0.1, 0.4, 0.1)))).into_objects());
+    let autumn = [(0.6, 0.3, 0.05), (0.5, 0.1, 0.05), (0.6, 0.5, 0.1)].map(|color| material_repository.add(lambertian(color)));
+
+    let mut objects: Vec<Box<dyn AxisAlignedBound + Send + Sync>> = vec![Box::new(sphere((0., -1000., 0.), 1000., material_repository.add(lambertian((0.3, 0.4, 0.2)))))];
+    for a in -12..12 {
+        for b in -12..12 {
+            let offset = vec3(a as Float + 0.8 * random_float(), 0., b as Float + 0.8 * random_float());
+            let size = random_in_range(0.7, 1.3);
+            let transform = Transform::translate(offset) * Transform::rotate(vec3!(0, 1, 0), random_in_range(0., 360.))
+                * Transform::scale(vec3(size, size * random_in_range(0.8, 1.2), size)).unwrap();
+            let material = (random_float() < 0.2).then(|| autumn[(random_float() * autumn.len() as Float) as usize]);
+            objects.push(Box::new(Instance { blas: tree.clone(), transform, material }));
+        }
+    }
+
+    let cam = Box::new(ThinLensCamera::new(film, vec3!(8, 3, 10), vec3!(0, 0, 0), vec3!(0, 1, 0), 35., 13., 0., (0., 0.).into()));
 
-    Scene { objects: Box::new(Bvh::from_slice(objects.as_mut_slice())), background_color: overcast_sky_background, cam, texture_repository: texture_repository }
-}
\ No newline at end of file
+    Scene { objects: bvh.build_objects(objects), background_color: Box::new(overcast_sky_background), cam, texture_repository: TextureRepository::new(), material_repository, lights: Vec::new(), exposure: Metering::None }
+}
+
+// Renders a single PLY mesh (bunny, dragon, ...) with the camera framing its bounding box,
+// mostly useful to exercise the Bvh on realistic geometry.
+pub fn ply_scene(film: &Film, path: &str, bvh: &mut BvhBuilder) -> Result<Scene, ImportError> {
+    let mut material_repository = MaterialRepository::new();
+    let mesh = load_ply(path, material_repository.add(Principled::default()))?;
+    if mesh.is_empty() {
+        return Err(ImportError::new(Path::new(path), None, "mesh has no faces"));
+    }
+
+    let mut objects = mesh.into_objects();
+    let aabb = objects.as_mut_slice().bound();
+    let center = vec3((aabb.x.min + aabb.x.max) / 2., (aabb.y.min + aabb.y.max) / 2., (aabb.z.min + aabb.z.max) / 2.);
+    let radius = vec3(aabb.x.length(), aabb.y.length(), aabb.z.length()).length() / 2.;
+
+    let from = center + radius * vec3(0.5, 0.5, 3.5);
+    let cam = Box::new(ThinLensCamera::new(film, from, center, vec3!(0, 1, 0), 30., (from - center).length(), 0., (0., 0.).into()));
+
+    Ok(Scene { objects: bvh.build_objects(objects), background_color: Box::new(overcast_sky_background), cam, texture_repository: TextureRepository::new(), material_repository, lights: Vec::new(), exposure: Metering::None })
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use crate::{color::color_rgb, config::{Color, Float}, util::clamp, vec3::Point};
-use image::{ColorType, ConvertColorOptions, ImageReader, RgbImage, metadata::Cicp};
+use image::{ColorType, ConvertColorOptions, ImageReader, ImageResult, RgbImage, metadata::Cicp};
 
 // XXX does this make sense as a trait?
 pub trait UV {
@@ -34,13 +34,17 @@ pub fn new() -> Self {
     }
 
     pub fn load_texture(&mut self, path_str: &str) -> TextureHandle {
+        self.try_load_texture(path_str).unwrap()
+    }
+
+    pub fn try_load_texture(&mut self, path_str: &str) -> ImageResult<TextureHandle> {
         if let Some(&index) = self.path_indices.get(path_str) {
-            index
+            Ok(index)
         } else {
             let index = self.textures.len();
-            self.textures.push(load_image_linear(path_str));
+            self.textures.push(try_load_image_linear(path_str)?);
             self.path_indices.insert(path_str.to_string(), index);
-            index
+            Ok(index)
         }
     }
 
@@ -50,9 +54,13 @@ pub fn texture_value(&self, index: TextureHandle, uv: (Float, Float), pos: Point
 }
 
 pub fn load_image_linear(path_str: &str) -> RgbImage {
-    let mut dyn_img = ImageReader::open(path_str).unwrap().with_guessed_format().unwrap().decode().unwrap();
-    dyn_img.convert_color_space(Cicp::SRGB_LINEAR, ConvertColorOptions::default(), ColorType::Rgb8).unwrap();
-    dyn_img.into_rgb8()
+    try_load_image_linear(path_str).unwrap()
+}
+
+pub fn try_load_image_linear(path_str: &str) -> ImageResult<RgbImage> {
+    let mut dyn_img = ImageReader::open(path_str)?.with_guessed_format()?.decode()?;
+    dyn_img.convert_color_space(Cicp::SRGB_LINEAR, ConvertColorOptions::default(), ColorType::Rgb8)?;
+    Ok(dyn_img.into_rgb8())
 }
 
 #[test]
@@ -68,4 +76,6 @@ fn test_texture_repository() {
 
     let color3 = repo.texture_value(idx1, (0.5, 0.5), vec3!(0., 0., 0.));
     assert_ne!(color1, color3);
+
+    assert!(repo.try_load_texture("res/does-not-exist.jpg").is_err());
 }
\ No newline at end of file
@@ -0,0 +1,301 @@
+use std::ops::Mul;
+
+use crate::{config::Float, hit::aabb::AABB, ray::{Ray, ray}, util::radians, vec3::{Point, Vec3, cross, dot, vec3}};
+
+// Row major, acting on column vectors, so a * b applies b first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[Float; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4 { m: [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.], [0., 0., 0., 1.]] };
+
+    pub fn transpose(&self) -> Matrix4 {
+        Matrix4 { m: std::array::from_fn(|i| std::array::from_fn(|j| self.m[j][i])) }
+    }
+
+    // Gauss-Jordan elimination with partial pivoting, None for (nearly) singular matrices
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::IDENTITY.m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1. / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                let factor = a[row][col];
+                if row == col || factor == 0. { continue; }
+                for j in 0..4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+        Some(Matrix4 { m: inv })
+    }
+
+    pub fn point(&self, p: Point) -> Point {
+        let m = &self.m;
+        let v = vec3(
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        );
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1. { v } else { v / w }
+    }
+
+    // ignores the translation
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        vec3(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        Matrix4 { m: std::array::from_fn(|i| std::array::from_fn(|j| (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum())) }
+    }
+}
+
+// An invertible matrix along with its inverse, which is what intersecting transformed objects needs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { matrix: Matrix4::IDENTITY, inverse: Matrix4::IDENTITY };
+
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        matrix.inverse().map(|inverse| Transform { matrix, inverse })
+    }
+
+    pub fn translate(offset: Vec3) -> Transform {
+        let matrix = |o: Vec3| Matrix4 { m: [[1., 0., 0., o.x], [0., 1., 0., o.y], [0., 0., 1., o.z], [0., 0., 0., 1.]] };
+        Transform { matrix: matrix(offset), inverse: matrix(-offset) }
+    }
+
+    // None if any of the factors is zero
+    pub fn scale(factors: Vec3) -> Option<Transform> {
+        let matrix = |s: Vec3| Matrix4 { m: [[s.x, 0., 0., 0.], [0., s.y, 0., 0.], [0., 0., s.z, 0.], [0., 0., 0., 1.]] };
+        (factors.x != 0. && factors.y != 0. && factors.z != 0.)
+            .then(|| Transform { matrix: matrix(factors), inverse: matrix(vec3(1. / factors.x, 1. / factors.y, 1. / factors.z)) })
+    }
+
+    // counter-clockwise when looking down the axis towards the origin
+    pub fn rotate(axis: Vec3, degrees: Float) -> Transform {
+        let a = axis.normalize();
+        let (sin, cos) = radians(degrees).sin_cos();
+        let matrix = Matrix4 { m: [
+            [cos + a.x * a.x * (1. - cos), a.x * a.y * (1. - cos) - a.z * sin, a.x * a.z * (1. - cos) + a.y * sin, 0.],
+            [a.y * a.x * (1. - cos) + a.z * sin, cos + a.y * a.y * (1. - cos), a.y * a.z * (1. - cos) - a.x * sin, 0.],
+            [a.z * a.x * (1. - cos) - a.y * sin, a.z * a.y * (1. - cos) + a.x * sin, cos + a.z * a.z * (1. - cos), 0.],
+            [0., 0., 0., 1.],
+        ] };
+        // rotations are orthogonal
+        Transform { matrix, inverse: matrix.transpose() }
+    }
+
+    pub fn from_quaternion(q: Quaternion) -> Transform {
+        let matrix = q.matrix();
+        Transform { matrix, inverse: matrix.transpose() }
+    }
+
+    pub fn point(&self, p: Point) -> Point {
+        self.matrix.point(p)
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        self.matrix.vector(v)
+    }
+
+    // normals transform by the inverse transpose to stay perpendicular to the surface, renormalized
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        self.inverse.transpose().vector(n).normalize()
+    }
+
+    // brings a world space ray into the transformed object's space, the direction isn't normalized so distances
+    // along the ray are the same in both spaces
+    pub fn inverse_ray(&self, r: Ray) -> Ray {
+        ray(self.inverse.point(r.origin), self.inverse.vector(r.direction), r.time)
+    }
+
+    // the box around the transformed corners of aabb
+    pub fn bound(&self, aabb: AABB) -> AABB {
+        let corners = aabb.corners().map(|c| self.point(c));
+        let inf = Float::INFINITY;
+        let (min, max) = corners.into_iter().fold((vec3(inf, inf, inf), vec3(-inf, -inf, -inf)), |(min, max), c| {
+            (vec3(min.x.min(c.x), min.y.min(c.y), min.z.min(c.z)), vec3(max.x.max(c.x), max.y.max(c.y), max.z.max(c.z)))
+        });
+        AABB::from_points(min, max)
+    }
+}
+
+// a * b applies b first
+impl Mul for Transform {
+    type Output = Transform;
+    fn mul(self, rhs: Transform) -> Transform {
+        Transform { matrix: self.matrix * rhs.matrix, inverse: rhs.inverse * self.inverse }
+    }
+}
+
+// Unit quaternion representing a rotation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quaternion {
+    pub w: Float,
+    pub v: Vec3,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion { w: 1., v: Vec3 { x: 0., y: 0., z: 0. } };
+
+    // same rotation as Transform::rotate
+    pub fn from_axis_angle(axis: Vec3, degrees: Float) -> Quaternion {
+        let (sin, cos) = (radians(degrees) / 2.).sin_cos();
+        Quaternion { w: cos, v: sin * axis.normalize() }
+    }
+
+    // rotates around x, then y, then z
+    pub fn from_euler(degrees: Vec3) -> Quaternion {
+        Quaternion::from_axis_angle(vec3!(0, 0, 1), degrees.z) * Quaternion::from_axis_angle(vec3!(0, 1, 0), degrees.y) * Quaternion::from_axis_angle(vec3!(1, 0, 0), degrees.x)
+    }
+
+    pub fn dot(self, other: Quaternion) -> Float {
+        self.w * other.w + dot(self.v, other.v)
+    }
+
+    pub fn normalize(self) -> Quaternion {
+        let length = self.dot(self).sqrt();
+        Quaternion { w: self.w / length, v: self.v / length }
+    }
+
+    // angle in radians of the rotation taking self to other
+    pub fn angle_to(self, other: Quaternion) -> Float {
+        2. * self.dot(other).abs().min(1.).acos()
+    }
+
+    // Interpolates at constant angular velocity along the shortest arc. Nearly identical rotations divide
+    // by almost zero, linear interpolation is just as good there.
+    pub fn slerp(self, other: Quaternion, t: Float) -> Quaternion {
+        let (other, cos) = if self.dot(other) < 0. { (Quaternion { w: -other.w, v: -other.v }, -self.dot(other)) } else { (other, self.dot(other)) };
+        let (a, b) = if cos > 0.9995 {
+            (1. - t, t)
+        } else {
+            let theta = cos.acos();
+            (((1. - t) * theta).sin() / theta.sin(), (t * theta).sin() / theta.sin())
+        };
+        Quaternion { w: a * self.w + b * other.w, v: a * self.v + b * other.v }.normalize()
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        let Quaternion { w, v: Vec3 { x, y, z } } = *self;
+        Matrix4 { m: [
+            [1. - 2. * (y * y + z * z), 2. * (x * y - w * z), 2. * (x * z + w * y), 0.],
+            [2. * (x * y + w * z), 1. - 2. * (x * x + z * z), 2. * (y * z - w * x), 0.],
+            [2. * (x * z - w * y), 2. * (y * z + w * x), 1. - 2. * (x * x + y * y), 0.],
+            [0., 0., 0., 1.],
+        ] }
+    }
+}
+
+// a * b rotates by b first
+impl Mul for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, rhs: Quaternion) -> Quaternion {
+        Quaternion { w: self.w * rhs.w - dot(self.v, rhs.v), v: self.w * rhs.v + rhs.w * self.v + cross(self.v, rhs.v) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use crate::{config::Float, hit::aabb::AABB, transform::{Matrix4, Quaternion, Transform}, util::radians, vec3::dot};
+
+    fn assert_matrix_eq(a: Matrix4, b: Matrix4) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert_abs_diff_eq!(a.m[i][j], b.m[i][j], epsilon = 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matrix_inverse() {
+        let m = Matrix4 { m: [[2., 1., 0., 3.], [0., 1., 4., -1.], [1., 0., 1., 2.], [0., 0., 0., 1.]] };
+        let inv = m.inverse().unwrap();
+        assert_matrix_eq(m * inv, Matrix4::IDENTITY);
+        assert_matrix_eq(inv * m, Matrix4::IDENTITY);
+        assert!(Matrix4 { m: [[1., 2., 3., 0.], [2., 4., 6., 0.], [0., 0., 1., 0.], [0., 0., 0., 1.]] }.inverse().is_none());
+    }
+
+    #[test]
+    fn test_transform() {
+        let t = Transform::translate(vec3!(1, 2, 3)) * Transform::rotate(vec3!(0, 1, 0), 90.) * Transform::scale(vec3!(2, 1, 1)).unwrap();
+        assert_matrix_eq(t.matrix * t.inverse, Matrix4::IDENTITY);
+        assert_matrix_eq(Transform::new(t.matrix).unwrap().inverse, t.inverse);
+
+        // scaled to (2, 0, 0), rotated onto -z, then moved
+        assert_abs_diff_eq!(t.point(vec3!(1, 0, 0)), vec3!(1, 2, 1), epsilon = 1e-12);
+        assert_abs_diff_eq!(t.vector(vec3!(1, 0, 0)), vec3!(0, 0, -2), epsilon = 1e-12);
+        assert_abs_diff_eq!(t.inverse.point(vec3!(1, 2, 1)), vec3!(1, 0, 0), epsilon = 1e-12);
+        assert!(Transform::scale(vec3!(1, 0, 1)).is_none());
+
+        // the normal of a plane stays perpendicular to it under non-uniform scaling
+        let s = Transform::scale(vec3!(1, 3, 1)).unwrap();
+        let (n, tangent) = (vec3!(1, 1, 0).normalize(), vec3!(1, -1, 0));
+        assert_abs_diff_eq!(dot(s.normal(n), s.vector(tangent)), 0., epsilon = 1e-12);
+        assert_abs_diff_eq!(s.normal(n).length(), 1., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_transform_bound() {
+        let aabb = AABB::from_points(vec3!(-1, -1, -1), vec3!(1, 1, 1));
+        let bound = Transform::rotate(vec3!(0, 0, 1), 45.).bound(aabb);
+        assert_abs_diff_eq!(bound.x.max, Float::sqrt(2.), epsilon = 1e-12);
+        assert_abs_diff_eq!(bound.y.min, -Float::sqrt(2.), epsilon = 1e-12);
+        assert_eq!(bound.z, aabb.z);
+        assert_eq!(Transform::translate(vec3!(1, 0, 0)).bound(aabb).x, (0., 2.).into());
+    }
+
+    #[test]
+    fn test_quaternion() {
+        let q = Quaternion::from_axis_angle(vec3!(1, 2, 3), 70.);
+        assert_matrix_eq(q.matrix(), Transform::rotate(vec3!(1, 2, 3), 70.).matrix);
+        let euler = Quaternion::from_euler(vec3!(30, 60, 90));
+        let rotations = Transform::rotate(vec3!(0, 0, 1), 90.) * Transform::rotate(vec3!(0, 1, 0), 60.) * Transform::rotate(vec3!(1, 0, 0), 30.);
+        assert_matrix_eq(Transform::from_quaternion(euler).matrix, rotations.matrix);
+
+        // halfway between 0 and 120 degrees is 60, going the short way around even when the signs differ
+        let a = Quaternion::IDENTITY;
+        let b = Quaternion::from_axis_angle(vec3!(0, 1, 0), 120.);
+        assert_matrix_eq(a.slerp(b, 0.5).matrix(), Transform::rotate(vec3!(0, 1, 0), 60.).matrix);
+        let minus_b = Quaternion { w: -b.w, v: -b.v };
+        assert_matrix_eq(a.slerp(minus_b, 0.25).matrix(), Transform::rotate(vec3!(0, 1, 0), 30.).matrix);
+        assert_abs_diff_eq!(a.angle_to(minus_b), radians(120.), epsilon = 1e-12);
+        assert_eq!(a.slerp(a, 0.3), a);
+    }
+}
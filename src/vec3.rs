@@ -1,6 +1,8 @@
 use std::fmt::{Display, Formatter};
 use std::ops::{Add, AddAssign, Div, Index, Mul, Neg, Sub};
 
+use approx::{UlpsEq, AbsDiffEq};
+
 use crate::config::Float;
 use crate::random::random_in_range;
 
@@ -33,6 +35,14 @@ pub fn cross(u: Vec3, v: Vec3) -> Vec3 {
          u.x * v.y - u.y * v.x)
 }
 
+// two vectors that together with the unit vector n form an orthonormal basis (Duff et al. 2017)
+pub fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let sign = (1. as Float).copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    (vec3(1. + sign * n.x * n.x * a, sign * b, -sign * n.x), vec3(b, sign + n.y * n.y * a, -n.y))
+}
+
 impl Vec3 {
     pub fn length_squared(self) -> Float { self.x * self.x + self.y * self.y + self.z * self.z }
 
@@ -129,6 +139,32 @@ fn index(&self, index: usize) -> &Float {
     }
 }
 
+impl AbsDiffEq for Vec3 {
+    type Epsilon = <Float as AbsDiffEq>::Epsilon;
+
+    fn default_epsilon() -> Self::Epsilon {
+        Float::default_epsilon()
+    }
+
+    fn abs_diff_eq(&self, other: &Self, epsilon: Self::Epsilon) -> bool {
+        Float::abs_diff_eq(&self.x, &other.x, epsilon) &&
+        Float::abs_diff_eq(&self.y, &other.y, epsilon) &&
+        Float::abs_diff_eq(&self.z, &other.z, epsilon)
+    }
+}
+
+impl UlpsEq for Vec3 {
+    fn default_max_ulps() -> u32 {
+        Float::default_max_ulps()
+    }
+
+    fn ulps_eq(&self, other: &Self, epsilon: <Float as AbsDiffEq>::Epsilon, max_ulps: u32) -> bool {
+        Float::ulps_eq(&self.x, &other.x, epsilon, max_ulps) &&
+        Float::ulps_eq(&self.y, &other.y, epsilon, max_ulps) &&
+        Float::ulps_eq(&self.z, &other.z, epsilon, max_ulps)
+    }
+}
+
 #[test]
 fn test_basics() {
     let v1 = vec3!(1, 2, 3);
@@ -139,12 +175,14 @@ fn test_basics() {
     assert_eq!(v1 * 2., vec3!(2, 4, 6));
     assert_eq!(v2 / 2., vec3!(3, 2, 1));
     assert_eq!(v1 * 2., 2. * v1);
+    approx::assert_abs_diff_eq!(v1, vec3!(1, 2, 3.001), epsilon = 0.01);
+    approx::assert_abs_diff_ne!(v1, vec3!(1, 2, 3.1), epsilon = 0.01);
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_ulps_eq;
-    use crate::{config::{PI, Float}, vec3::{dot, random_unit_vector}};
+    use crate::{config::{PI, Float}, vec3::{cross, dot, orthonormal_basis, random_unit_vector}};
 
     #[test]
     fn test_dot() {
@@ -167,6 +205,17 @@ fn test_length() {
         assert_ulps_eq!((vec3!(5, 3, 9)).normalize().length(), 1.);
     }
 
+    #[test]
+    fn test_orthonormal_basis() {
+        for n in [vec3!(0, 0, 1), vec3!(0, 0, -1), vec3!(1, 2, 3).normalize(), vec3!(-3, 1, -0.5).normalize()] {
+            let (u, v) = orthonormal_basis(n);
+            assert_ulps_eq!(u.length(), 1.);
+            assert_ulps_eq!(v.length(), 1.);
+            assert!(dot(u, v).abs() < 1e-12 && dot(u, n).abs() < 1e-12 && dot(v, n).abs() < 1e-12);
+            assert!(dot(cross(u, v), n) > 0.);
+        }
+    }
+
     #[test]
     fn test_random() {
         let v = random_unit_vector();